@@ -0,0 +1,259 @@
+//! Request and response mapping for the Microsoft Graph `sendMail` API with MIME content
+//! (`POST /users/{mailbox}/sendMail`).
+//!
+//! The message is sent as base64 with `Content-Type: text/plain`. Graph takes the recipients
+//! from the message headers, so envelope recipients that are not in the `To:` or `Cc:` header
+//! are written to the `Bcc:` header. Addresses in `To:` and `Cc:` that are not envelope
+//! recipients are still delivered to by Graph.
+//!
+//! Access tokens are either supplied directly or fetched with the OAuth 2.0 client credentials
+//! flow from the Microsoft identity platform.
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use lettre::Address;
+use serde_json::Value;
+
+use super::{JsonError, message};
+use crate::parser;
+
+/// Default authority that issues access tokens
+pub const DEFAULT_AUTHORITY_URL: &str = "https://login.microsoftonline.com";
+
+/// Scope requested with the client credentials flow
+const SCOPE: &str = "https://graph.microsoft.com/.default";
+
+/// Error codes Graph uses for throttled requests
+const THROTTLING_ERRORS: [&str; 3] = [
+    "ApplicationThrottled",
+    "MailboxConcurrency",
+    "ErrorExceededMessageLimit",
+];
+
+/// Application credentials for the OAuth 2.0 client credentials flow
+#[derive(Clone, PartialEq, Eq)]
+pub struct ClientCredentials {
+    /// Token endpoint of the tenant
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl ClientCredentials {
+    /// Credentials for the token endpoint of `tenant_id` at `authority_url`
+    #[must_use]
+    pub fn new(
+        authority_url: &str,
+        tenant_id: &str,
+        client_id: String,
+        client_secret: String,
+    ) -> Self {
+        Self {
+            token_url: format!(
+                "{}/{tenant_id}/oauth2/v2.0/token",
+                authority_url.trim_end_matches('/')
+            ),
+            client_id,
+            client_secret,
+        }
+    }
+
+    /// Form fields of the token request
+    pub(super) fn token_request(&self) -> [(&str, &str); 4] {
+        [
+            ("grant_type", "client_credentials"),
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("scope", SCOPE),
+        ]
+    }
+}
+
+impl std::fmt::Debug for ClientCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The client secret is never printed
+        f.debug_struct("ClientCredentials")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Path of the `sendMail` endpoint of `mailbox`
+#[must_use]
+pub fn send_mail_path(mailbox: &Address) -> String {
+    format!("/users/{mailbox}/sendMail")
+}
+
+/// Access token and its lifetime in seconds from a token response
+pub(super) fn parse_token_response(body: &str) -> Option<(String, u64)> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let token = value.get("access_token")?.as_str()?.to_string();
+    let expires_in = value
+        .get("expires_in")
+        .and_then(|expires_in| {
+            // Some endpoints send the lifetime as a string
+            expires_in
+                .as_u64()
+                .or_else(|| expires_in.as_str()?.parse().ok())
+        })
+        .unwrap_or(0);
+    Some((token, expires_in))
+}
+
+/// Readable error from an OAuth error response (`error` and `error_description`)
+pub(super) fn parse_token_error(body: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let description = value.get("error_description").and_then(Value::as_str);
+    let error = value.get("error").and_then(Value::as_str);
+    // Descriptions carry a trace and correlation id on later lines
+    description
+        .and_then(|description| description.lines().next())
+        .or(error)
+        .map(str::to_string)
+}
+
+/// Build the request body for a message consisting of `head` followed by `body`.
+pub(super) fn request_body(envelope_to: &[&Address], head: &str, body: &[u8]) -> Vec<u8> {
+    let headers = parser::parse_email_headers(head);
+    let bcc = message::recipients(&headers, envelope_to)
+        .bcc
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    // Recipients that were only in a `Bcc:` header are not envelope recipients anymore
+    let head = if parser::has_header(&headers, "Bcc") {
+        parser::replace_header_values(head, "Bcc", |_| Some(bcc.clone()))
+    } else if bcc.is_empty() {
+        head.to_string()
+    } else {
+        // Use the line ending of the field the header is placed before
+        let first_line = head.split_inclusive('\n').next().unwrap_or_default();
+        let line_ending = if first_line.ends_with("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        format!("Bcc: {bcc}{line_ending}{head}")
+    };
+    BASE64_STANDARD
+        .encode([head.as_bytes(), body].concat())
+        .into_bytes()
+}
+
+/// Extract the error from the OData error shape:
+/// `{"error": {"code": .., "message": ..}}`. Returns `None` for other bodies.
+pub(super) fn parse_error(body: &str) -> Option<JsonError> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let error = value.get("error")?.as_object()?;
+    let code = error.get("code").and_then(Value::as_str);
+    let message = error
+        .get("message")
+        .and_then(Value::as_str)
+        .filter(|message| !message.trim().is_empty())
+        .map(|message| match code {
+            Some(code) => format!("{code}: {message}"),
+            None => message.to_string(),
+        })
+        .or_else(|| code.map(str::to_string));
+    Some(JsonError {
+        message,
+        code: code.map(str::to_string),
+    })
+}
+
+/// Whether an error code taken from [`parse_error`] means the request was throttled
+pub(super) fn is_throttling(code: &str) -> bool {
+    THROTTLING_ERRORS.contains(&code)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn decode(body: &[u8]) -> String {
+        String::from_utf8(BASE64_STANDARD.decode(body).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_request_body_adds_bcc_recipients() {
+        let to = Address::from_str("to@example.com").unwrap();
+        let hidden = Address::from_str("hidden@example.com").unwrap();
+        let body = request_body(
+            &[&to, &hidden],
+            "To: to@example.com\r\nSubject: Test\r\n\r\n",
+            b"Body",
+        );
+        assert_eq!(
+            decode(&body),
+            "Bcc: hidden@example.com\r\nTo: to@example.com\r\nSubject: Test\r\n\r\nBody"
+        );
+
+        let body = request_body(&[&to], "To: to@example.com\n\n", b"Body");
+        assert_eq!(decode(&body), "To: to@example.com\n\nBody");
+    }
+
+    #[test]
+    fn test_request_body_replaces_bcc_header() {
+        let to = Address::from_str("to@example.com").unwrap();
+        let body = request_body(
+            &[&to],
+            "To: to@example.com\r\nBcc: other@example.com\r\n\r\n",
+            b"Body",
+        );
+        assert_eq!(decode(&body), "To: to@example.com\r\nBcc: \r\n\r\nBody");
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = parse_error(
+            r#"{"error": {"code": "ErrorInvalidRecipients", "message": "At least one recipient is not valid.", "innerError": {"request-id": "1"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            error.message.as_deref(),
+            Some("ErrorInvalidRecipients: At least one recipient is not valid.")
+        );
+        assert_eq!(error.code.as_deref(), Some("ErrorInvalidRecipients"));
+        assert!(!is_throttling("ErrorInvalidRecipients"));
+        assert!(is_throttling("ApplicationThrottled"));
+
+        assert_eq!(parse_error(r#"{"message": "other"}"#), None);
+    }
+
+    #[test]
+    fn test_token_response() {
+        assert_eq!(
+            parse_token_response(
+                r#"{"token_type": "Bearer", "expires_in": 3599, "access_token": "eyJ0"}"#
+            ),
+            Some(("eyJ0".to_string(), 3599))
+        );
+        assert_eq!(parse_token_response("{}"), None);
+        assert_eq!(
+            parse_token_error(
+                r#"{"error": "invalid_client", "error_description": "AADSTS7000215: Invalid client secret provided.\r\nTrace ID: 1"}"#
+            )
+            .as_deref(),
+            Some("AADSTS7000215: Invalid client secret provided.")
+        );
+    }
+
+    #[test]
+    fn test_token_url() {
+        let credentials = ClientCredentials::new(
+            "https://login.microsoftonline.com/",
+            "contoso.onmicrosoft.com",
+            "client".to_string(),
+            "s3cr3t".to_string(),
+        );
+        assert_eq!(
+            credentials.token_url,
+            "https://login.microsoftonline.com/contoso.onmicrosoft.com/oauth2/v2.0/token"
+        );
+        assert!(!format!("{credentials:?}").contains("s3cr3t"));
+    }
+}
@@ -0,0 +1,159 @@
+//! Settings for the Mailgun v3 API.
+//!
+//! By default the message is posted to the endpoint that accepts raw MIME messages, as
+//! `multipart/form-data` with one `to` field per recipient and the message as `message` file
+//! part. With [`ApiFormat::MailgunV3`](crate::args::ApiFormat::MailgunV3) the message is
+//! converted to the form fields of the `messages` endpoint instead (`from`, `to`, `subject`,
+//! `text`, `html`), dropping attachments. Errors (`{"message": ..}`) and the `id` of accepted
+//! messages use the shapes the generic provider already understands.
+
+use lettre::Address;
+use log::{debug, warn};
+use rootcause::prelude::*;
+use url::form_urlencoded;
+
+use super::message;
+use crate::encoding;
+use crate::parser;
+
+/// User name Mailgun expects for HTTP Basic authentication, the token is the password
+pub const BASIC_AUTH_USER: &str = "api";
+
+/// Path of the endpoint that accepts raw MIME messages for `domain`
+#[must_use]
+pub fn messages_path(domain: &str) -> String {
+    format!("/v3/{domain}/messages.mime")
+}
+
+/// Path of the endpoint that builds the message from form fields for `domain`
+#[must_use]
+pub fn form_messages_path(domain: &str) -> String {
+    format!("/v3/{domain}/messages")
+}
+
+/// Build the `application/x-www-form-urlencoded` body for the `messages` endpoint from a message
+/// consisting of `head` followed by `body`.
+///
+/// Envelope recipients that appear in the `To:` or `Cc:` header are sent as `to` or `cc`, all
+/// others as `bcc`. If none of them is in the `To:` header, all recipients are sent as `to`
+/// together with empty `recipient-variables`, which makes Mailgun send every recipient a copy
+/// of their own.
+pub(super) fn form_body(
+    envelope_from: &Address,
+    envelope_to: &[&Address],
+    head: &str,
+    body: &[u8],
+) -> Result<Vec<u8>, Report> {
+    let (headers, content) = message::parse(head, body);
+    if content.skipped > 0 {
+        warn!(
+            "API backend: the Mailgun messages endpoint does not take raw messages, dropping {} non-text part(s)",
+            content.skipped
+        );
+    }
+    let text = content.text.filter(|text| !text.is_empty());
+    let html = content.html.filter(|html| !html.is_empty());
+    if text.is_none() && html.is_none() {
+        return Err(report!(
+            "Mailgun requires a message with a non-empty text or HTML body"
+        ));
+    }
+
+    let address_list = |addresses: &[&Address]| {
+        addresses
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut form = form_urlencoded::Serializer::new(String::new());
+    form.append_pair(
+        "from",
+        &message::sender_mailbox(&headers, envelope_from).to_string(),
+    );
+    let recipients = message::recipients(&headers, envelope_to);
+    if recipients.to.is_empty() {
+        debug!("API backend: no recipient in the To: header, sending one message each");
+        form.append_pair("to", &address_list(envelope_to));
+        form.append_pair("recipient-variables", "{}");
+    } else {
+        form.append_pair("to", &address_list(&recipients.to));
+        if !recipients.cc.is_empty() {
+            form.append_pair("cc", &address_list(&recipients.cc));
+        }
+        if !recipients.bcc.is_empty() {
+            form.append_pair("bcc", &address_list(&recipients.bcc));
+        }
+    }
+    if let Some(subject) = parser::header_values(&headers, "Subject").next() {
+        form.append_pair("subject", &encoding::decode_rfc2047(subject));
+    }
+    if let Some(text) = &text {
+        form.append_pair("text", text);
+    }
+    if let Some(html) = &html {
+        form.append_pair("html", html);
+    }
+    if let Some(reply_to) = parser::header_values(&headers, "Reply-To").next() {
+        form.append_pair("h:Reply-To", reply_to.trim());
+    }
+    Ok(form.finish().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn fields(envelope_to: &[&str], email: &str) -> Vec<(String, String)> {
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to: Vec<Address> = envelope_to
+            .iter()
+            .map(|address| Address::from_str(address).unwrap())
+            .collect();
+        let to_refs: Vec<&Address> = to.iter().collect();
+        let (head, body) = email.split_once("\n\n").unwrap();
+        let body = form_body(&from, &to_refs, &format!("{head}\n\n"), body.as_bytes()).unwrap();
+        form_urlencoded::parse(&body).into_owned().collect()
+    }
+
+    #[test]
+    fn test_form_body() {
+        let fields = fields(
+            &["a@example.com", "b@example.com", "hidden@example.com"],
+            "From: Sender <sender@example.com>\nTo: a@example.com\nCc: b@example.com\n\
+             Reply-To: help@example.com\nSubject: =?UTF-8?Q?Gr=C3=BC=C3=9Fe?=\n\nHello & bye",
+        );
+        let expected = [
+            ("from", "Sender <sender@example.com>"),
+            ("to", "a@example.com"),
+            ("cc", "b@example.com"),
+            ("bcc", "hidden@example.com"),
+            ("subject", "Grüße"),
+            ("text", "Hello & bye"),
+            ("h:Reply-To", "help@example.com"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(fields, expected);
+    }
+
+    #[test]
+    fn test_form_body_without_to_header_recipient() {
+        let fields = fields(&["a@example.com", "b@example.com"], "Subject: Hi\n\nBody");
+        assert!(fields.contains(&("to".to_string(), "a@example.com, b@example.com".to_string())));
+        assert!(fields.contains(&("recipient-variables".to_string(), "{}".to_string())));
+        assert!(!fields.iter().any(|(name, _)| name == "bcc"));
+    }
+
+    #[test]
+    fn test_form_body_requires_content() {
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("a@example.com").unwrap();
+        let head = "Content-Type: application/octet-stream\n\n";
+        assert!(form_body(&from, &[&to], head, b"data").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Content extraction for APIs that do not accept raw messages.
+//!
+//! The message is walked for its first text and HTML part, which are decoded from their
+//! transfer encoding. Everything else (like attachments) is counted so callers can warn about
+//! dropping it.
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use lettre::{
+    Address,
+    message::{Mailbox, Mailboxes},
+};
+
+use crate::encoding;
+use crate::parser::{self, HeaderField};
+
+/// Maximum nesting of multipart bodies that is searched for text parts
+const MAX_MULTIPART_DEPTH: usize = 8;
+
+/// Text and HTML content found in a message
+#[derive(Debug, Default)]
+pub(super) struct Content {
+    pub(super) text: Option<String>,
+    pub(super) html: Option<String>,
+    /// Number of parts that are neither text nor HTML (like attachments)
+    pub(super) skipped: usize,
+}
+
+/// Parse a message consisting of `head` followed by `body` into its headers and content.
+pub(super) fn parse(head: &str, body: &[u8]) -> (Vec<HeaderField>, Content) {
+    // The whole message is passed as `head` when it was not streamed
+    let (head, rest) = split_part(head.as_bytes());
+    let body = [rest, body].concat();
+    let headers = parser::parse_email_headers(&head);
+
+    let mut content = Content::default();
+    collect_content(&headers, &body, &mut content, 0);
+    (headers, content)
+}
+
+/// All addresses in the header fields named `name`, skipping fields that do not parse
+fn header_addresses(headers: &[HeaderField], name: &str) -> Vec<Address> {
+    parser::header_values(headers, name)
+        .filter_map(|value| parser::parse_mailboxes_header(value).ok())
+        .flatten()
+        .collect()
+}
+
+/// Envelope recipients sorted by where they appear in the message headers
+#[derive(Debug, Default)]
+pub(super) struct Recipients<'a> {
+    /// Recipients listed in the `To:` header
+    pub(super) to: Vec<&'a Address>,
+    /// Recipients listed in the `Cc:` header
+    pub(super) cc: Vec<&'a Address>,
+    /// All other recipients
+    pub(super) bcc: Vec<&'a Address>,
+}
+
+/// Sort the envelope recipients into `To`, `Cc` and `Bcc` by the headers of the message.
+pub(super) fn recipients<'a>(
+    headers: &[HeaderField],
+    envelope_to: &[&'a Address],
+) -> Recipients<'a> {
+    let header_to = header_addresses(headers, "To");
+    let header_cc = header_addresses(headers, "Cc");
+    let mut recipients = Recipients::default();
+    for recipient in envelope_to {
+        let list = if header_to.contains(recipient) {
+            &mut recipients.to
+        } else if header_cc.contains(recipient) {
+            &mut recipients.cc
+        } else {
+            &mut recipients.bcc
+        };
+        list.push(*recipient);
+    }
+    recipients
+}
+
+/// The sender is always the envelope sender. Its display name is taken from the `From:` header
+/// if the header contains the same address.
+pub(super) fn sender_mailbox(headers: &[HeaderField], envelope_from: &Address) -> Mailbox {
+    let name = parser::header_values(headers, "From")
+        .next()
+        .and_then(|value| value.parse::<Mailboxes>().ok())
+        .and_then(|mailboxes| {
+            mailboxes
+                .into_iter()
+                .find(|mailbox| &mailbox.email == envelope_from)
+        })
+        .and_then(|mailbox| mailbox.name);
+    Mailbox::new(name, envelope_from.clone())
+}
+
+/// Lowercased media type and parameters of a `Content-Type:` header value
+fn parse_content_type(value: &str) -> (String, Vec<(String, String)>) {
+    let mut parts = value.split(';');
+    let media_type = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+    let params = parts
+        .filter_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            let value = value.trim().trim_matches('"');
+            Some((name.trim().to_ascii_lowercase(), value.to_string()))
+        })
+        .collect();
+    (media_type, params)
+}
+
+/// Walk a (possibly multipart) message body and keep the first text and HTML part.
+fn collect_content(headers: &[HeaderField], body: &[u8], content: &mut Content, depth: usize) {
+    let (media_type, params) = parser::header_values(headers, "Content-Type")
+        .next()
+        .map(parse_content_type)
+        .unwrap_or_else(|| ("text/plain".to_string(), Vec::new()));
+    let is_attachment = parser::header_values(headers, "Content-Disposition")
+        .next()
+        .is_some_and(|value| {
+            value
+                .trim_start()
+                .to_ascii_lowercase()
+                .starts_with("attachment")
+        });
+
+    if media_type.starts_with("multipart/") && depth < MAX_MULTIPART_DEPTH {
+        let boundary = params
+            .iter()
+            .find(|(name, _)| name == "boundary")
+            .map(|(_, value)| value.as_str());
+        if let Some(boundary) = boundary {
+            for part in split_multipart(body, boundary) {
+                let (part_head, part_body) = split_part(part);
+                let part_headers = parser::parse_email_headers(&part_head);
+                collect_content(&part_headers, part_body, content, depth + 1);
+            }
+            return;
+        }
+    }
+
+    let slot = match media_type.as_str() {
+        "text/plain" if !is_attachment => &mut content.text,
+        "text/html" if !is_attachment => &mut content.html,
+        _ => {
+            content.skipped += 1;
+            return;
+        }
+    };
+    if slot.is_some() {
+        content.skipped += 1;
+        return;
+    }
+
+    let transfer_encoding = parser::header_values(headers, "Content-Transfer-Encoding")
+        .next()
+        .map(|value| value.trim().to_ascii_lowercase());
+    let decoded = match transfer_encoding.as_deref() {
+        Some("base64") => {
+            let data: Vec<u8> = body
+                .iter()
+                .copied()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect();
+            BASE64_STANDARD
+                .decode(data)
+                .unwrap_or_else(|_| body.to_vec())
+        }
+        Some("quoted-printable") => encoding::decode_quoted_printable(body),
+        _ => body.to_vec(),
+    };
+    *slot = Some(String::from_utf8_lossy(&decoded).into_owned());
+}
+
+/// Split a multipart body into its parts, dropping the preamble and the epilogue.
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{boundary}");
+    let mut parts = Vec::new();
+    let mut part_start = None;
+    let mut offset = 0;
+
+    for line in body.split_inclusive(|&b| b == b'\n') {
+        let trimmed = line.trim_ascii_end();
+        if let Some(rest) = trimmed.strip_prefix(delimiter.as_bytes()) {
+            if let Some(start) = part_start {
+                // The line break before the delimiter belongs to the delimiter
+                let end = if body[..offset].ends_with(b"\r\n") {
+                    offset - 2
+                } else if body[..offset].ends_with(b"\n") {
+                    offset - 1
+                } else {
+                    offset
+                };
+                parts.push(&body[start..end.max(start)]);
+            }
+            if rest.starts_with(b"--") {
+                return parts;
+            }
+            part_start = Some(offset + line.len());
+        }
+        offset += line.len();
+    }
+    // Unterminated multipart: keep the last part
+    if let Some(start) = part_start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+/// Split a body part into its header section and its content.
+fn split_part(part: &[u8]) -> (String, &[u8]) {
+    let mut offset = 0;
+    for line in part.split_inclusive(|&b| b == b'\n') {
+        offset += line.len();
+        if line.trim_ascii().is_empty() {
+            let head = String::from_utf8_lossy(&part[..offset]).into_owned();
+            return (head, &part[offset..]);
+        }
+    }
+    // A part without an empty line only has headers
+    (String::from_utf8_lossy(part).into_owned(), &[])
+}
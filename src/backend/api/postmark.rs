@@ -0,0 +1,325 @@
+//! Request and response mapping for the Postmark email API (`POST /email`).
+//!
+//! Postmark does not accept raw messages, so the envelope and the parsed message are converted
+//! to its JSON request shape with the text and HTML bodies; attachments are dropped. Messages
+//! without a recipient in the `To:` header are sent through `POST /email/batch` as one message
+//! per recipient, so the recipients are not disclosed to each other.
+//!
+//! Postmark reports some failures with status 200 and a nonzero `ErrorCode`, so the body of
+//! successful responses has to be checked as well.
+
+use lettre::Address;
+use log::{debug, warn};
+use rootcause::prelude::*;
+use serde_json::{Value, json};
+
+use super::{BackendError, JsonError, message, truncate_message};
+use crate::encoding;
+use crate::parser;
+
+/// Header Postmark expects the server token in
+pub const AUTH_HEADER: &str = "X-Postmark-Server-Token";
+
+/// Path of the endpoint that sends a single message
+pub const EMAIL_PATH: &str = "/email";
+
+/// `ErrorCode` for recipients that bounced, complained or unsubscribed before
+const INACTIVE_RECIPIENT: i64 = 406;
+
+/// `ErrorCode` for requests rejected because of rate limiting
+const RATE_LIMITED: i64 = 429;
+
+/// A request for the Postmark API
+#[derive(Debug)]
+pub(super) struct Request {
+    pub(super) json: Vec<u8>,
+    /// Whether the request has to go to the batch endpoint
+    pub(super) batch: bool,
+}
+
+/// Build the request for a message consisting of `head` followed by `body`.
+///
+/// Envelope recipients that appear in the `To:` or `Cc:` header are sent as `To` or `Cc`, all
+/// others as `Bcc`. Postmark requires a `To` recipient, so if there is none a batch of one
+/// message per recipient is built instead.
+pub(super) fn request_body(
+    envelope_from: &Address,
+    envelope_to: &[&Address],
+    head: &str,
+    body: &[u8],
+) -> Result<Request, Report> {
+    let (headers, content) = message::parse(head, body);
+    if content.skipped > 0 {
+        warn!(
+            "API backend: Postmark does not support raw messages, dropping {} non-text part(s)",
+            content.skipped
+        );
+    }
+    let text = content.text.filter(|text| !text.is_empty());
+    let html = content.html.filter(|html| !html.is_empty());
+    if text.is_none() && html.is_none() {
+        return Err(report!(
+            "Postmark requires a message with a non-empty text or HTML body"
+        ));
+    }
+
+    let sender = message::sender_mailbox(&headers, envelope_from);
+    let mut message = json!({"From": sender.to_string()});
+    if let Some(subject) = parser::header_values(&headers, "Subject").next() {
+        message["Subject"] = Value::from(encoding::decode_rfc2047(subject));
+    }
+    if let Some(text) = text {
+        message["TextBody"] = Value::from(text);
+    }
+    if let Some(html) = html {
+        message["HtmlBody"] = Value::from(html);
+    }
+    if let Some(reply_to) = parser::header_values(&headers, "Reply-To").next() {
+        message["ReplyTo"] = Value::from(reply_to.trim());
+    }
+
+    let recipients = message::recipients(&headers, envelope_to);
+    let (request, batch) = if recipients.to.is_empty() {
+        debug!("API backend: no recipient in the To: header, sending one message each");
+        let messages: Vec<Value> = envelope_to
+            .iter()
+            .map(|recipient| {
+                let mut message = message.clone();
+                message["To"] = Value::from(recipient.to_string());
+                message
+            })
+            .collect();
+        (Value::from(messages), true)
+    } else {
+        message["To"] = Value::from(address_list(&recipients.to));
+        if !recipients.cc.is_empty() {
+            message["Cc"] = Value::from(address_list(&recipients.cc));
+        }
+        if !recipients.bcc.is_empty() {
+            message["Bcc"] = Value::from(address_list(&recipients.bcc));
+        }
+        (message, false)
+    };
+
+    let json = serde_json::to_vec(&request)
+        .map_err(|e| report!("Failed to encode Postmark request: {e}"))?;
+    Ok(Request { json, batch })
+}
+
+/// Extract the error from Postmark's error shape: `{"ErrorCode": .., "Message": ..}`.
+/// Returns `None` for other bodies.
+pub(super) fn parse_error(body: &str) -> Option<JsonError> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let code = value.get("ErrorCode")?.as_i64()?;
+    Some(JsonError {
+        message: value
+            .get("Message")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        code: Some(code.to_string()),
+    })
+}
+
+/// Whether an error code taken from [`parse_error`] means the request was rate limited
+pub(super) fn is_rate_limited(code: &str) -> bool {
+    code == RATE_LIMITED.to_string()
+}
+
+/// Check the body of a successful response and return the identifier of the accepted message.
+///
+/// A batch is answered with one result per message, in the order of `envelope_to`. If only
+/// some of them failed, the message was partially delivered.
+pub(super) fn check_response(
+    body: Option<&str>,
+    envelope_to: &[&Address],
+    batch: bool,
+    error_message_limit: usize,
+) -> Result<Option<String>, BackendError> {
+    let Some(value) = body.and_then(|body| serde_json::from_str::<Value>(body).ok()) else {
+        debug!("API backend: Postmark response is not JSON, assuming success");
+        return Ok(None);
+    };
+    let results = match (&value, batch) {
+        (Value::Array(results), true) => results.iter().collect(),
+        _ => vec![&value],
+    };
+
+    let mut message_ids = Vec::new();
+    let mut accepted = Vec::new();
+    let mut rejected = Vec::new();
+    let mut rate_limited = true;
+    for (index, result) in results.iter().enumerate() {
+        let code = result.get("ErrorCode").and_then(Value::as_i64).unwrap_or(0);
+        if code != 0 {
+            debug!("API backend: Postmark result {index} has error code {code}: {result}");
+        }
+        let message = result
+            .get("Message")
+            .and_then(Value::as_str)
+            .map(|message| truncate_message(message, error_message_limit))
+            .unwrap_or_else(|| format!("Postmark error {code}"));
+        let recipient = match (batch, envelope_to.get(index)) {
+            (true, Some(recipient)) => recipient.to_string(),
+            _ => envelope_to
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+
+        if code == 0 {
+            if let Some(id) = result.get("MessageID").and_then(Value::as_str) {
+                message_ids.push(id.to_string());
+            }
+            accepted.push(recipient);
+            continue;
+        }
+        debug!("API backend: Postmark error code={code} for {recipient}");
+        if code == INACTIVE_RECIPIENT {
+            // Inactive recipients stay suppressed, so this is a permanent failure
+            warn!("API backend: Postmark does not deliver to the inactive recipient {recipient}");
+        }
+        rate_limited &= code == RATE_LIMITED;
+        rejected.push((recipient, message, code));
+    }
+
+    if rejected.is_empty() {
+        return Ok((!message_ids.is_empty()).then(|| message_ids.join(", ")));
+    }
+    if !accepted.is_empty() {
+        return Err(BackendError::PartialDelivery {
+            accepted,
+            rejected: rejected
+                .into_iter()
+                .map(|(recipient, message, _)| (recipient, message))
+                .collect(),
+        });
+    }
+
+    let mut report = report!("API request failed: {}", rejected[0].1).into_dynamic();
+    for (recipient, message, code) in &rejected {
+        report = report.attach(format!(
+            "Rejected: {recipient}: {message} (error code {code})"
+        ));
+    }
+    if rate_limited {
+        // Rate limiting goes away when retrying later, unlike the other errors
+        return Err(BackendError::RateLimited {
+            report,
+            retry_after: None,
+        });
+    }
+    Err(report.into())
+}
+
+/// Comma-separated list of addresses, as Postmark expects in `To`, `Cc` and `Bcc`
+fn address_list(addresses: &[&Address]) -> String {
+    addresses
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn address(address: &str) -> Address {
+        Address::from_str(address).unwrap()
+    }
+
+    fn request(envelope_to: &[&str], email: &str) -> (Value, bool) {
+        let from = address("sender@example.com");
+        let to: Vec<Address> = envelope_to.iter().map(|to| address(to)).collect();
+        let to_refs: Vec<&Address> = to.iter().collect();
+        let (head, body) = email.split_once("\n\n").unwrap();
+        let request =
+            request_body(&from, &to_refs, &format!("{head}\n\n"), body.as_bytes()).unwrap();
+        (
+            serde_json::from_slice(&request.json).unwrap(),
+            request.batch,
+        )
+    }
+
+    #[test]
+    fn test_request_body() {
+        let (request, batch) = request(
+            &["to@example.com", "cc@example.com", "hidden@example.com"],
+            "From: Sender <sender@example.com>\nTo: to@example.com\nCc: cc@example.com\nReply-To: reply@example.com\nSubject: Hello\n\nBody text",
+        );
+        assert!(!batch);
+        assert_eq!(
+            request,
+            json!({
+                "From": "Sender <sender@example.com>",
+                "To": "to@example.com",
+                "Cc": "cc@example.com",
+                "Bcc": "hidden@example.com",
+                "ReplyTo": "reply@example.com",
+                "Subject": "Hello",
+                "TextBody": "Body text",
+            })
+        );
+    }
+
+    #[test]
+    fn test_request_body_without_to_header_recipient() {
+        let (request, batch) = request(
+            &["a@example.com", "b@example.com"],
+            "Subject: Hello\n\nBody",
+        );
+        assert!(batch);
+        let to: Vec<&str> = request
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["To"].as_str().unwrap())
+            .collect();
+        assert_eq!(to, ["a@example.com", "b@example.com"]);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = parse_error(r#"{"ErrorCode": 300, "Message": "Invalid 'From' address."}"#);
+        assert_eq!(
+            error,
+            Some(JsonError {
+                message: Some("Invalid 'From' address.".to_string()),
+                code: Some("300".to_string()),
+            })
+        );
+        assert_eq!(parse_error(r#"{"message": "other"}"#), None);
+        assert!(is_rate_limited("429"));
+        assert!(!is_rate_limited("406"));
+    }
+
+    #[test]
+    fn test_check_response() {
+        let a = address("a@example.com");
+        let b = address("b@example.com");
+
+        let ok = r#"{"ErrorCode": 0, "Message": "OK", "MessageID": "pm-1"}"#;
+        let id = check_response(Some(ok), &[&a], false, 100).unwrap();
+        assert_eq!(id.as_deref(), Some("pm-1"));
+
+        let inactive =
+            r#"{"ErrorCode": 406, "Message": "You tried to send to an inactive recipient."}"#;
+        let err = check_response(Some(inactive), &[&a], false, 100).unwrap_err();
+        assert!(matches!(err, BackendError::Failed(_)));
+
+        let limited = r#"{"ErrorCode": 429, "Message": "Rate limit exceeded."}"#;
+        let err = check_response(Some(limited), &[&a], false, 100).unwrap_err();
+        assert!(matches!(err, BackendError::RateLimited { .. }));
+
+        let partial = format!("[{ok}, {inactive}]");
+        let err = check_response(Some(&partial), &[&a, &b], true, 100).unwrap_err();
+        let BackendError::PartialDelivery { accepted, rejected } = err else {
+            panic!("expected a partial delivery");
+        };
+        assert_eq!(accepted, ["a@example.com"]);
+        assert_eq!(rejected[0].0, "b@example.com");
+    }
+}
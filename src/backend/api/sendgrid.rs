@@ -0,0 +1,252 @@
+//! Request and response mapping for the SendGrid v3 mail send API (`POST /v3/mail/send`).
+//!
+//! SendGrid does not accept raw messages, so the envelope and the parsed message are converted
+//! to its JSON request shape. Only the text and HTML bodies are transferred; attachments and
+//! custom headers are dropped.
+
+use lettre::{Address, message::Mailboxes};
+use log::{debug, warn};
+use rootcause::prelude::*;
+use serde_json::{Value, json};
+
+use super::{JsonError, message};
+use crate::encoding;
+use crate::parser::{self, HeaderField};
+
+/// Build the JSON request body for a message consisting of `head` followed by `body`.
+///
+/// Envelope recipients that appear in the `To:` or `Cc:` header are sent as `to` or `cc`, all
+/// others as `bcc`. SendGrid requires a `to` recipient, so if there is none every recipient
+/// gets a personalization of its own instead.
+pub(super) fn request_body(
+    envelope_from: &Address,
+    envelope_to: &[&Address],
+    head: &str,
+    body: &[u8],
+) -> Result<Vec<u8>, Report> {
+    let (headers, content) = message::parse(head, body);
+    if content.skipped > 0 {
+        warn!(
+            "API backend: SendGrid does not support raw messages, dropping {} non-text part(s)",
+            content.skipped
+        );
+    }
+    let mut contents = Vec::new();
+    if let Some(text) = content.text.filter(|text| !text.is_empty()) {
+        contents.push(json!({"type": "text/plain", "value": text}));
+    }
+    if let Some(html) = content.html.filter(|html| !html.is_empty()) {
+        contents.push(json!({"type": "text/html", "value": html}));
+    }
+    if contents.is_empty() {
+        return Err(report!(
+            "SendGrid requires a message with a non-empty text or HTML body"
+        ));
+    }
+
+    let mut request = json!({
+        "personalizations": personalizations(&headers, envelope_to),
+        "from": from_field(&headers, envelope_from),
+        "content": contents,
+    });
+    if let Some(subject) = parser::header_values(&headers, "Subject").next() {
+        request["subject"] = Value::from(encoding::decode_rfc2047(subject));
+    }
+    if let Some(reply_to) = parser::header_values(&headers, "Reply-To")
+        .next()
+        .and_then(|value| value.parse::<Mailboxes>().ok())
+        .and_then(|mailboxes| mailboxes.into_single())
+    {
+        request["reply_to"] = email_object(&reply_to.email, reply_to.name.as_deref());
+    }
+
+    serde_json::to_vec(&request).map_err(|e| report!("Failed to encode SendGrid request: {e}"))
+}
+
+/// Extract the error messages from SendGrid's error envelope:
+/// `{"errors": [{"message": .., "field": ..}]}`. Returns `None` for other bodies.
+pub(super) fn parse_error(body: &str) -> Option<JsonError> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let errors = value.get("errors")?.as_array()?;
+    let messages: Vec<String> = errors
+        .iter()
+        .filter_map(|error| {
+            let message = error.get("message")?.as_str()?;
+            Some(match error.get("field").and_then(Value::as_str) {
+                Some(field) => format!("{field}: {message}"),
+                None => message.to_string(),
+            })
+        })
+        .collect();
+    Some(JsonError {
+        message: (!messages.is_empty()).then(|| messages.join("; ")),
+        code: None,
+    })
+}
+
+/// Identifier SendGrid assigned to an accepted message
+pub(super) fn message_id(response: &ureq::Response) -> Option<String> {
+    response
+        .header("X-Message-Id")
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+}
+
+fn email_object(address: &Address, name: Option<&str>) -> Value {
+    match name.filter(|name| !name.is_empty()) {
+        Some(name) => json!({"email": address.to_string(), "name": name}),
+        None => json!({"email": address.to_string()}),
+    }
+}
+
+/// The sender is always the envelope sender. Its display name is taken from the `From:` header
+/// if the header contains the same address.
+fn from_field(headers: &[HeaderField], envelope_from: &Address) -> Value {
+    let sender = message::sender_mailbox(headers, envelope_from);
+    email_object(&sender.email, sender.name.as_deref())
+}
+
+fn personalizations(headers: &[HeaderField], envelope_to: &[&Address]) -> Value {
+    let recipients = message::recipients(headers, envelope_to);
+    let email_objects = |addresses: &[&Address]| -> Vec<Value> {
+        addresses
+            .iter()
+            .map(|address| email_object(address, None))
+            .collect()
+    };
+
+    if recipients.to.is_empty() {
+        debug!("API backend: no recipient in the To: header, sending one personalization each");
+        return envelope_to
+            .iter()
+            .map(|recipient| json!({"to": [email_object(recipient, None)]}))
+            .collect();
+    }
+
+    let mut personalization = json!({"to": email_objects(&recipients.to)});
+    if !recipients.cc.is_empty() {
+        personalization["cc"] = Value::from(email_objects(&recipients.cc));
+    }
+    if !recipients.bcc.is_empty() {
+        personalization["bcc"] = Value::from(email_objects(&recipients.bcc));
+    }
+    json!([personalization])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn request(envelope_to: &[&str], email: &str) -> Value {
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to: Vec<Address> = envelope_to
+            .iter()
+            .map(|address| Address::from_str(address).unwrap())
+            .collect();
+        let to_refs: Vec<&Address> = to.iter().collect();
+        let (head, body) = email.split_once("\n\n").unwrap();
+        let body = request_body(&from, &to_refs, &format!("{head}\n\n"), body.as_bytes()).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_request_body_plain_text() {
+        let request = request(
+            &["a@example.com", "b@example.com", "hidden@example.com"],
+            "From: Sender Name <sender@example.com>\nTo: a@example.com\nCc: b@example.com\n\
+             Subject: =?UTF-8?Q?Gr=C3=BC=C3=9Fe?=\nReply-To: help@example.com\n\nHello\n",
+        );
+        assert_eq!(
+            request,
+            json!({
+                "personalizations": [{
+                    "to": [{"email": "a@example.com"}],
+                    "cc": [{"email": "b@example.com"}],
+                    "bcc": [{"email": "hidden@example.com"}],
+                }],
+                "from": {"email": "sender@example.com", "name": "Sender Name"},
+                "subject": "Grüße",
+                "reply_to": {"email": "help@example.com"},
+                "content": [{"type": "text/plain", "value": "Hello\n"}],
+            })
+        );
+    }
+
+    #[test]
+    fn test_request_body_without_to_header_recipient() {
+        let request = request(
+            &["one@example.com", "two@example.com"],
+            "Subject: Hidden\n\nBody",
+        );
+        assert_eq!(
+            request["personalizations"],
+            json!([
+                {"to": [{"email": "one@example.com"}]},
+                {"to": [{"email": "two@example.com"}]},
+            ])
+        );
+        assert!(request.get("reply_to").is_none());
+    }
+
+    #[test]
+    fn test_request_body_multipart_alternative() {
+        let email = "To: a@example.com\nContent-Type: multipart/mixed; boundary=\"outer\"\n\n\
+                     preamble\n\
+                     --outer\n\
+                     Content-Type: multipart/alternative; boundary=inner\n\n\
+                     --inner\n\
+                     Content-Type: text/plain; charset=utf-8\n\
+                     Content-Transfer-Encoding: quoted-printable\n\n\
+                     Gr=C3=BC=C3=9Fe=\n aus K=C3=B6ln\n\
+                     --inner\n\
+                     Content-Type: text/html\n\
+                     Content-Transfer-Encoding: base64\n\n\
+                     PHA+SGk8L3A+\n\
+                     --inner--\n\
+                     --outer\n\
+                     Content-Type: application/pdf\n\
+                     Content-Disposition: attachment; filename=a.pdf\n\n\
+                     JVBERi0=\n\
+                     --outer--\n\
+                     epilogue\n";
+        let request = request(&["a@example.com"], email);
+        assert_eq!(
+            request["content"],
+            json!([
+                {"type": "text/plain", "value": "Grüße aus Köln"},
+                {"type": "text/html", "value": "<p>Hi</p>"},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_request_body_requires_content() {
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("a@example.com").unwrap();
+        let head = "Content-Type: application/octet-stream\n\n";
+        assert!(request_body(&from, &[&to], head, b"data").is_err());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let body = r#"{"errors": [
+            {"message": "The from address does not match a verified Sender Identity.", "field": "from", "help": null},
+            {"message": "Permission denied"}
+        ]}"#;
+        assert_eq!(
+            parse_error(body),
+            Some(JsonError {
+                message: Some(
+                    "from: The from address does not match a verified Sender Identity.; Permission denied"
+                        .to_string()
+                ),
+                code: None,
+            })
+        );
+        assert_eq!(parse_error(r#"{"error": "other shape"}"#), None);
+        assert_eq!(parse_error("not json"), None);
+    }
+}
@@ -0,0 +1,132 @@
+//! Request and response mapping for the Amazon SES v2 `SendEmail` API with raw content
+//! (`POST /v2/email/outbound-emails`).
+//!
+//! The message is sent unchanged as base64 in `Content.Raw.Data`, with the envelope as
+//! `FromEmailAddress` and `Destination`. Requests are signed with AWS Signature Version 4.
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use lettre::Address;
+use rootcause::prelude::*;
+use serde_json::{Value, json};
+
+use super::JsonError;
+
+/// Path of the `SendEmail` endpoint
+pub const SEND_EMAIL_PATH: &str = "/v2/email/outbound-emails";
+
+/// Service name used in the SigV4 credential scope
+pub const SERVICE: &str = "ses";
+
+/// Error types SES uses for throttled requests and exceeded sending quotas
+const THROTTLING_ERRORS: [&str; 3] = [
+    "TooManyRequestsException",
+    "ThrottlingException",
+    "Throttling",
+];
+
+/// Build the JSON request body for a message consisting of `head` followed by `body`.
+pub(super) fn request_body(
+    envelope_from: &Address,
+    envelope_to: &[&Address],
+    head: &str,
+    body: &[u8],
+) -> Result<Vec<u8>, Report> {
+    let raw = [head.as_bytes(), body].concat();
+    let recipients: Vec<String> = envelope_to.iter().map(ToString::to_string).collect();
+    let request = json!({
+        "FromEmailAddress": envelope_from.to_string(),
+        "Destination": {"ToAddresses": recipients},
+        "Content": {"Raw": {"Data": BASE64_STANDARD.encode(raw)}},
+    });
+    serde_json::to_vec(&request).map_err(|e| report!("Failed to encode SES request: {e}"))
+}
+
+/// Extract the error from the SES error shape: `{"message": ..}` with the error type in the
+/// `x-amzn-ErrorType` header (`error_type`) or the `__type` field. Returns `None` for other
+/// bodies.
+pub(super) fn parse_error(body: &str, error_type: Option<&str>) -> Option<JsonError> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let message = value
+        .get("message")
+        .or_else(|| value.get("Message"))
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let code = error_type
+        .or_else(|| value.get("__type").and_then(Value::as_str))
+        // The type may be followed by `:` and a URL, or preceded by a namespace and `#`
+        .map(|code| code.split(':').next().unwrap_or(code))
+        .map(|code| code.rsplit('#').next().unwrap_or(code).to_string());
+    Some(JsonError { message, code })
+}
+
+/// Whether an error type taken from [`parse_error`] means the request was throttled
+pub(super) fn is_throttling(code: &str) -> bool {
+    THROTTLING_ERRORS.contains(&code)
+}
+
+/// Identifier SES assigned to an accepted message
+pub(super) fn message_id(body: Option<&str>) -> Option<String> {
+    let value: Value = serde_json::from_str(body?).ok()?;
+    value
+        .get("MessageId")
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_request_body() {
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        let body = request_body(&from, &[&to], "Subject: Test\r\n\r\n", b"Body").unwrap();
+        let request: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "FromEmailAddress": "sender@example.com",
+                "Destination": {"ToAddresses": ["recipient@example.com"]},
+                "Content": {"Raw": {"Data": BASE64_STANDARD.encode("Subject: Test\r\n\r\nBody")}},
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = parse_error(
+            r#"{"message": "Maximum sending rate exceeded."}"#,
+            Some("TooManyRequestsException:http://internal.amazon.com/coral/com.amazon.coral.service/"),
+        )
+        .unwrap();
+        assert_eq!(
+            error.message.as_deref(),
+            Some("Maximum sending rate exceeded.")
+        );
+        assert_eq!(error.code.as_deref(), Some("TooManyRequestsException"));
+        assert!(is_throttling(error.code.as_deref().unwrap()));
+
+        let error = parse_error(
+            r#"{"__type": "com.amazonaws.sesv2#MessageRejected", "message": "Email address is not verified."}"#,
+            None,
+        )
+        .unwrap();
+        assert_eq!(error.code.as_deref(), Some("MessageRejected"));
+        assert!(!is_throttling("MessageRejected"));
+    }
+
+    #[test]
+    fn test_message_id() {
+        assert_eq!(
+            message_id(Some(r#"{"MessageId": "0100018c-abc"}"#)).as_deref(),
+            Some("0100018c-abc")
+        );
+        assert_eq!(message_id(Some("{}")), None);
+        assert_eq!(message_id(None), None);
+    }
+}
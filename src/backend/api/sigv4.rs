@@ -0,0 +1,310 @@
+//! AWS Signature Version 4 request signing.
+//!
+//! Only what is needed to sign a single request is implemented: the canonical request, the
+//! string to sign and the HMAC chain deriving the signing key. The `Host` and `X-Amz-Date`
+//! headers (and `X-Amz-Security-Token` for temporary credentials) are always signed.
+
+use std::time::SystemTime;
+
+use ring::{digest, hmac};
+use url::Url;
+
+use crate::date;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// Credentials and scope used to sign requests
+#[derive(Clone, PartialEq, Eq)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Session token of temporary credentials
+    pub session_token: Option<String>,
+    pub region: String,
+}
+
+impl std::fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The secret key and the session token are never printed
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("region", &self.region)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Lowercase hex encoding of `data`
+pub(super) fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Hex-encoded SHA-256 hash of a payload, as used in the canonical request
+pub(super) fn payload_hash(payload: &mut dyn std::io::Read) -> std::io::Result<String> {
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buffer = [0; 8192];
+    loop {
+        let read = payload.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hex(context.finish().as_ref()));
+        }
+        context.update(&buffer[..read]);
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> hmac::Tag {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+}
+
+/// URI-encode a string as required by SigV4: everything but unreserved characters is encoded.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Value of the `Host` header ureq sends for `url`
+fn host_header(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    }
+}
+
+/// Derive the signing key for a date (`YYYYMMDD`), region and service.
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> hmac::Tag {
+    let key = hmac_sha256(format!("AWS4{secret_access_key}").as_bytes(), date);
+    let key = hmac_sha256(key.as_ref(), region);
+    let key = hmac_sha256(key.as_ref(), service);
+    hmac_sha256(key.as_ref(), "aws4_request")
+}
+
+/// Build the canonical request and the list of signed header names.
+fn canonical_request(
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    payload_hash: &str,
+) -> (String, String) {
+    let path = if url.path().is_empty() {
+        "/"
+    } else {
+        url.path()
+    };
+    let canonical_uri = uri_encode(path, false);
+
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| (uri_encode(&name, true), uri_encode(&value, true)))
+        .collect();
+    query.sort();
+    let canonical_query = query
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut headers: Vec<(String, String)> = headers
+        .iter()
+        .map(|(name, value)| {
+            let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+            (name.to_ascii_lowercase(), value)
+        })
+        .collect();
+    headers.sort();
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{value}\n"))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let request = format!(
+        "{method}\n{canonical_uri}\n{canonical_query}\n{canonical_headers}\n{signed_headers}\n{payload_hash}"
+    );
+    (request, signed_headers)
+}
+
+/// Sign a request and return the headers that have to be added to it.
+///
+/// `headers` are additional headers to sign besides `Host`, `X-Amz-Date` and
+/// `X-Amz-Security-Token`; they must be sent with the request unchanged.
+pub(super) fn sign(
+    credentials: &AwsCredentials,
+    service: &str,
+    method: &str,
+    url: &Url,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+    time: SystemTime,
+) -> Vec<(String, String)> {
+    let timestamp = date::basic_timestamp(time);
+    let date = &timestamp[..8];
+
+    let mut added = vec![("X-Amz-Date".to_string(), timestamp.clone())];
+    if let Some(token) = &credentials.session_token {
+        added.push(("X-Amz-Security-Token".to_string(), token.clone()));
+    }
+    let signed: Vec<(String, String)> = headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .chain(std::iter::once(("Host".to_string(), host_header(url))))
+        .chain(added.iter().cloned())
+        .collect();
+
+    let (canonical_request, signed_headers) = canonical_request(method, url, &signed, payload_hash);
+    let scope = format!("{date}/{}/{service}/aws4_request", credentials.region);
+    let canonical_hash =
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref());
+    let string_to_sign = format!("{ALGORITHM}\n{timestamp}\n{scope}\n{canonical_hash}");
+
+    let key = signing_key(
+        &credentials.secret_access_key,
+        date,
+        &credentials.region,
+        service,
+    );
+    let signature = hex(hmac_sha256(key.as_ref(), &string_to_sign).as_ref());
+
+    added.push((
+        "Authorization".to_string(),
+        format!(
+            "{ALGORITHM} Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            credentials.access_key_id
+        ),
+    ));
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Credentials of the AWS Signature Version 4 test suite
+    fn test_credentials() -> AwsCredentials {
+        AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+            region: "us-east-1".to_string(),
+        }
+    }
+
+    /// 2015-08-30T12:36:00Z, the time used by the test suite
+    fn test_time() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_440_938_160)
+    }
+
+    fn authorization(headers: &[(String, String)]) -> &str {
+        &headers
+            .iter()
+            .find(|(name, _)| name == "Authorization")
+            .unwrap()
+            .1
+    }
+
+    const EMPTY_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS documentation on deriving the signing key
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(key.as_ref()),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_get_vanilla() {
+        let url = Url::parse("https://example.amazonaws.com/").unwrap();
+        let headers = sign(
+            &test_credentials(),
+            "service",
+            "GET",
+            &url,
+            &[],
+            EMPTY_HASH,
+            test_time(),
+        );
+        assert_eq!(
+            authorization(&headers),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn test_post_vanilla() {
+        let url = Url::parse("https://example.amazonaws.com/").unwrap();
+        let headers = sign(
+            &test_credentials(),
+            "service",
+            "POST",
+            &url,
+            &[],
+            EMPTY_HASH,
+            test_time(),
+        );
+        assert_eq!(
+            authorization(&headers),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+        );
+    }
+
+    #[test]
+    fn test_get_vanilla_query_order() {
+        let url = Url::parse("https://example.amazonaws.com/?Param2=value2&Param1=value1").unwrap();
+        let headers = sign(
+            &test_credentials(),
+            "service",
+            "GET",
+            &url,
+            &[],
+            EMPTY_HASH,
+            test_time(),
+        );
+        assert_eq!(
+            authorization(&headers),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+    }
+
+    #[test]
+    fn test_session_token_is_signed() {
+        let mut credentials = test_credentials();
+        credentials.session_token = Some("session-token".to_string());
+        let url = Url::parse("https://example.amazonaws.com/").unwrap();
+        let headers = sign(
+            &credentials,
+            "service",
+            "GET",
+            &url,
+            &[],
+            EMPTY_HASH,
+            test_time(),
+        );
+        assert!(headers.contains(&(
+            "X-Amz-Security-Token".to_string(),
+            "session-token".to_string()
+        )));
+        assert!(
+            authorization(&headers).contains("SignedHeaders=host;x-amz-date;x-amz-security-token,")
+        );
+    }
+}
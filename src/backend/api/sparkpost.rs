@@ -0,0 +1,211 @@
+//! Request and response mapping for the SparkPost Transmissions API
+//! (`POST /api/v1/transmissions`).
+//!
+//! The message is sent unchanged as `content.email_rfc822`, with one structured recipient
+//! object per envelope recipient. The token is sent as it is in `Authorization`, without a
+//! scheme.
+//!
+//! SparkPost reports recipients it rejected in the `results` of a successful response, so the
+//! body of successful responses has to be checked as well.
+
+use lettre::Address;
+use log::debug;
+use rootcause::prelude::*;
+use serde_json::{Value, json};
+
+use super::{BackendError, JsonError, truncate_message};
+
+/// Header SparkPost expects the API key in
+pub const AUTH_HEADER: &str = "Authorization";
+
+/// Path of the Transmissions endpoint
+pub const TRANSMISSIONS_PATH: &str = "/api/v1/transmissions";
+
+/// Build the JSON request body for a message consisting of `head` followed by `body`.
+///
+/// In `sandbox` mode SparkPost accepts the message without delivering it, so the setup can be
+/// tested before a sending domain is verified.
+pub(super) fn request_body(
+    envelope_to: &[&Address],
+    head: &str,
+    body: &[u8],
+    sandbox: bool,
+) -> Result<Vec<u8>, Report> {
+    // JSON strings cannot hold arbitrary bytes, and SparkPost takes the message as a string
+    let body = std::str::from_utf8(body)
+        .map_err(|e| report!("SparkPost requires a message that is valid UTF-8: {e}"))?;
+    let recipients: Vec<Value> = envelope_to
+        .iter()
+        .map(|recipient| json!({"address": {"email": recipient.to_string()}}))
+        .collect();
+    let mut request = json!({
+        "recipients": recipients,
+        "content": {"email_rfc822": format!("{head}{body}")},
+    });
+    if sandbox {
+        request["options"] = json!({"sandbox": true});
+    }
+    serde_json::to_vec(&request).map_err(|e| report!("Failed to encode SparkPost request: {e}"))
+}
+
+/// Extract the error from SparkPost's error envelope:
+/// `{"errors": [{"message": .., "description": .., "code": ..}]}`. Returns `None` for other
+/// bodies.
+pub(super) fn parse_error(body: &str) -> Option<JsonError> {
+    errors(&serde_json::from_str(body).ok()?)
+}
+
+fn errors(value: &Value) -> Option<JsonError> {
+    let errors = value.get("errors")?.as_array()?;
+    let messages: Vec<String> = errors
+        .iter()
+        .filter_map(|error| {
+            let message = error.get("message")?.as_str()?;
+            Some(match error.get("description").and_then(Value::as_str) {
+                Some(description) => format!("{message}: {description}"),
+                None => message.to_string(),
+            })
+        })
+        .collect();
+    if messages.is_empty() {
+        return None;
+    }
+    Some(JsonError {
+        message: Some(messages.join("; ")),
+        code: errors[0]
+            .get("code")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    })
+}
+
+/// Check the body of a successful response and return the identifier of the transmission.
+///
+/// SparkPost only reports how many recipients it rejected, not which ones, so a partial
+/// delivery names the counts instead of the addresses.
+pub(super) fn check_response(
+    body: Option<&str>,
+    envelope_to: &[&Address],
+    error_message_limit: usize,
+) -> Result<Option<String>, BackendError> {
+    let Some(value) = body.and_then(|body| serde_json::from_str::<Value>(body).ok()) else {
+        debug!("API backend: SparkPost response is not JSON, assuming success");
+        return Ok(None);
+    };
+    if let Some(error) = errors(&value) {
+        let message = error.message.unwrap_or_default();
+        let mut report = report!(
+            "API request failed: {}",
+            truncate_message(&message, error_message_limit)
+        );
+        if let Some(code) = error.code {
+            report = report.attach(format!("SparkPost error code: {code}"));
+        }
+        return Err(report.into());
+    }
+
+    let results = value.get("results");
+    let count = |field: &str| {
+        results
+            .and_then(|results| results.get(field))
+            .and_then(Value::as_u64)
+            .unwrap_or(0)
+    };
+    let accepted = count("total_accepted_recipients");
+    let rejected = count("total_rejected_recipients");
+    let id = results
+        .and_then(|results| results.get("id"))
+        .and_then(Value::as_str)
+        .map(str::to_string);
+
+    if rejected == 0 {
+        return Ok(id);
+    }
+    debug!("API backend: SparkPost accepted {accepted} and rejected {rejected} recipient(s)");
+    if accepted == 0 {
+        let mut report = report!("API request failed: SparkPost rejected all recipients");
+        for recipient in envelope_to {
+            report = report.attach(format!("Rejected: {recipient}"));
+        }
+        return Err(report.into());
+    }
+    Err(BackendError::PartialDelivery {
+        accepted: vec![format!("{accepted} recipient(s)")],
+        rejected: vec![(
+            format!("{rejected} recipient(s)"),
+            "rejected by SparkPost, which does not say which".to_string(),
+        )],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn address(address: &str) -> Address {
+        Address::from_str(address).unwrap()
+    }
+
+    #[test]
+    fn test_request_body() {
+        let a = address("a@example.com");
+        let b = address("b@example.com");
+        let body = request_body(&[&a, &b], "Subject: Test\r\n\r\n", b"Body", false).unwrap();
+        let request: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "recipients": [
+                    {"address": {"email": "a@example.com"}},
+                    {"address": {"email": "b@example.com"}},
+                ],
+                "content": {"email_rfc822": "Subject: Test\r\n\r\nBody"},
+            })
+        );
+
+        let body = request_body(&[&a], "Subject: Test\r\n\r\n", b"Body", true).unwrap();
+        let request: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(request["options"], json!({"sandbox": true}));
+
+        assert!(request_body(&[&a], "Subject: Test\r\n\r\n", b"\xff", false).is_err());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = parse_error(
+            r#"{"errors": [{"message": "Invalid domain", "description": "Unconfigured sending domain", "code": "7001"}]}"#,
+        );
+        assert_eq!(
+            error,
+            Some(JsonError {
+                message: Some("Invalid domain: Unconfigured sending domain".to_string()),
+                code: Some("7001".to_string()),
+            })
+        );
+        assert_eq!(parse_error(r#"{"results": {}}"#), None);
+    }
+
+    #[test]
+    fn test_check_response() {
+        let a = address("a@example.com");
+        let b = address("b@example.com");
+
+        let ok = r#"{"results": {"total_rejected_recipients": 0, "total_accepted_recipients": 2, "id": "11668787484950529"}}"#;
+        let id = check_response(Some(ok), &[&a, &b], 100).unwrap();
+        assert_eq!(id.as_deref(), Some("11668787484950529"));
+
+        let partial = r#"{"results": {"total_rejected_recipients": 1, "total_accepted_recipients": 1, "id": "1"}}"#;
+        let err = check_response(Some(partial), &[&a, &b], 100).unwrap_err();
+        assert!(matches!(err, BackendError::PartialDelivery { .. }));
+
+        let all_rejected = r#"{"results": {"total_rejected_recipients": 2, "total_accepted_recipients": 0, "id": "1"}}"#;
+        let err = check_response(Some(all_rejected), &[&a, &b], 100).unwrap_err();
+        assert!(matches!(err, BackendError::Failed(_)));
+
+        let error = r#"{"errors": [{"message": "Message generation rejected", "code": "1902"}]}"#;
+        let err = check_response(Some(error), &[&a], 100).unwrap_err();
+        assert!(err.to_string().contains("Message generation rejected"));
+    }
+}
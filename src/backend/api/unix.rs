@@ -0,0 +1,248 @@
+//! HTTP requests over a unix domain socket, for API URLs like
+//! `http+unix:///var/run/mailer.sock:/send`.
+//!
+//! ureq only connects over TCP, so the request built with ureq is written to the socket here and
+//! the raw response is handed back to ureq to parse. Status codes and response bodies are then
+//! handled exactly like those of a request over TCP.
+
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rootcause::prelude::*;
+use url::Url;
+
+/// URL scheme of API endpoints behind a unix domain socket
+pub const SCHEME: &str = "http+unix";
+
+/// Largest response read from the socket, a little above the body limit applied by ureq
+#[cfg(unix)]
+const MAX_RESPONSE_SIZE: u64 = 10 * 1024 * 1024 + 64 * 1024;
+
+/// Socket and request target of an `http+unix://` URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnixEndpoint {
+    /// Path of the socket the server listens on
+    pub socket_path: PathBuf,
+    /// Path and query sent in the request line
+    pub target: String,
+}
+
+impl UnixEndpoint {
+    /// Split an `http+unix://` URL into the socket path and the request target.
+    ///
+    /// The socket path follows the empty host and is separated from the request path by the
+    /// first `:`, so socket paths containing `:` cannot be used.
+    pub fn parse(url: &Url) -> Result<Self, Report> {
+        let (socket_path, path) = split_path(url)?;
+        let socket_path = decode_socket_path(socket_path).map_err(|message| {
+            report!("Invalid unix socket API URL: {message}").attach(format!("URL: '{url}'"))
+        })?;
+        let target = match url.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path.to_string(),
+        };
+        Ok(Self {
+            socket_path: PathBuf::from(socket_path.as_ref()),
+            target,
+        })
+    }
+}
+
+/// Check that `url` is a usable `http+unix://` URL.
+///
+/// The error describes what is wrong, without repeating the URL.
+pub fn check_url(url: &Url) -> Result<(), &'static str> {
+    let (socket_path, _) = split(url)?;
+    decode_socket_path(socket_path)?;
+    Ok(())
+}
+
+/// The socket path and request path of an `http+unix://` URL, both still percent-encoded.
+fn split_path(url: &Url) -> Result<(&str, &str), Report> {
+    split(url).map_err(|message| {
+        report!("Invalid unix socket API URL: {message}")
+            .attach(format!("URL: '{url}'"))
+            .attach(format!(
+                "Expected format: '{SCHEME}:///path/to/socket:/request/path'"
+            ))
+    })
+}
+
+fn split(url: &Url) -> Result<(&str, &str), &'static str> {
+    if url.scheme() != SCHEME {
+        return Err("the scheme is not http+unix");
+    }
+    if cfg!(not(unix)) {
+        return Err("unix domain sockets are not supported on this platform");
+    }
+    if url.host_str().is_some_and(|host| !host.is_empty()) {
+        return Err("the URL has a host, the socket path must follow 'http+unix://' directly");
+    }
+    let Some((socket_path, path)) = url.path().split_once(':') else {
+        return Err("no request path after the socket path");
+    };
+    if socket_path.trim_end_matches('/').is_empty() {
+        return Err("the socket path is empty");
+    }
+    if !path.starts_with('/') {
+        return Err("the request path must start with '/'");
+    }
+    Ok((socket_path, path))
+}
+
+fn decode_socket_path(socket_path: &str) -> Result<std::borrow::Cow<'_, str>, &'static str> {
+    percent_encoding::percent_decode_str(socket_path)
+        .decode_utf8()
+        .map_err(|_| "the socket path is not valid UTF-8")
+}
+
+/// Replace the request path of an `http+unix://` URL with `path`, keeping the socket path.
+pub fn set_request_path(url: &mut Url, path: &str) -> Result<(), Report> {
+    let (socket_path, _) = split_path(url)?;
+    let path = format!("{socket_path}:{path}");
+    url.set_path(&path);
+    Ok(())
+}
+
+/// The request path of an `http+unix://` URL, still percent-encoded.
+pub fn request_path(url: &Url) -> Result<&str, Report> {
+    split_path(url).map(|(_, path)| path)
+}
+
+/// Send `request` with `body` to the server listening on the socket of `endpoint`.
+///
+/// The result is the same as that of sending the request with ureq: responses with an error
+/// status are returned as [`ureq::Error::Status`], failures without a response as
+/// [`ureq::Error::Transport`]. The error is boxed, as it is large.
+#[cfg(unix)]
+pub fn send(
+    endpoint: &UnixEndpoint,
+    request: &ureq::Request,
+    body: &mut dyn Read,
+    timeout: Duration,
+) -> Result<ureq::Response, Box<ureq::Error>> {
+    let raw = exchange(endpoint, request, body, timeout).map_err(|e| Box::new(e.into()))?;
+    let response: ureq::Response = String::from_utf8_lossy(&raw).parse().map_err(Box::new)?;
+    match response.status() {
+        status @ 400.. => Err(Box::new(ureq::Error::Status(status, response))),
+        _ => Ok(response),
+    }
+}
+
+/// Write the request to the socket and read the whole response.
+#[cfg(unix)]
+fn exchange(
+    endpoint: &UnixEndpoint,
+    request: &ureq::Request,
+    body: &mut dyn Read,
+    timeout: Duration,
+) -> Result<Vec<u8>, io::Error> {
+    use std::io::{BufWriter, Write};
+    use std::os::unix::net::UnixStream;
+
+    let stream = UnixStream::connect(&endpoint.socket_path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut writer = BufWriter::new(&stream);
+    write!(
+        writer,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n",
+        request.method(),
+        endpoint.target
+    )?;
+    for name in request.header_names() {
+        // The connection is not reused, and compressing responses on a local socket gains
+        // nothing, while ureq could not parse a response that is not text
+        if matches!(name.as_str(), "host" | "connection" | "accept-encoding") {
+            continue;
+        }
+        for value in request.all(&name) {
+            write!(writer, "{name}: {value}\r\n")?;
+        }
+    }
+    writer.write_all(b"Connection: close\r\n\r\n")?;
+    io::copy(body, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+
+    let mut raw = Vec::new();
+    (&stream).take(MAX_RESPONSE_SIZE).read_to_end(&mut raw)?;
+    Ok(raw)
+}
+
+/// Send `request` with `body` to the server listening on the socket of `endpoint`.
+///
+/// Unix domain sockets are not supported on this platform, so this always fails.
+#[cfg(not(unix))]
+pub fn send(
+    _endpoint: &UnixEndpoint,
+    _request: &ureq::Request,
+    _body: &mut dyn Read,
+    _timeout: Duration,
+) -> Result<ureq::Response, Box<ureq::Error>> {
+    Err(Box::new(
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix domain sockets are not supported on this platform",
+        )
+        .into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(url: &str) -> Result<UnixEndpoint, String> {
+        UnixEndpoint::parse(&Url::parse(url).unwrap()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_parse_socket_and_request_path() {
+        assert_eq!(
+            parse("http+unix:///var/run/mailer.sock:/send").unwrap(),
+            UnixEndpoint {
+                socket_path: PathBuf::from("/var/run/mailer.sock"),
+                target: "/send".to_string(),
+            }
+        );
+        assert_eq!(
+            parse("http+unix:///run/my%20mailer.sock:/v1/send?priority=low").unwrap(),
+            UnixEndpoint {
+                socket_path: PathBuf::from("/run/my mailer.sock"),
+                target: "/v1/send?priority=low".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let cases = [
+            ("http+unix://mailer/var/run/mailer.sock:/send", "has a host"),
+            ("http+unix:///var/run/mailer.sock", "no request path"),
+            (
+                "http+unix:///var/run/mailer.sock:send",
+                "must start with '/'",
+            ),
+            ("http+unix:///:/send", "socket path is empty"),
+            ("http://localhost/send", "scheme"),
+        ];
+        for (url, expected) in cases {
+            let error = parse(url).unwrap_err();
+            assert!(error.contains(expected), "{url}: {error}");
+        }
+    }
+
+    #[test]
+    fn test_set_request_path() {
+        let mut url = Url::parse("http+unix:///var/run/mailer.sock:/send?a=1").unwrap();
+        set_request_path(&mut url, "/v1/send/").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http+unix:///var/run/mailer.sock:/v1/send/?a=1"
+        );
+        assert_eq!(request_path(&url).unwrap(), "/v1/send/");
+    }
+}
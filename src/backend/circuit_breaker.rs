@@ -0,0 +1,338 @@
+use std::{
+    io::Read,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lettre::Address;
+use log::{debug, warn};
+use rootcause::prelude::*;
+
+use super::{BackendError, EmailBackend, SendReceipt};
+
+/// Wraps another backend and stops calling it after repeated failures.
+///
+/// After `threshold` consecutive transient failures within `window`, the circuit opens and every
+/// `send` fails immediately with [`BackendError::Unavailable`] without contacting the wrapped
+/// backend. Errors that are specific to a message, like rejected recipients, do not count. Once
+/// `cooldown` has elapsed a single trial send is let through; if it succeeds the circuit closes
+/// again, otherwise it reopens.
+pub struct CircuitBreakerBackend {
+    inner: Box<dyn EmailBackend>,
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    first_failure: Option<Instant>,
+    open_until: Option<Instant>,
+    half_open: bool,
+}
+
+impl CircuitBreakerBackend {
+    /// Wrap `inner` with a circuit breaker. A `threshold` of 0 disables the breaker.
+    #[must_use]
+    pub fn new(
+        inner: Box<dyn EmailBackend>,
+        threshold: u32,
+        window: Duration,
+        cooldown: Duration,
+    ) -> Self {
+        Self {
+            inner,
+            threshold,
+            window,
+            cooldown,
+            state: Mutex::new(CircuitState::default()),
+        }
+    }
+
+    /// Run a send through the breaker, recording its outcome.
+    fn guard(
+        &self,
+        send: impl FnOnce() -> Result<SendReceipt, BackendError>,
+    ) -> Result<SendReceipt, BackendError> {
+        if self.threshold == 0 {
+            return send();
+        }
+
+        self.check_closed()?;
+        let result = send();
+        match &result {
+            // A partial delivery means the backend itself is reachable
+            Ok(_) | Err(BackendError::PartialDelivery { .. }) => self.record_success(),
+            Err(
+                BackendError::Unavailable(_)
+                | BackendError::RateLimited { .. }
+                | BackendError::Timeout(_),
+            ) => self.record_failure(),
+            // Retrying does not help with these, so they say nothing about whether the backend
+            // is reachable
+            Err(
+                BackendError::Failed(_)
+                | BackendError::SmtpAuthFailed(_)
+                | BackendError::Unauthorized(_)
+                | BackendError::InvalidConfiguration(_)
+                | BackendError::SmtpProtocolError(_)
+                | BackendError::MessageTooLarge { .. },
+            ) => {}
+        }
+        result
+    }
+
+    fn check_closed(&self) -> Result<(), BackendError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(open_until) = state.open_until else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        if now < open_until {
+            let remaining = open_until - now;
+            return Err(BackendError::Unavailable(
+                report!(
+                    "Backend unavailable: circuit breaker is open after {} consecutive failures",
+                    state.consecutive_failures
+                )
+                .attach(format!("Retry in: {}s", remaining.as_secs() + 1))
+                .into_dynamic(),
+            ));
+        }
+
+        debug!("Circuit breaker: cooldown elapsed, allowing a trial send");
+        state.open_until = None;
+        state.half_open = true;
+        Ok(())
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.consecutive_failures > 0 {
+            debug!("Circuit breaker: send succeeded, resetting failure count");
+        }
+        *state = CircuitState::default();
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let within_window = state
+            .first_failure
+            .is_some_and(|first| now.duration_since(first) <= self.window);
+        if within_window {
+            state.consecutive_failures += 1;
+        } else {
+            state.consecutive_failures = 1;
+            state.first_failure = Some(now);
+        }
+
+        if state.half_open || state.consecutive_failures >= self.threshold {
+            warn!(
+                "Circuit breaker: opening after {} consecutive failure(s), cooling down for {}s",
+                state.consecutive_failures,
+                self.cooldown.as_secs()
+            );
+            state.open_until = Some(now + self.cooldown);
+            state.half_open = false;
+        }
+    }
+}
+
+impl EmailBackend for CircuitBreakerBackend {
+    fn send(
+        &self,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        raw_email: &str,
+    ) -> Result<SendReceipt, BackendError> {
+        self.guard(|| self.inner.send(envelope_from, envelope_to, raw_email))
+    }
+
+    fn send_stream(
+        &self,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        head: &str,
+        body: &mut dyn Read,
+    ) -> Result<SendReceipt, BackendError> {
+        self.guard(|| {
+            self.inner
+                .send_stream(envelope_from, envelope_to, head, body)
+        })
+    }
+
+    fn send_raw(
+        &self,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        raw_email: &[u8],
+    ) -> Result<SendReceipt, BackendError> {
+        self.guard(|| self.inner.send_raw(envelope_from, envelope_to, raw_email))
+    }
+
+    fn default_sender(&self) -> Address {
+        self.inner.default_sender()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn verify(&self) -> Result<String, BackendError> {
+        // A check is not a send, so it neither counts towards nor is blocked by the breaker
+        self.inner.verify()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    use super::*;
+
+    struct FlakyBackend {
+        fail: Arc<AtomicBool>,
+        calls: Arc<AtomicU32>,
+    }
+
+    impl EmailBackend for FlakyBackend {
+        fn send(
+            &self,
+            _: Option<&Address>,
+            _: &[&Address],
+            _: &str,
+        ) -> Result<SendReceipt, BackendError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.fail.load(Ordering::SeqCst) {
+                Err(BackendError::Unavailable(
+                    report!("Forced failure").into_dynamic(),
+                ))
+            } else {
+                Ok(SendReceipt::default())
+            }
+        }
+    }
+
+    fn flaky_breaker(
+        threshold: u32,
+        cooldown: Duration,
+    ) -> (CircuitBreakerBackend, Arc<AtomicBool>, Arc<AtomicU32>) {
+        let fail = Arc::new(AtomicBool::new(true));
+        let calls = Arc::new(AtomicU32::new(0));
+        let inner = FlakyBackend {
+            fail: fail.clone(),
+            calls: calls.clone(),
+        };
+        let breaker = CircuitBreakerBackend::new(
+            Box::new(inner),
+            threshold,
+            Duration::from_secs(60),
+            cooldown,
+        );
+        (breaker, fail, calls)
+    }
+
+    #[test]
+    fn test_circuit_breaker_opens_after_threshold() {
+        let (breaker, _fail, calls) = flaky_breaker(2, Duration::from_secs(60));
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+
+        breaker.send(Some(&from), &[&to], "Body").unwrap_err();
+        breaker.send(Some(&from), &[&to], "Body").unwrap_err();
+        let err = breaker.send(Some(&from), &[&to], "Body").unwrap_err();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(matches!(err, BackendError::Unavailable(_)), "{err:?}");
+        assert!(format!("{err}").contains("circuit breaker is open"));
+    }
+
+    /// Backend that rejects every message for good
+    struct RejectingBackend {
+        calls: Arc<AtomicU32>,
+    }
+
+    impl EmailBackend for RejectingBackend {
+        fn send(
+            &self,
+            _: Option<&Address>,
+            _: &[&Address],
+            _: &str,
+        ) -> Result<SendReceipt, BackendError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(report!("All recipients were rejected").into())
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker_ignores_permanent_failures() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let inner = RejectingBackend {
+            calls: calls.clone(),
+        };
+        let breaker = CircuitBreakerBackend::new(
+            Box::new(inner),
+            2,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+
+        for _ in 0..5 {
+            let err = breaker.send(Some(&from), &[&to], "Body").unwrap_err();
+            assert!(matches!(err, BackendError::Failed(_)), "{err:?}");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_circuit_breaker_closes_after_successful_trial() {
+        let (breaker, fail, calls) = flaky_breaker(1, Duration::ZERO);
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+
+        breaker.send(Some(&from), &[&to], "Body").unwrap_err();
+        fail.store(false, Ordering::SeqCst);
+        breaker.send(Some(&from), &[&to], "Body").unwrap();
+        breaker.send(Some(&from), &[&to], "Body").unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_circuit_breaker_send_raw() {
+        let (breaker, fail, calls) = flaky_breaker(1, Duration::from_secs(60));
+        fail.store(false, Ordering::SeqCst);
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+
+        breaker.send_raw(Some(&from), &[&to], b"Body").unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // The default implementation refuses messages that are not UTF-8 without sending them
+        let err = breaker.send_raw(Some(&from), &[&to], b"\xff").unwrap_err();
+        assert!(err.to_string().contains("not valid UTF-8"), "{err}");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_circuit_breaker_disabled_with_zero_threshold() {
+        let (breaker, _fail, calls) = flaky_breaker(0, Duration::from_secs(60));
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+
+        for _ in 0..5 {
+            breaker.send(Some(&from), &[&to], "Body").unwrap_err();
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+}
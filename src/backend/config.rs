@@ -0,0 +1,48 @@
+//! Default values of the backend settings.
+//!
+//! The command line and environment defaults in [`crate::args`] are taken from here as well, so
+//! every default is defined in one place.
+
+use std::time::Duration;
+
+/// Port of the SMTP relay (submission)
+pub const DEFAULT_SMTP_PORT: u16 = 587;
+
+/// Timeout for connecting to and talking with the SMTP relay, per command
+pub const DEFAULT_SMTP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Line the file backend writes before and after each message
+pub const DEFAULT_FILE_SEPARATOR: &str = "---";
+
+/// Time the file backend waits for other writers to release the lock on the output file
+pub const DEFAULT_FILE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Timeout for a whole API request, from connecting to reading the response
+pub const DEFAULT_API_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Idle connections to the API endpoint kept open for reuse by later requests
+pub const DEFAULT_API_POOL_MAX_IDLE_PER_HOST: usize = 1;
+
+/// Smallest message the API backend compresses when compression is enabled
+pub const DEFAULT_API_COMPRESS_MIN_BYTES: usize = 1024;
+
+/// Header the API backend sends the Message-ID in, so retried requests can be deduplicated
+pub const DEFAULT_IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+/// Maximum length of an error message taken from an API response body
+pub const DEFAULT_ERROR_MESSAGE_LIMIT: usize = 512;
+
+/// Time waited before retrying a rate limited API request without a usable `Retry-After` header
+pub const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(1);
+
+/// Upper bound for the time waited before retrying a rate limited API request
+pub const DEFAULT_RATE_LIMIT_MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Consecutive failures after which the circuit breaker opens
+pub const DEFAULT_CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+
+/// Time window in which the circuit breaker counts consecutive failures
+pub const DEFAULT_CIRCUIT_BREAKER_WINDOW: Duration = Duration::from_secs(60);
+
+/// Time the circuit breaker stays open before letting a trial send through
+pub const DEFAULT_CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
@@ -1,66 +1,743 @@
-use std::{io::Write, path::PathBuf};
+use std::{
+    fs::TryLockError,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
 
-use super::EmailBackend;
+use super::config::{DEFAULT_FILE_LOCK_TIMEOUT, DEFAULT_FILE_SEPARATOR};
+use super::{BackendError, EmailBackend, SendReceipt, format_sender};
+use crate::args::{FileFormat, LineEnding};
+use crate::date;
 use lettre::Address;
+use log::{debug, info};
 use rootcause::prelude::*;
+use uuid::Uuid;
 
 pub struct FileBackend {
-    path: PathBuf,
+    /// Files the messages are appended to, or directories they are written to one file each
+    paths: Vec<PathBuf>,
+    /// Write every message to a new file in the directories of `paths`
+    per_message: bool,
+    /// Line ending of the envelope lines and separators
+    line_ending: LineEnding,
+    /// Separator line, possibly with a `{uuid}` placeholder
+    separator: String,
+    /// How messages appended to a file are kept apart from the separators
+    format: FileFormat,
+    /// How long to wait for other writers to release an output file
+    lock_timeout: Duration,
+    /// Flush every message to the disk before reporting success
+    sync: bool,
 }
 
+/// Size of the chunks copied from the message body to the output files
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Time between two attempts to lock an output file
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
 impl FileBackend {
     pub fn new(path: PathBuf) -> Result<Self, Report> {
-        let path = PathBuf::from(".").join(path);
+        Self::new_multi(vec![path], false)
+    }
+
+    /// Create a backend that writes every message to all of `paths`.
+    ///
+    /// Missing directories of the files are created if `create_dirs` is set.
+    pub fn new_multi(paths: Vec<PathBuf>, create_dirs: bool) -> Result<Self, Report> {
+        if paths.is_empty() {
+            return Err(report!("No output file path specified"));
+        }
+        let paths = paths
+            .into_iter()
+            .map(|path| Self::resolve_path(path, create_dirs))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            paths,
+            per_message: false,
+            line_ending: LineEnding::Lf,
+            separator: DEFAULT_FILE_SEPARATOR.to_string(),
+            format: FileFormat::default(),
+            lock_timeout: DEFAULT_FILE_LOCK_TIMEOUT,
+            sync: false,
+        })
+    }
+
+    /// Create a backend that writes every message to a new file in each of `dirs`.
+    ///
+    /// The files are named `msg-<timestamp>-<uuid>.eml` and hold the envelope lines followed by
+    /// the message. Missing directories are created if `create_dirs` is set.
+    pub fn new_per_message(dirs: Vec<PathBuf>, create_dirs: bool) -> Result<Self, Report> {
+        if dirs.is_empty() {
+            return Err(report!("No output directory specified"));
+        }
+        for dir in &dirs {
+            if create_dirs && !dir.exists() {
+                debug!("File backend: creating directory {}", dir.display());
+                std::fs::create_dir_all(dir).map_err(|e| {
+                    report!("Failed to create the output directory: {e}")
+                        .attach(format!("Path: {}", dir.display()))
+                })?;
+            }
+            if !dir.is_dir() {
+                return Err(report!("Output directory does not exist")
+                    .attach(format!("Path: {}", dir.display())));
+            }
+        }
+        Ok(Self {
+            paths: dirs,
+            per_message: true,
+            line_ending: LineEnding::Lf,
+            separator: DEFAULT_FILE_SEPARATOR.to_string(),
+            format: FileFormat::default(),
+            lock_timeout: DEFAULT_FILE_LOCK_TIMEOUT,
+            sync: false,
+        })
+    }
+
+    /// Write `separator` instead of `---` before and after each message.
+    ///
+    /// Every `{uuid}` in the separator is replaced by a UUID that is new for each message, so a
+    /// reader can take the separator line that follows `Envelope-To:` and look for the same
+    /// line to find the end of the message, even if the message contains `---` lines.
+    pub fn with_separator(mut self, separator: String) -> Result<Self, Report> {
+        if separator.is_empty() || separator.contains(['\r', '\n']) {
+            return Err(report!("File separator must be a non-empty single line")
+                .attach(format!("Separator: {separator:?}")));
+        }
+        debug!("File backend: separating messages with {separator}");
+        self.separator = separator;
+        Ok(self)
+    }
+
+    /// Frame the messages appended to a file in `format`. The default is
+    /// [`FileFormat::Quoted`], which [`FileBackend::parse_records`] reads back.
+    #[must_use]
+    pub fn with_format(mut self, format: FileFormat) -> Self {
+        debug!("File backend: writing messages in the {format:?} format");
+        self.format = format;
+        self
+    }
+
+    /// Write the envelope lines and separators with `line_ending`. The default is
+    /// [`LineEnding::Lf`]; the message itself is written as it is.
+    #[must_use]
+    pub fn with_line_ending(mut self, line_ending: LineEnding) -> Self {
+        debug!("File backend: using {line_ending:?} line endings");
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Give up with an error if another writer holds the lock on an output file for longer
+    /// than `timeout`. The default is [`DEFAULT_FILE_LOCK_TIMEOUT`].
+    #[must_use]
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        debug!(
+            "File backend: waiting at most {}s for locked files",
+            timeout.as_secs()
+        );
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Flush every message to the disk before `send` returns, so a message that was reported
+    /// as sent survives a power loss.
+    ///
+    /// Appended messages are synced with `fsync`. In the per-message mode, where every file is
+    /// synced before it gets its final name anyway, the directory is synced after the rename.
+    #[must_use]
+    pub fn with_sync(mut self) -> Self {
+        debug!("File backend: syncing messages to disk");
+        self.sync = true;
+        self
+    }
+
+    /// The path the messages for `path` are written to: absolute paths are used as they are,
+    /// relative paths are taken relative to the working directory. The directory is
+    /// canonicalized, so errors name the file that is actually used.
+    fn resolve_path(path: PathBuf, create_dirs: bool) -> Result<PathBuf, Report> {
+        let path = if path.is_absolute() {
+            path
+        } else {
+            let cwd = std::env::current_dir().map_err(|e| {
+                report!("Failed to get the working directory: {e}")
+                    .attach(format!("Path: {}", path.display()))
+            })?;
+            cwd.join(path)
+        };
         let parent_dir = path.parent().ok_or_else(|| {
             report!("Output file path does not have a parent directory")
                 .attach(format!("Path: {}", path.display()))
         })?;
-        if !parent_dir.exists() {
-            return Err(
-                report!("Parent directory of the output file does not exist")
-                    .attach(format!("Path: {}", path.display()))
-                    .attach(format!("Parent: {}", parent_dir.display())),
-            );
-        }
         let basename = path.file_name().ok_or_else(|| {
             report!("Failed to get basename of the output file")
                 .attach(format!("Path: {}", path.display()))
         })?;
-        let absolute_path = parent_dir.join(basename);
+        if create_dirs && !parent_dir.exists() {
+            debug!("File backend: creating directory {}", parent_dir.display());
+            std::fs::create_dir_all(parent_dir).map_err(|e| {
+                report!("Failed to create the directory of the output file: {e}")
+                    .attach(format!("Path: {}", path.display()))
+            })?;
+        }
+        let parent_dir = parent_dir.canonicalize().map_err(|e| match e.kind() {
+            ErrorKind::NotFound => report!("Parent directory of the output file does not exist")
+                .attach(format!("Path: {}", path.display()))
+                .attach(format!("Parent: {}", parent_dir.display()))
+                .attach("Set SENDMAIL_FILE_CREATE_DIR to create it"),
+            _ => report!("Failed to resolve the directory of the output file: {e}")
+                .attach(format!("Path: {}", path.display())),
+        })?;
+        Ok(parent_dir.join(basename))
+    }
+
+    /// Split a file the backend appended messages to back into the messages.
+    ///
+    /// The file has to be written in the default [`FileFormat::Quoted`] format. The separator
+    /// of each message is taken from the line after `Envelope-To:`, so custom separators and
+    /// line endings are read as well.
+    pub fn parse_records(reader: impl Read) -> Result<Vec<FileRecord>, Report> {
+        let mut reader = BufReader::new(reader);
+        let mut records = Vec::new();
+        let mut line = Vec::new();
+        while read_line(&mut reader, &mut line)? {
+            let envelope_from = envelope_value(&line, "Envelope-From: ")?;
+            if !read_line(&mut reader, &mut line)? {
+                return Err(report!("File ends after an Envelope-From line"));
+            }
+            let envelope_to = envelope_value(&line, "Envelope-To: ")?;
+            if !read_line(&mut reader, &mut line)? {
+                return Err(report!("File ends before the separator of a message"));
+            }
+            // The separator line is written with the same line ending as the closing one
+            let separator = line.clone();
+            let (separator_text, eol) = split_line_ending(&separator);
+
+            let mut message = Vec::new();
+            loop {
+                if !read_line(&mut reader, &mut line)? {
+                    return Err(
+                        report!("File ends before the closing separator of a message")
+                            .attach(format!("Envelope-From: {envelope_from}")),
+                    );
+                }
+                if line == separator {
+                    break;
+                }
+                let (text, _) = split_line_ending(&line);
+                let text = text.strip_suffix(b"\r").unwrap_or(text);
+                match text.split_first() {
+                    Some((b'>', rest)) if is_quoted_separator(rest, separator_text) => {
+                        message.extend_from_slice(&line[1..]);
+                    }
+                    _ => message.extend_from_slice(&line),
+                }
+            }
+            // The line break before the closing separator is always added by the backend
+            message.truncate(message.len() - eol.len().min(message.len()));
+
+            records.push(FileRecord {
+                envelope_from,
+                envelope_to: envelope_to
+                    .split(", ")
+                    .filter(|address| !address.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                message,
+            });
+        }
+        Ok(records)
+    }
+}
+
+/// A message read back by [`FileBackend::parse_records`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRecord {
+    /// Value of the `Envelope-From:` line, `<>` for the null sender
+    pub envelope_from: String,
+    /// Addresses of the `Envelope-To:` line
+    pub envelope_to: Vec<String>,
+    /// The message as it was given to the backend
+    pub message: Vec<u8>,
+}
+
+/// Read the next line including its line ending into `line`. Returns `false` at the end.
+fn read_line(reader: &mut impl BufRead, line: &mut Vec<u8>) -> Result<bool, Report> {
+    line.clear();
+    let read = reader
+        .read_until(b'\n', line)
+        .map_err(|e| report!("Failed to read the file: {e}"))?;
+    Ok(read > 0)
+}
+
+/// Split a line into its text and its line ending, `\r\n`, `\n` or none.
+fn split_line_ending(line: &[u8]) -> (&[u8], &[u8]) {
+    let text_len = if line.ends_with(b"\r\n") {
+        line.len() - 2
+    } else if line.ends_with(b"\n") {
+        line.len() - 1
+    } else {
+        line.len()
+    };
+    line.split_at(text_len)
+}
+
+/// The value of an envelope line starting with `prefix`.
+fn envelope_value(line: &[u8], prefix: &str) -> Result<String, Report> {
+    let (text, _) = split_line_ending(line);
+    let value = text.strip_prefix(prefix.as_bytes()).ok_or_else(|| {
+        report!("Expected an envelope line")
+            .attach(format!("Expected: {}", prefix.trim_end()))
+            .attach(format!("Found: {}", String::from_utf8_lossy(text)))
+    })?;
+    String::from_utf8(value.to_vec()).map_err(|e| report!("Envelope line is not valid UTF-8: {e}"))
+}
+
+/// Whether `line` is `separator` after any number of `>`.
+fn is_quoted_separator(line: &[u8], separator: &[u8]) -> bool {
+    line.ends_with(separator)
+        && line[..line.len() - separator.len()]
+            .iter()
+            .all(|&byte| byte == b'>')
+}
+
+/// Quotes the lines of a message that could be taken for the separator, the way mbox quotes
+/// `From ` lines: a line that is the separator after any number of `>` gets one more `>`.
+///
+/// The message arrives in chunks, so the start of every line is held back until it is clear
+/// whether the line needs quoting. A `\r` before the line break is ignored, so the line is
+/// quoted whatever the line ending of the file.
+struct SeparatorQuoter {
+    separator: Vec<u8>,
+    /// Start of the current line, while it could still turn out to be a separator
+    pending: Vec<u8>,
+    /// Number of `>` that `pending` starts with
+    quotes: usize,
+    /// Whether the current line is known not to be a separator
+    passing: bool,
+    /// Quoted output of the last call
+    out: Vec<u8>,
+}
+
+impl SeparatorQuoter {
+    fn new(separator: &str) -> Self {
+        Self {
+            separator: separator.as_bytes().to_vec(),
+            pending: Vec::new(),
+            quotes: 0,
+            passing: false,
+            out: Vec::new(),
+        }
+    }
+
+    /// Quote the next chunk of the message.
+    fn quote(&mut self, data: &[u8]) -> &[u8] {
+        self.out.clear();
+        for &byte in data {
+            if self.passing {
+                self.out.push(byte);
+                self.passing = byte != b'\n';
+            } else if byte == b'\n' {
+                self.flush();
+                self.out.push(byte);
+            } else {
+                if byte == b'>' && self.quotes == self.pending.len() {
+                    self.quotes += 1;
+                }
+                self.pending.push(byte);
+                if !self.could_be_separator() {
+                    self.out.append(&mut self.pending);
+                    self.quotes = 0;
+                    self.passing = true;
+                }
+            }
+        }
+        &self.out
+    }
+
+    /// The rest of a message that does not end with a line break.
+    fn finish(&mut self) -> &[u8] {
+        self.out.clear();
+        self.flush();
+        &self.out
+    }
+
+    /// Move the line held back to the output, quoted if it is a separator.
+    fn flush(&mut self) {
+        let line = self.pending.strip_suffix(b"\r").unwrap_or(&self.pending);
+        if is_quoted_separator(line, &self.separator) {
+            self.out.push(b'>');
+        }
+        self.out.append(&mut self.pending);
+        self.quotes = 0;
+    }
+
+    /// Whether the line held back is the start of the separator, or all of it, after any
+    /// number of `>`.
+    ///
+    /// The separator starts after the leading `>` of the line, or within them if it starts with
+    /// `>` itself, so only that many starts are tried and long lines of `>` stay linear.
+    fn could_be_separator(&self) -> bool {
+        let line = self.pending.strip_suffix(b"\r").unwrap_or(&self.pending);
+        let separator_quotes = self
+            .separator
+            .iter()
+            .take_while(|&&byte| byte == b'>')
+            .count();
+        (self.quotes.saturating_sub(separator_quotes)..=self.quotes).any(|start| {
+            self.separator.starts_with(&self.pending[start..]) || line[start..] == self.separator
+        })
+    }
+}
+
+/// An output file that is still being written to
+struct Output {
+    path: PathBuf,
+    file: std::fs::File,
+    /// Temporary file that is renamed to `path` once the message is complete
+    temp_path: Option<PathBuf>,
+    /// Lock file held instead of a lock on `file`, where file locks are not supported
+    lock_path: Option<PathBuf>,
+    /// Length of an appended file before the message, which it is truncated back to if the
+    /// message is not finished
+    start_len: Option<u64>,
+}
+
+impl Output {
+    /// Open the file the message is appended to and lock it, so messages of concurrent
+    /// senders do not interleave.
+    ///
+    /// The lock is released when the output is dropped. Fails with [`ErrorKind::TimedOut`] if
+    /// the file is still locked after `lock_timeout`. Files that are not regular files, like
+    /// FIFOs, are not locked.
+    fn append(path: &Path, lock_timeout: Duration) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut output = Self {
+            path: path.to_path_buf(),
+            file,
+            temp_path: None,
+            lock_path: None,
+            start_len: None,
+        };
+        if output.file.metadata()?.is_file() {
+            output.lock(lock_timeout)?;
+            output.start_len = Some(output.file.metadata()?.len());
+        }
+        Ok(output)
+    }
+
+    /// Take an exclusive lock on the file, or create a `.lock` file next to it if the
+    /// platform does not support file locks.
+    fn lock(&mut self, timeout: Duration) -> std::io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut lock_path = None;
+        loop {
+            let result = match &lock_path {
+                None => match self.file.try_lock() {
+                    Ok(()) => return Ok(()),
+                    Err(TryLockError::WouldBlock) => Err(ErrorKind::WouldBlock.into()),
+                    Err(TryLockError::Error(e)) if e.kind() == ErrorKind::Unsupported => {
+                        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+                        name.push(".lock");
+                        debug!("File backend: file locks are not supported, using a lock file");
+                        lock_path = Some(self.path.with_file_name(name));
+                        continue;
+                    }
+                    Err(TryLockError::Error(e)) => Err(e),
+                },
+                Some(lock_path) => std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(lock_path)
+                    .map(drop)
+                    .map_err(|e| match e.kind() {
+                        ErrorKind::AlreadyExists => ErrorKind::WouldBlock.into(),
+                        _ => e,
+                    }),
+            };
+            match result {
+                Ok(()) => {
+                    self.lock_path = lock_path;
+                    return Ok(());
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(ErrorKind::TimedOut.into());
+                    }
+                    std::thread::sleep(LOCK_RETRY_INTERVAL);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
+    /// Open a temporary file next to `path`, so the message only shows up at `path` once it
+    /// is complete.
+    fn new_file(path: PathBuf) -> std::io::Result<Self> {
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
         Ok(Self {
-            path: absolute_path,
+            path,
+            file,
+            temp_path: Some(temp_path),
+            lock_path: None,
+            start_len: None,
         })
     }
+
+    /// Finish the file, writing `trailer` to files that are appended to.
+    ///
+    /// With `sync`, the message is on the disk when this returns: appended messages are synced,
+    /// and so is the directory a new file was renamed in.
+    fn finish(mut self, trailer: &str, sync: bool) -> std::io::Result<()> {
+        let Some(temp_path) = self.temp_path.take() else {
+            self.file.write_all(trailer.as_bytes())?;
+            self.start_len = None;
+            if sync && self.file.metadata()?.is_file() {
+                self.file.sync_all()?;
+            }
+            return Ok(());
+        };
+        let result = self
+            .file
+            .sync_all()
+            .and_then(|()| std::fs::rename(&temp_path, &self.path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result?;
+        if sync && let Some(dir) = self.path.parent() {
+            // The rename is only durable once the directory entry is
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        // A message that was not finished must not be left behind as a temporary file
+        if let Some(temp_path) = &self.temp_path {
+            let _ = std::fs::remove_file(temp_path);
+        }
+        // nor as a record without its closing separator, which is cut off while still locked
+        if let Some(start_len) = self.start_len {
+            let _ = self.file.set_len(start_len);
+        }
+        if let Some(lock_path) = &self.lock_path {
+            let _ = std::fs::remove_file(lock_path);
+        }
+    }
 }
 
 impl EmailBackend for FileBackend {
     fn send(
         &self,
-        envelope_from: &Address,
+        envelope_from: Option<&Address>,
         envelope_to: &[&Address],
         raw_email: &str,
-    ) -> Result<(), Report> {
-        let mut file = std::fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&self.path)
-            .map_err(|e| {
-                report!("Failed to open file for writing: {e}")
-                    .attach(format!("Path: {}", self.path.display()))
-            })?;
+    ) -> Result<SendReceipt, BackendError> {
+        self.send_stream(envelope_from, envelope_to, raw_email, &mut std::io::empty())
+    }
+
+    fn send_raw(
+        &self,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        raw_email: &[u8],
+    ) -> Result<SendReceipt, BackendError> {
+        // The message is written as it is, so it does not need to be valid UTF-8
+        self.send_stream(envelope_from, envelope_to, "", &mut &*raw_email)
+    }
 
-        writeln!(file, "Envelope-From: {envelope_from}")?;
+    fn send_stream(
+        &self,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        head: &str,
+        body: &mut dyn Read,
+    ) -> Result<SendReceipt, BackendError> {
         let recipients_str = envelope_to
             .iter()
             .map(std::string::ToString::to_string)
             .collect::<Vec<_>>()
             .join(", ");
-        writeln!(file, "Envelope-To: {recipients_str}")?;
-        writeln!(file, "---")?;
-        writeln!(file, "{raw_email}")?;
-        writeln!(file, "---")?;
-        Ok(())
+        let eol = self.line_ending.as_str();
+        let uuid = Uuid::new_v4().to_string();
+        let envelope = format!(
+            "Envelope-From: {}{eol}Envelope-To: {recipients_str}{eol}",
+            format_sender(envelope_from)
+        );
+        let separator = self.separator.replace("{uuid}", &uuid);
+        // A file of its own needs no separators, the envelope lines go right before the headers
+        let (file_name, preamble, trailer) = if self.per_message {
+            let timestamp = date::basic_timestamp(SystemTime::now());
+            let file_name = format!("msg-{timestamp}-{uuid}.eml");
+            (Some(file_name), envelope, String::new())
+        } else {
+            (
+                None,
+                format!("{envelope}{separator}{eol}"),
+                format!("{eol}{separator}{eol}"),
+            )
+        };
+        // Quote message lines that look like the separator, so the file can be split again
+        let mut quoter = (file_name.is_none() && self.format == FileFormat::Quoted)
+            .then(|| SeparatorQuoter::new(&separator));
+
+        // A failing file must not prevent the others from receiving the message, so errors are
+        // collected and the first one is returned at the end
+        let mut errors = Vec::new();
+        let write_error = |path: &Path, e: std::io::Error| {
+            report!("Failed to write to file: {e}").attach(format!("Path: {}", path.display()))
+        };
+
+        let mut outputs = Vec::new();
+        for path in &self.paths {
+            let output = match &file_name {
+                Some(file_name) => Output::new_file(path.join(file_name)),
+                None => Output::append(path, self.lock_timeout),
+            };
+            match output {
+                Ok(mut output) => match output.file.write_all(preamble.as_bytes()) {
+                    Ok(()) => outputs.push(output),
+                    Err(e) => errors.push(write_error(&output.path, e)),
+                },
+                Err(e) if e.kind() == ErrorKind::TimedOut => errors.push(
+                    report!(
+                        "Output file is locked by another sender, gave up after {}s",
+                        self.lock_timeout.as_secs()
+                    )
+                    .attach(format!("Path: {}", path.display()))
+                    .attach("The time limit is set by SENDMAIL_FILE_LOCK_TIMEOUT_SECS"),
+                ),
+                Err(e) => errors.push(
+                    report!("Failed to open file for writing: {e}")
+                        .attach(format!("Path: {}", path.display())),
+                ),
+            }
+        }
+
+        let mut write_all = |outputs: &mut Vec<Output>, data: &[u8]| {
+            outputs.retain_mut(|output| match output.file.write_all(data) {
+                Ok(()) => true,
+                Err(e) => {
+                    errors.push(write_error(&output.path, e));
+                    false
+                }
+            });
+        };
+        let mut write = |outputs: &mut Vec<Output>, data: &[u8]| match &mut quoter {
+            Some(quoter) => write_all(outputs, quoter.quote(data)),
+            None => write_all(outputs, data),
+        };
+        write(&mut outputs, head.as_bytes());
+        let mut chunk = vec![0; COPY_CHUNK_SIZE];
+        while !outputs.is_empty() {
+            let read = match body.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            write(&mut outputs, &chunk[..read]);
+        }
+        if let Some(quoter) = &mut quoter {
+            write_all(&mut outputs, quoter.finish());
+        }
+
+        let mut written = None;
+        for output in outputs {
+            let path = output.path.clone();
+            match output.finish(&trailer, self.sync) {
+                Ok(()) if file_name.is_some() => {
+                    info!("File backend: wrote the message to {}", path.display());
+                    written.get_or_insert(path);
+                }
+                Ok(()) => {}
+                Err(e) => errors.push(write_error(&path, e)),
+            }
+        }
+
+        match errors.into_iter().next() {
+            Some(error) => Err(error.into()),
+            None => Ok(SendReceipt {
+                message_id: None,
+                path: written,
+            }),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut description = format!("file path={}", self.paths[0].display());
+        if self.per_message {
+            description.push_str(" mode=per-message");
+        }
+        if self.paths.len() > 1 {
+            let extra: Vec<String> = self.paths[1..]
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            description.push_str(&format!(" extra={}", extra.join(",")));
+        }
+        if self.separator != DEFAULT_FILE_SEPARATOR {
+            description.push_str(&format!(" separator={}", self.separator));
+        }
+        if self.line_ending != LineEnding::Lf {
+            description.push_str(&format!(
+                " line-ending={}",
+                super::value_name(&self.line_ending)
+            ));
+        }
+        if self.format != FileFormat::default() {
+            description.push_str(&format!(" format={}", super::value_name(&self.format)));
+        }
+        if self.sync {
+            description.push_str(" sync=yes");
+        }
+        if self.lock_timeout != DEFAULT_FILE_LOCK_TIMEOUT {
+            description.push_str(&format!(" lock-timeout={}s", self.lock_timeout.as_secs()));
+        }
+        description
+    }
+
+    fn verify(&self) -> Result<String, BackendError> {
+        if self.per_message {
+            for dir in &self.paths {
+                // Dropping the output removes the temporary file again
+                Output::new_file(dir.join(format!("verify-{}", Uuid::new_v4()))).map_err(|e| {
+                    report!("Failed to create a file in the output directory: {e}")
+                        .attach(format!("Path: {}", dir.display()))
+                })?;
+            }
+            return Ok(format!("{} directory(ies) writable", self.paths.len()));
+        }
+        for path in &self.paths {
+            let existed = path.exists();
+            std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .map_err(|e| {
+                    report!("Failed to open file for writing: {e}")
+                        .attach(format!("Path: {}", path.display()))
+                })?;
+            // Do not leave an empty file behind
+            if !existed {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        Ok(format!("{} file(s) writable", self.paths.len()))
     }
 }
 
@@ -69,7 +746,7 @@ mod tests {
     use super::*;
     use std::fs;
     use std::str::FromStr;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
     fn create_temp_file() -> std::path::PathBuf {
         let timestamp = SystemTime::now()
@@ -92,7 +769,7 @@ mod tests {
 
         let from = Address::from_str("sender@example.com").unwrap();
         let to = Address::from_str("recipient@example.com").unwrap();
-        assert!(backend.send(&from, &[&to], raw_email).is_ok());
+        assert!(backend.send(Some(&from), &[&to], raw_email).is_ok());
 
         let content = fs::read_to_string(&temp_file).unwrap();
         assert!(content.contains("Envelope-From: sender@example.com"));
@@ -113,7 +790,11 @@ mod tests {
         let to1 = Address::from_str("recipient1@example.com").unwrap();
         let to2 = Address::from_str("recipient2@example.com").unwrap();
         let to3 = Address::from_str("recipient3@example.com").unwrap();
-        assert!(backend.send(&from, &[&to1, &to2, &to3], raw_email).is_ok());
+        assert!(
+            backend
+                .send(Some(&from), &[&to1, &to2, &to3], raw_email)
+                .is_ok()
+        );
 
         let content = fs::read_to_string(&temp_file).unwrap();
         assert!(content.contains("Envelope-From: sender@example.com"));
@@ -131,7 +812,7 @@ mod tests {
         let raw_email = "From: sender@example.com\nSubject: Test\n\nTest body";
 
         let from = Address::from_str("sender@example.com").unwrap();
-        assert!(backend.send(&from, &[], raw_email).is_ok());
+        assert!(backend.send(Some(&from), &[], raw_email).is_ok());
 
         let content = fs::read_to_string(&temp_file).unwrap();
         assert!(content.contains("Envelope-From: sender@example.com"));
@@ -151,8 +832,8 @@ mod tests {
         let from2 = Address::from_str("sender2@example.com").unwrap();
         let to = Address::from_str("recipient@example.com").unwrap();
 
-        assert!(backend.send(&from1, &[&to], raw_email1).is_ok());
-        assert!(backend.send(&from2, &[&to], raw_email2).is_ok());
+        assert!(backend.send(Some(&from1), &[&to], raw_email1).is_ok());
+        assert!(backend.send(Some(&from2), &[&to], raw_email2).is_ok());
 
         let content = fs::read_to_string(&temp_file).expect("File should exist after sending");
         // Should contain both emails
@@ -191,7 +872,7 @@ mod tests {
 
         let from = Address::from_str("sender@example.com").unwrap();
         let to = Address::from_str("recipient@example.com").unwrap();
-        assert!(backend.send(&from, &[&to], raw_email).is_ok());
+        assert!(backend.send(Some(&from), &[&to], raw_email).is_ok());
 
         let content = fs::read_to_string(&temp_file).expect("File should exist after sending");
         let lines: Vec<&str> = content.lines().collect();
@@ -224,7 +905,7 @@ mod tests {
 
         let from = Address::from_str("sender@example.com").unwrap();
         let to = Address::from_str("recipient@example.com").unwrap();
-        assert!(backend.send(&from, &[&to], raw_email).is_ok());
+        assert!(backend.send(Some(&from), &[&to], raw_email).is_ok());
 
         let content = fs::read_to_string(&temp_file).unwrap();
         assert!(content.contains("Envelope-From: sender@example.com"));
@@ -241,7 +922,7 @@ mod tests {
 
         let from = Address::from_str("sender+test@example.com").unwrap();
         let to = Address::from_str("recipient@example.com").unwrap();
-        assert!(backend.send(&from, &[&to], raw_email).is_ok());
+        assert!(backend.send(Some(&from), &[&to], raw_email).is_ok());
 
         let content = fs::read_to_string(&temp_file).unwrap();
         assert!(content.contains("Envelope-From: sender+test@example.com"));
@@ -258,7 +939,7 @@ mod tests {
 
         let from = Address::from_str("sender@example.com").unwrap();
         let to = Address::from_str("recipient@example.com").unwrap();
-        assert!(backend.send(&from, &[&to], raw_email).is_ok());
+        assert!(backend.send(Some(&from), &[&to], raw_email).is_ok());
 
         let content = fs::read_to_string(&temp_file).unwrap();
         assert!(content.contains("Line 1"));
@@ -268,6 +949,515 @@ mod tests {
         let _ = fs::remove_file(&temp_file);
     }
 
+    #[test]
+    fn test_file_backend_send_stream() {
+        let temp_file = create_temp_file();
+        let backend = FileBackend::new(temp_file.clone()).unwrap();
+        let head = "From: sender@example.com\nSubject: Test\n\n";
+        let mut body = std::io::Cursor::new(b"Streamed\nbody".to_vec());
+
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        assert!(
+            backend
+                .send_stream(Some(&from), &[&to], head, &mut body)
+                .is_ok()
+        );
+
+        let content = fs::read_to_string(&temp_file).unwrap();
+        assert!(
+            content
+                .ends_with("---\nFrom: sender@example.com\nSubject: Test\n\nStreamed\nbody\n---\n")
+        );
+
+        let _ = fs::remove_file(&temp_file);
+    }
+
+    #[test]
+    fn test_file_backend_send_stream_read_error() {
+        struct FailingReader(Option<&'static [u8]>);
+        impl Read for FailingReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match self.0.take() {
+                    Some(data) => {
+                        buf[..data.len()].copy_from_slice(data);
+                        Ok(data.len())
+                    }
+                    None => Err(std::io::Error::other("read failed")),
+                }
+            }
+        }
+
+        let temp_file = create_temp_file();
+        let backend = FileBackend::new(temp_file.clone()).unwrap();
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        assert!(
+            backend
+                .send(Some(&from), &[&to], "Subject: First\n\nFirst body")
+                .is_ok()
+        );
+        let before = fs::read(&temp_file).unwrap();
+
+        let head = "Subject: Second\n\n";
+        let mut body = FailingReader(Some(b"Half of the"));
+        assert!(
+            backend
+                .send_stream(Some(&from), &[&to], head, &mut body)
+                .is_err()
+        );
+
+        assert_eq!(fs::read(&temp_file).unwrap(), before);
+        let records = FileBackend::parse_records(fs::File::open(&temp_file).unwrap()).unwrap();
+        assert_eq!(records.len(), 1);
+
+        let _ = fs::remove_file(&temp_file);
+    }
+
+    #[test]
+    fn test_file_backend_crlf_line_ending() {
+        let temp_file = create_temp_file();
+        let backend = FileBackend::new(temp_file.clone())
+            .unwrap()
+            .with_line_ending(LineEnding::Crlf);
+        assert!(backend.describe().ends_with(" line-ending=crlf"));
+
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        let raw_email = "Subject: Test\r\n\r\nBody";
+        assert!(backend.send(Some(&from), &[&to], raw_email).is_ok());
+
+        let content = fs::read_to_string(&temp_file).unwrap();
+        assert_eq!(
+            content,
+            "Envelope-From: sender@example.com\r\nEnvelope-To: recipient@example.com\r\n---\r\nSubject: Test\r\n\r\nBody\r\n---\r\n"
+        );
+
+        let _ = fs::remove_file(&temp_file);
+    }
+
+    #[test]
+    fn test_file_backend_unique_separator() {
+        let temp_file = create_temp_file();
+        let new_backend = || FileBackend::new(temp_file.clone()).unwrap();
+        assert!(new_backend().with_separator("a\nb".to_string()).is_err());
+        assert!(new_backend().with_separator(String::new()).is_err());
+        let backend = new_backend()
+            .with_separator("=== message {uuid} ===".to_string())
+            .unwrap();
+
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        let first = "Subject: First\n\nAbove\n---\nBelow\n";
+        let second = "Subject: Second\n\n---\n";
+        assert!(backend.send(Some(&from), &[&to], first).is_ok());
+        assert!(backend.send(Some(&from), &[&to], second).is_ok());
+
+        // Split the file by taking the separator of each message and looking for it again
+        let content = fs::read_to_string(&temp_file).unwrap();
+        let mut lines = content.lines();
+        let mut messages = Vec::new();
+        let mut separators = Vec::new();
+        while let Some(line) = lines.next() {
+            assert!(line.starts_with("Envelope-From:"), "{line}");
+            assert!(lines.next().unwrap().starts_with("Envelope-To:"));
+            let separator = lines.next().unwrap();
+            assert!(separator.starts_with("=== message ") && separator.ends_with(" ==="));
+            let message: Vec<&str> = lines.by_ref().take_while(|l| *l != separator).collect();
+            messages.push(message.join("\n"));
+            separators.push(separator);
+        }
+        assert_eq!(
+            messages,
+            [
+                "Subject: First\n\nAbove\n---\nBelow\n",
+                "Subject: Second\n\n---\n"
+            ]
+        );
+        assert_ne!(separators[0], separators[1]);
+
+        let _ = fs::remove_file(&temp_file);
+    }
+
+    #[test]
+    fn test_separator_quoter() {
+        let input: &[u8] = b"---\n>---\n--- \n----\n>>x\n---\r\nA\n>>---";
+        let quoted: &[u8] = b">---\n>>---\n--- \n----\n>>x\n>---\r\nA\n>>>---";
+
+        let mut quoter = SeparatorQuoter::new("---");
+        let mut output = quoter.quote(input).to_vec();
+        output.extend_from_slice(quoter.finish());
+        assert_eq!(output, quoted);
+
+        // Lines split across chunks are quoted the same way
+        let mut quoter = SeparatorQuoter::new("---");
+        let mut output = Vec::new();
+        for byte in input.chunks(1) {
+            output.extend_from_slice(quoter.quote(byte));
+        }
+        output.extend_from_slice(quoter.finish());
+        assert_eq!(output, quoted);
+    }
+
+    #[test]
+    fn test_separator_quoter_separator_starting_with_quote() {
+        let mut quoter = SeparatorQuoter::new(">>");
+        let mut output = quoter.quote(b">>\n>>>\n>x\n").to_vec();
+        output.extend_from_slice(quoter.finish());
+        assert_eq!(output, b">>>\n>>>>\n>x\n");
+
+        // A long line of `>` is held back, but still quoted
+        let line = vec![b'>'; 100_000];
+        let mut quoter = SeparatorQuoter::new(">>");
+        let mut output = quoter.quote(&line).to_vec();
+        output.extend_from_slice(quoter.finish());
+        assert_eq!(output.len(), line.len() + 1);
+    }
+
+    #[test]
+    fn test_file_backend_records_round_trip() {
+        let from = Address::from_str("sender@example.com").unwrap();
+        let a = Address::from_str("a@example.com").unwrap();
+        let b = Address::from_str("b@example.com").unwrap();
+        let messages = [
+            "Subject: Front matter\n\n---\ntitle: Test\n---\nBody\n",
+            "Subject: Quoted\n\n>---\n>>---\n",
+            "Subject: CRLF\r\n\r\n---\r\n-- \r\nSignature\r\n",
+            "Subject: No line break\n\n---",
+            "",
+        ];
+
+        for line_ending in [LineEnding::Lf, LineEnding::Crlf] {
+            let temp_file = create_temp_file();
+            let backend = FileBackend::new(temp_file.clone())
+                .unwrap()
+                .with_line_ending(line_ending);
+            for message in messages {
+                backend.send(Some(&from), &[&a, &b], message).unwrap();
+            }
+            backend.send(None, &[&a], messages[0]).unwrap();
+
+            let records = FileBackend::parse_records(fs::File::open(&temp_file).unwrap()).unwrap();
+            let parsed: Vec<&[u8]> = records.iter().map(|r| r.message.as_slice()).collect();
+            let expected: Vec<&[u8]> = messages
+                .iter()
+                .chain(&messages[..1])
+                .map(|message| message.as_bytes())
+                .collect();
+            assert_eq!(parsed, expected, "{line_ending:?}");
+            assert_eq!(records[0].envelope_from, "sender@example.com");
+            assert_eq!(records[0].envelope_to, ["a@example.com", "b@example.com"]);
+            assert_eq!(records[5].envelope_from, "<>");
+            assert_eq!(records[5].envelope_to, ["a@example.com"]);
+            let _ = fs::remove_file(&temp_file);
+        }
+    }
+
+    #[test]
+    fn test_file_backend_parse_records_custom_separator() {
+        let temp_file = create_temp_file();
+        let backend = FileBackend::new(temp_file.clone())
+            .unwrap()
+            .with_separator("=== {uuid} ===".to_string())
+            .unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        backend.send(None, &[&to], "Subject: A\n\n---\n").unwrap();
+        backend.send(None, &[&to], "Subject: B\n\n>---\n").unwrap();
+
+        let records = FileBackend::parse_records(fs::File::open(&temp_file).unwrap()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].message, b"Subject: A\n\n---\n");
+        assert_eq!(records[1].message, b"Subject: B\n\n>---\n");
+        let _ = fs::remove_file(&temp_file);
+    }
+
+    #[test]
+    fn test_file_backend_parse_records_malformed() {
+        for (content, expected) in [
+            ("Subject: Test\n", "Expected an envelope line"),
+            (
+                "Envelope-From: a@example.com\n",
+                "ends after an Envelope-From",
+            ),
+            (
+                "Envelope-From: a@example.com\nEnvelope-To: b@example.com\n---\nBody\n",
+                "closing separator",
+            ),
+        ] {
+            let error = FileBackend::parse_records(content.as_bytes()).unwrap_err();
+            assert!(error.to_string().contains(expected), "{content:?}: {error}");
+        }
+        assert!(FileBackend::parse_records(&b""[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_backend_legacy_format() {
+        let temp_file = create_temp_file();
+        let backend = FileBackend::new(temp_file.clone())
+            .unwrap()
+            .with_format(FileFormat::Legacy);
+        assert!(backend.describe().contains(" format=legacy"));
+        let to = Address::from_str("recipient@example.com").unwrap();
+        backend
+            .send(None, &[&to], "Subject: Test\n\n---\n")
+            .unwrap();
+
+        let content = fs::read_to_string(&temp_file).unwrap();
+        assert_eq!(
+            content,
+            "Envelope-From: <>\nEnvelope-To: recipient@example.com\n---\nSubject: Test\n\n---\n\n---\n"
+        );
+        let _ = fs::remove_file(&temp_file);
+    }
+
+    #[test]
+    fn test_file_backend_multiple_paths() {
+        let temp_file1 = create_temp_file();
+        let temp_file2 = create_temp_file();
+        let backend =
+            FileBackend::new_multi(vec![temp_file1.clone(), temp_file2.clone()], false).unwrap();
+        let raw_email = "From: sender@example.com\nSubject: Test\n\nTest body";
+
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        assert!(backend.send(Some(&from), &[&to], raw_email).is_ok());
+
+        let content1 = fs::read_to_string(&temp_file1).unwrap();
+        let content2 = fs::read_to_string(&temp_file2).unwrap();
+        assert!(content1.contains("Test body"));
+        assert_eq!(content1, content2);
+
+        let _ = fs::remove_file(&temp_file1);
+        let _ = fs::remove_file(&temp_file2);
+    }
+
+    #[test]
+    fn test_file_backend_multiple_paths_one_failing() {
+        let temp_file = create_temp_file();
+        // A directory passes validation but cannot be opened as a file
+        let failing = std::env::temp_dir();
+        let backend = FileBackend::new_multi(vec![failing, temp_file.clone()], false).unwrap();
+        let raw_email = "From: sender@example.com\nSubject: Test\n\nTest body";
+
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        let err = backend.send(Some(&from), &[&to], raw_email).unwrap_err();
+        assert!(format!("{err}").contains("Failed to open file for writing"));
+
+        let content = fs::read_to_string(&temp_file).unwrap();
+        assert!(content.contains("Envelope-From: sender@example.com"));
+        assert!(content.contains("Test body"));
+
+        let _ = fs::remove_file(&temp_file);
+    }
+
+    #[test]
+    fn test_file_backend_new_multi_validates_all_paths() {
+        let temp_file = create_temp_file();
+        let missing = std::env::temp_dir()
+            .join("wasix_sendmail_missing_dir")
+            .join("out.txt");
+        assert!(FileBackend::new_multi(vec![temp_file, missing], false).is_err());
+        assert!(FileBackend::new_multi(vec![], false).is_err());
+    }
+
+    #[test]
+    fn test_file_backend_resolves_paths() {
+        let temp_dir = std::env::temp_dir().canonicalize().unwrap();
+        let absolute = temp_dir.join("wasix_sendmail_absolute.txt");
+        let backend = FileBackend::new(absolute.clone()).unwrap();
+        assert_eq!(backend.paths, [absolute]);
+
+        let backend = FileBackend::new(PathBuf::from("wasix_sendmail_relative.txt")).unwrap();
+        let cwd = std::env::current_dir().unwrap().canonicalize().unwrap();
+        assert_eq!(backend.paths, [cwd.join("wasix_sendmail_relative.txt")]);
+    }
+
+    #[test]
+    fn test_file_backend_missing_directory() {
+        let dir = create_temp_file().with_extension("d");
+        let path = dir.join("nested").join("out.txt");
+        let Err(error) = FileBackend::new(path.clone()) else {
+            panic!("the missing directory should be an error");
+        };
+        assert!(
+            format!("{error:?}").contains(&path.display().to_string()),
+            "{error:?}"
+        );
+
+        let backend = FileBackend::new_multi(vec![path.clone()], true).unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        backend.send(None, &[&to], "Subject: Test\n\nBody").unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains("Body"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_file_backend_per_message() {
+        let dir = create_temp_file().with_extension("d");
+        assert!(FileBackend::new_per_message(vec![dir.clone()], false).is_err());
+        let backend = FileBackend::new_per_message(vec![dir.clone()], true).unwrap();
+
+        let from = Address::from_str("sender@example.com").unwrap();
+        let to = Address::from_str("recipient@example.com").unwrap();
+        let first = backend
+            .send(Some(&from), &[&to], "Subject: First\n\nFirst body")
+            .unwrap()
+            .path
+            .unwrap();
+        let second = backend
+            .send(Some(&from), &[&to], "Subject: Second\n\nSecond body")
+            .unwrap()
+            .path
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), Some(dir.as_path()));
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("msg-") && name.ends_with(".eml"), "{name}");
+
+        assert_eq!(
+            fs::read_to_string(&first).unwrap(),
+            "Envelope-From: sender@example.com\nEnvelope-To: recipient@example.com\nSubject: First\n\nFirst body"
+        );
+        // Only the two messages, no temporary files
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_file_backend_send_raw() {
+        let to = Address::from_str("recipient@example.com").unwrap();
+        let temp_file = create_temp_file();
+        let backend = FileBackend::new(temp_file.clone()).unwrap();
+
+        backend
+            .send_raw(None, &[&to], b"Subject: Test\n\nLatin-1 \xe9t\xe9")
+            .unwrap();
+        let content = fs::read(&temp_file).unwrap();
+        assert!(
+            content.ends_with(b"Subject: Test\n\nLatin-1 \xe9t\xe9\n---\n"),
+            "{}",
+            String::from_utf8_lossy(&content)
+        );
+        let _ = fs::remove_file(&temp_file);
+    }
+
+    #[test]
+    fn test_file_backend_sync() {
+        let to = Address::from_str("recipient@example.com").unwrap();
+
+        let temp_file = create_temp_file();
+        let backend = FileBackend::new(temp_file.clone()).unwrap().with_sync();
+        assert!(backend.describe().ends_with(" sync=yes"));
+        backend.send(None, &[&to], "Subject: Test\n\nBody").unwrap();
+        assert!(
+            fs::read_to_string(&temp_file)
+                .unwrap()
+                .ends_with("Subject: Test\n\nBody\n---\n")
+        );
+        let _ = fs::remove_file(&temp_file);
+
+        let dir = create_temp_file();
+        let backend = FileBackend::new_per_message(vec![dir.clone()], true)
+            .unwrap()
+            .with_sync();
+        let path = backend
+            .send(None, &[&to], "Subject: Test\n\nBody")
+            .unwrap()
+            .path
+            .unwrap();
+        assert!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .ends_with("Subject: Test\n\nBody")
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Reader that hands out `data` in small pieces, giving other threads time to write
+    struct SlowReader(std::io::Cursor<Vec<u8>>);
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(1));
+            let len = buf.len().min(512);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_file_backend_concurrent_writers_do_not_interleave() {
+        let temp_file = create_temp_file();
+        let handles: Vec<_> = ["a", "b", "c", "d"]
+            .into_iter()
+            .map(|name| {
+                let path = temp_file.clone();
+                std::thread::spawn(move || {
+                    let backend = FileBackend::new(path).unwrap();
+                    let from = Address::from_str(&format!("{name}@example.com")).unwrap();
+                    let body = format!("{name}\n").repeat(2000).into_bytes();
+                    backend
+                        .send_stream(
+                            Some(&from),
+                            &[&from],
+                            "Subject: Concurrent\n\n",
+                            &mut SlowReader(std::io::Cursor::new(body)),
+                        )
+                        .unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let content = fs::read_to_string(&temp_file).unwrap();
+        let records: Vec<&str> = content
+            .split("Envelope-From: ")
+            .filter(|record| !record.is_empty())
+            .collect();
+        assert_eq!(records.len(), 4);
+        for record in records {
+            let name = &record[..1];
+            let body = record
+                .split_once("Subject: Concurrent\n\n")
+                .unwrap()
+                .1
+                .strip_suffix("\n---\n")
+                .unwrap();
+            assert_eq!(body, format!("{name}\n").repeat(2000), "record of {name}");
+        }
+
+        let _ = fs::remove_file(&temp_file);
+    }
+
+    #[test]
+    fn test_file_backend_gives_up_on_locked_file() {
+        let temp_file = create_temp_file();
+        let other_writer = fs::File::create(&temp_file).unwrap();
+        other_writer.lock().unwrap();
+
+        let backend = FileBackend::new(temp_file.clone())
+            .unwrap()
+            .with_lock_timeout(Duration::from_millis(50));
+        let to = Address::from_str("recipient@example.com").unwrap();
+        let err = backend
+            .send(None, &[&to], "Subject: Test\n\nTest body")
+            .unwrap_err();
+        assert!(err.to_string().contains("Output file is locked"), "{err}");
+
+        drop(other_writer);
+        assert!(
+            backend
+                .send(None, &[&to], "Subject: Test\n\nTest body")
+                .is_ok()
+        );
+
+        let _ = fs::remove_file(&temp_file);
+    }
+
     #[test]
     fn test_file_backend_default_sender() {
         let temp_file = create_temp_file();
@@ -1,19 +1,134 @@
 pub mod api;
+pub mod circuit_breaker;
+pub mod config;
 pub mod file;
 pub mod smtp;
 
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
-pub use api::ApiBackend;
-pub use file::FileBackend;
+pub use api::{ApiAuth, ApiBackend};
+pub use circuit_breaker::CircuitBreakerBackend;
+pub use file::{FileBackend, FileRecord};
 use lettre::Address;
 pub use smtp::SmtpBackend;
 
-use crate::args::BackendConfig;
+use crate::args::{
+    ApiAuthScheme, ApiBackendConfig, ApiCompression, ApiFormat, ApiProvider, ApiRecipientsIn,
+    BackendConfig, DsnNotify, FileFormat, FileMode, LineEnding, SmtpAuthMechanism,
+    SmtpRelayProtocol,
+};
 use log::{debug, info};
 use rootcause::prelude::*;
 
+/// Information about a message accepted by a backend
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SendReceipt {
+    /// Identifier the backend assigned to the message, if it reported one
+    pub message_id: Option<String>,
+    /// File the message was written to, if the backend stores each message in a file of its
+    /// own
+    pub path: Option<PathBuf>,
+}
+
+/// Error returned by [`EmailBackend::send`]
+#[derive(Debug)]
+pub enum BackendError {
+    /// The message was not delivered
+    Failed(Report),
+    /// The backend could not be reached or failed on its side, so retrying later may succeed
+    Unavailable(Report),
+    /// The server asked to retry later because of rate limiting
+    RateLimited {
+        report: Report,
+        /// How long the server asked to wait before retrying, if it said so
+        retry_after: Option<Duration>,
+    },
+    /// The message was delivered to some recipients but others were rejected
+    PartialDelivery {
+        /// Addresses that were accepted by the server
+        accepted: Vec<String>,
+        /// Rejected addresses with the server response for each of them
+        rejected: Vec<(String, String)>,
+    },
+    /// The SMTP relay rejected the credentials, or offers no mechanism to use them
+    SmtpAuthFailed(String),
+    /// The API rejected the credentials with `401 Unauthorized` or `403 Forbidden`
+    Unauthorized(Report),
+    /// Sending did not finish within the configured time
+    Timeout(Duration),
+    /// A setting of the backend configuration is invalid; the message names the setting
+    InvalidConfiguration(String),
+    /// The SMTP relay refused the session for good, e.g. with a `554` greeting; the message
+    /// includes the server's reply
+    SmtpProtocolError(String),
+    /// The message is larger than the configured limit, the `SIZE` the SMTP relay announced or
+    /// what the API accepts
+    MessageTooLarge {
+        /// Size of the message in bytes
+        size: usize,
+        /// The smaller of the two limits in bytes, if the backend knows its limit
+        limit: Option<usize>,
+        /// The server's explanation, for limits only the server knows
+        detail: Option<String>,
+    },
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::Failed(report)
+            | BackendError::Unavailable(report)
+            | BackendError::Unauthorized(report)
+            | BackendError::RateLimited { report, .. } => write!(f, "{report}"),
+            BackendError::PartialDelivery { accepted, rejected } => write!(
+                f,
+                "Partial delivery: {} recipient(s) rejected, {} accepted",
+                rejected.len(),
+                accepted.len()
+            ),
+            BackendError::SmtpAuthFailed(reason) => write!(f, "Failed to authenticate: {reason}"),
+            BackendError::Timeout(timeout) => {
+                write!(f, "Sending timed out after {}ms", timeout.as_millis())
+            }
+            BackendError::InvalidConfiguration(detail) => {
+                write!(f, "Invalid configuration: {detail}")
+            }
+            BackendError::SmtpProtocolError(detail) => write!(f, "SMTP protocol error: {detail}"),
+            BackendError::MessageTooLarge {
+                size,
+                limit,
+                detail,
+            } => {
+                write!(f, "Message is too large: {size} bytes")?;
+                if let Some(limit) = limit {
+                    write!(f, ", the limit is {limit} bytes")?;
+                }
+                if let Some(detail) = detail {
+                    write!(f, " ({detail})")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+impl From<Report> for BackendError {
+    fn from(report: Report) -> Self {
+        BackendError::Failed(report)
+    }
+}
+
+impl From<std::io::Error> for BackendError {
+    fn from(error: std::io::Error) -> Self {
+        BackendError::Failed(error.into())
+    }
+}
+
 /// Backend trait mirroring POSIX sendmail interface.
 ///
 /// The backend receives:
@@ -24,15 +139,50 @@ pub trait EmailBackend: Send + Sync {
     /// Send email with envelope information.
     ///
     /// # Arguments
-    /// * `envelope_from` - Envelope sender address (from -f flag or From header)
+    /// * `envelope_from` - Envelope sender address (from -f flag or From header), or `None` for
+    ///   the null sender (`<>`) used by bounces
     /// * `envelope_to` - Envelope recipient addresses (from command line or headers)
     /// * `raw_email` - Raw email content as read from stdin (headers + body)
     fn send(
         &self,
-        envelope_from: &Address,
+        envelope_from: Option<&Address>,
         envelope_to: &[&Address],
         raw_email: &str,
-    ) -> Result<(), Report>;
+    ) -> Result<SendReceipt, BackendError>;
+
+    /// Send email whose body is read from a stream.
+    ///
+    /// `head` is the header section of the message including the empty line that ends it, and
+    /// `body` yields the rest of the message. The default implementation buffers the whole
+    /// message and calls [`EmailBackend::send`]; backends that can write the message
+    /// incrementally should override it.
+    fn send_stream(
+        &self,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        head: &str,
+        body: &mut dyn Read,
+    ) -> Result<SendReceipt, BackendError> {
+        let mut raw_email = head.to_string();
+        body.read_to_string(&mut raw_email)?;
+        self.send(envelope_from, envelope_to, &raw_email)
+    }
+
+    /// Send email given as bytes, headers and body together.
+    ///
+    /// The default implementation requires the message to be valid UTF-8 and calls
+    /// [`EmailBackend::send`]; backends that do not need to look at the message text should
+    /// override it to skip the check.
+    fn send_raw(
+        &self,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        raw_email: &[u8],
+    ) -> Result<SendReceipt, BackendError> {
+        let raw_email = std::str::from_utf8(raw_email)
+            .map_err(|e| report!("Message is not valid UTF-8: {e}"))?;
+        self.send(envelope_from, envelope_to, raw_email)
+    }
 
     /// Get the default sender address for this backend.
     ///
@@ -44,6 +194,34 @@ pub trait EmailBackend: Send + Sync {
         let sender_str = format!("{username}@localhost");
         Address::from_str(&sender_str).expect("username@localhost should be a valid email address")
     }
+
+    /// One-line summary of the backend and its configuration, e.g.
+    /// `smtp relay=mail.example.com:587 tls=starttls`.
+    ///
+    /// Secrets like passwords and tokens are never included, only `***` in their place.
+    fn describe(&self) -> String {
+        "custom".to_string()
+    }
+
+    /// Check that the backend is usable without sending a message.
+    ///
+    /// Returns a short summary of what was checked, e.g. `connected to mail.example.com:587`.
+    /// The default implementation checks nothing.
+    fn verify(&self) -> Result<String, BackendError> {
+        Ok("no checks available".to_string())
+    }
+}
+
+/// Name of a value as it is written on the command line
+pub(crate) fn value_name(value: &impl clap::ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+/// Format an envelope sender for logs and error messages, showing the null sender as `<>`.
+pub(crate) fn format_sender(envelope_from: Option<&Address>) -> String {
+    envelope_from.map_or_else(|| "<>".to_string(), ToString::to_string)
 }
 
 /// Create a backend instance based on configuration.
@@ -53,14 +231,170 @@ pub trait EmailBackend: Send + Sync {
 /// 2. SMTP relay (if `SENDMAIL_RELAY_HOST` is set)
 /// 3. Backend/REST API (if `SENDMAIL_API_URL` is set)
 ///
-/// If no backend is configured, returns an error.
+/// If no backend is configured or settings it needs are missing or cannot be used, like an
+/// unreadable certificate, returns [`BackendError::InvalidConfiguration`].
+/// If `SENDMAIL_SMTP_PREFLIGHT` is set and the check fails, returns its error, e.g.
+/// [`BackendError::SmtpAuthFailed`] for rejected credentials.
 /// If sending with the selected backend fails, sendmail fails - no fallback to other backends.
-pub fn create_from_config(config: &BackendConfig) -> Result<Box<dyn EmailBackend>, Report> {
+///
+/// The selected backend is wrapped in a [`CircuitBreakerBackend`].
+pub fn create_from_config(config: &BackendConfig) -> Result<Box<dyn EmailBackend>, BackendError> {
+    validate_config(config)?;
+    let backend = select_backend(config)?;
+
+    let breaker = &config.circuit_breaker;
+    debug!(
+        "Circuit breaker: threshold={} window={}s cooldown={}s",
+        breaker.circuit_breaker_threshold,
+        breaker.circuit_breaker_window_secs,
+        breaker.circuit_breaker_cooldown_secs
+    );
+    Ok(Box::new(CircuitBreakerBackend::new(
+        backend,
+        breaker.circuit_breaker_threshold,
+        Duration::from_secs(breaker.circuit_breaker_window_secs),
+        Duration::from_secs(breaker.circuit_breaker_cooldown_secs),
+    )))
+}
+
+/// Check the settings of the backend selected by `config` that can be checked without
+/// connecting anywhere.
+///
+/// Fails with [`BackendError::InvalidConfiguration`] naming the first invalid setting.
+/// Settings that are missing are left to [`create_from_config`], which knows which ones the
+/// selected backend needs.
+pub fn validate_config(config: &BackendConfig) -> Result<(), BackendError> {
+    let invalid = |detail: String| Err(BackendError::InvalidConfiguration(detail));
+
+    if let Some(file_path) = &config.file.file_path {
+        let paths = std::iter::once(("SENDMAIL_FILE_PATH", file_path)).chain(
+            config
+                .file
+                .file_path_extra
+                .iter()
+                .map(|path| ("SENDMAIL_FILE_PATH_EXTRA", path)),
+        );
+        for (variable, path) in paths {
+            if path.is_empty() {
+                return invalid(format!("{variable} is empty"));
+            }
+            if config.file.file_mode == FileMode::PerMessage {
+                // The path is the directory itself, which may still be created
+                if !config.file.file_create_dir && !Path::new(path).is_dir() {
+                    return invalid(format!(
+                        "{variable}: the directory {path} does not exist (set SENDMAIL_FILE_CREATE_DIR to create it)"
+                    ));
+                }
+                continue;
+            }
+            let parent = Path::new(path).parent().unwrap_or(Path::new(""));
+            if !config.file.file_create_dir && !parent.as_os_str().is_empty() && !parent.is_dir() {
+                return invalid(format!(
+                    "{variable}: the directory {} does not exist (set SENDMAIL_FILE_CREATE_DIR to create it)",
+                    parent.display()
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    let relay = &config.smtp_relay;
+    if let Some(relay_host) = &relay.relay_host {
+        if relay_host.trim().is_empty() {
+            return invalid("SENDMAIL_RELAY_HOST is empty".to_string());
+        }
+        if relay.relay_port == 0 {
+            return invalid("SENDMAIL_RELAY_PORT must be between 1 and 65535".to_string());
+        }
+        if relay.relay_user.is_some() != relay.relay_pass.is_some() {
+            return invalid(
+                "SENDMAIL_RELAY_USER and SENDMAIL_RELAY_PASS must be set together".to_string(),
+            );
+        }
+        if relay.relay_client_cert.is_some() != relay.relay_client_key.is_some() {
+            return invalid(
+                "SENDMAIL_RELAY_CLIENT_CERT and SENDMAIL_RELAY_CLIENT_KEY must be set together"
+                    .to_string(),
+            );
+        }
+        if relay.relay_dsn_notify.len() > 1 && relay.relay_dsn_notify.contains(&DsnNotify::Never) {
+            return invalid(
+                "SENDMAIL_RELAY_DSN_NOTIFY: never cannot be combined with other values".to_string(),
+            );
+        }
+        if relay.relay_auth_mechanism == SmtpAuthMechanism::External {
+            if relay.relay_client_cert.is_none() {
+                return invalid(
+                    "SENDMAIL_RELAY_AUTH_MECHANISM=external requires a client certificate in SENDMAIL_RELAY_CLIENT_CERT and SENDMAIL_RELAY_CLIENT_KEY"
+                        .to_string(),
+                );
+            }
+            if matches!(relay.relay_proto, SmtpRelayProtocol::Plain) {
+                return invalid(
+                    "SENDMAIL_RELAY_AUTH_MECHANISM=external requires TLS, but SENDMAIL_RELAY_PROTO is plain"
+                        .to_string(),
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(url) = &config.api.api_url
+        && let Err(e) = api::parse_url(url)
+    {
+        return invalid(format!("invalid SENDMAIL_API_URL: {e}"));
+    }
+    if let Some(sender) = &config.api.api_sender
+        && Address::from_str(sender).is_err()
+    {
+        return invalid(format!(
+            "invalid SENDMAIL_API_SENDER: '{sender}' is not an email address"
+        ));
+    }
+    // Certificates are not verified at all with insecure TLS, so the CA file would be ignored
+    if config.api.api_ca_file.is_some() && config.api.api_tls_insecure {
+        return invalid(
+            "SENDMAIL_API_CA_FILE cannot be combined with SENDMAIL_API_TLS_INSECURE".to_string(),
+        );
+    }
+    Ok(())
+}
+
+fn select_backend(config: &BackendConfig) -> Result<Box<dyn EmailBackend>, BackendError> {
     // Priority 1: File backend
     if let Some(file_path) = &config.file.file_path {
-        let path = PathBuf::from(file_path);
-        info!("Using file backend to {}", path.display());
-        return Ok(Box::new(FileBackend::new(path)?));
+        let paths: Vec<PathBuf> = std::iter::once(file_path)
+            .chain(&config.file.file_path_extra)
+            .map(PathBuf::from)
+            .collect();
+        for path in &paths {
+            info!("Using file backend to {}", path.display());
+        }
+        let mut backend = match config.file.file_mode {
+            FileMode::Append => FileBackend::new_multi(paths, config.file.file_create_dir)?,
+            FileMode::PerMessage => {
+                FileBackend::new_per_message(paths, config.file.file_create_dir)?
+            }
+        };
+        if config.file.file_separator != config::DEFAULT_FILE_SEPARATOR {
+            backend = backend
+                .with_separator(config.file.file_separator.clone())
+                .map_err(invalid_setting("SENDMAIL_FILE_SEPARATOR"))?;
+        }
+        if config.file.file_format != FileFormat::default() {
+            backend = backend.with_format(config.file.file_format);
+        }
+        if config.file.file_line_ending != LineEnding::Lf {
+            backend = backend.with_line_ending(config.file.file_line_ending);
+        }
+        if config.file.file_sync {
+            backend = backend.with_sync();
+        }
+        let lock_timeout = Duration::from_secs(config.file.file_lock_timeout_secs);
+        if lock_timeout != config::DEFAULT_FILE_LOCK_TIMEOUT {
+            backend = backend.with_lock_timeout(lock_timeout);
+        }
+        return Ok(Box::new(backend));
     }
 
     // Priority 2: SMTP relay
@@ -68,19 +402,89 @@ pub fn create_from_config(config: &BackendConfig) -> Result<Box<dyn EmailBackend
         info!("Using SMTP relay backend");
         let port = config.smtp_relay.relay_port;
         let proto = config.smtp_relay.relay_proto.clone();
-        let username = config.smtp_relay.relay_user.clone();
-        let password = config.smtp_relay.relay_pass.clone();
 
         debug!("SMTP relay: host={relay_host} port={port} proto={proto:?}");
 
-        let credentials = username.zip(password);
+        // validate_config ensures that the user and the password are either both set or both
+        // unset
+        let credentials = config
+            .smtp_relay
+            .relay_user
+            .clone()
+            .zip(config.smtp_relay.relay_pass.clone());
+        let credentials = if config.smtp_relay.relay_no_auth {
+            if credentials.is_some() {
+                info!(
+                    "SMTP relay: ignoring the configured credentials because authentication is disabled"
+                );
+            }
+            None
+        } else {
+            credentials
+        };
+
+        let mut backend = SmtpBackend::new(relay_host.clone(), port, proto, credentials)
+            .map_err(invalid_setting("SENDMAIL_RELAY_HOST"))?
+            .with_auth_mechanism(config.smtp_relay.relay_auth_mechanism);
+        if config.smtp_relay.relay_host_ipv6 {
+            backend = backend
+                .with_ipv6_host()
+                .map_err(invalid_setting("SENDMAIL_RELAY_HOST_IPV6"))?;
+        }
+        // validate_config ensures that the certificate and the key are either both set or both
+        // unset
+        if let (Some(cert), Some(key)) = (
+            &config.smtp_relay.relay_client_cert,
+            &config.smtp_relay.relay_client_key,
+        ) {
+            backend = backend
+                .with_client_certificate(Path::new(cert), Path::new(key))
+                .map_err(invalid_setting("SENDMAIL_RELAY_CLIENT_CERT"))?;
+        }
 
-        return Ok(Box::new(SmtpBackend::new(
-            relay_host.clone(),
-            port,
-            proto,
-            credentials,
-        )?));
+        if let Some(sni_host) = &config.smtp_relay.relay_tls_sni_host {
+            backend = backend
+                .with_tls_sni_host(sni_host.clone())
+                .map_err(invalid_setting("SENDMAIL_RELAY_TLS_SNI_HOST"))?;
+        }
+        if let Some(dir) = &config.smtp_relay.ssl_cert_dir {
+            backend = backend
+                .with_ca_dir(Path::new(dir))
+                .map_err(invalid_setting("SENDMAIL_SSL_CERT_DIR"))?;
+        }
+        if config.smtp_relay.relay_force_helo {
+            backend = backend.with_force_helo();
+        }
+        if config.smtp_relay.relay_wrap_long_lines {
+            backend = backend.with_wrap_long_lines();
+        }
+        if config.smtp_relay.relay_bdat {
+            backend = backend.with_bdat();
+        }
+        let timeout = Duration::from_secs(config.smtp_relay.relay_timeout_secs);
+        if timeout != config::DEFAULT_SMTP_TIMEOUT {
+            backend = backend.with_timeout(timeout);
+        }
+        if let Some(secs) = config.smtp_relay.relay_connect_timeout_secs {
+            backend = backend.with_connect_timeout(Duration::from_secs(secs));
+        }
+        if let Some(limit) = config.smtp_relay.relay_max_message_size {
+            backend = backend.with_max_message_size(limit);
+        }
+        if !config.smtp_relay.relay_dsn_notify.is_empty() {
+            backend = backend
+                .with_dsn_notify(config.smtp_relay.relay_dsn_notify.clone())
+                .map_err(invalid_setting("SENDMAIL_RELAY_DSN_NOTIFY"))?;
+        }
+        if let Some(ret) = config.smtp_relay.relay_dsn_ret {
+            backend = backend.with_dsn_ret(ret);
+        }
+        if config.smtp_relay.smtp_preflight {
+            info!("SMTP relay: checking the connection before sending");
+            backend.verify_connection()?;
+        }
+
+        return Ok(Box::new(backend));
     }
 
     // Priority 3: Backend/REST API
@@ -89,29 +493,554 @@ pub fn create_from_config(config: &BackendConfig) -> Result<Box<dyn EmailBackend
     let api_token_set = config.api.api_token.is_some();
 
     if api_url_set || api_sender_set || api_token_set {
-        // Check if all three are set
-        if !api_url_set || !api_sender_set || !api_token_set {
-            return Err(report!(
+        // The Mailgun v3 format implies the Mailgun provider
+        let provider = match (config.api.api_provider, config.api.api_format) {
+            (ApiProvider::Generic, ApiFormat::MailgunV3) => ApiProvider::Mailgun,
+            (provider, _) => provider,
+        };
+        let is_mailgun = provider == ApiProvider::Mailgun;
+        let is_postmark = provider == ApiProvider::Postmark;
+        let is_ses = provider == ApiProvider::Ses;
+        let is_graph = provider == ApiProvider::Graph;
+        let is_sparkpost = provider == ApiProvider::Sparkpost;
+
+        // SES requests are signed with the AWS credentials and Graph has its own token variables
+        let uses_api_token = !is_ses && !is_graph;
+        if !uses_api_token && (!api_url_set || !api_sender_set) {
+            return Err(BackendError::InvalidConfiguration(
+                "API configuration incomplete: SENDMAIL_API_URL and SENDMAIL_API_SENDER must be set"
+                    .to_string(),
+            ));
+        }
+        if uses_api_token && (!api_url_set || !api_sender_set || !api_token_set) {
+            return Err(BackendError::InvalidConfiguration(
                 "API configuration incomplete: all three variables (SENDMAIL_API_URL, SENDMAIL_API_SENDER, SENDMAIL_API_TOKEN) must be set"
+                    .to_string(),
+            ));
+        }
+
+        let auth = if is_ses {
+            if config.api.api_auth_scheme.is_some() || config.api.api_auth_header.is_some() {
+                return Err(BackendError::InvalidConfiguration(
+                    "API configuration conflict: the SES provider always signs requests with the AWS credentials"
+                        .to_string(),
+                ));
+            }
+            aws_sigv4_auth(&config.api)?
+        } else if is_graph {
+            if config.api.api_auth_scheme.is_some() || config.api.api_auth_header.is_some() {
+                return Err(BackendError::InvalidConfiguration(
+                    "API configuration conflict: the Graph provider always sends a bearer token"
+                        .to_string(),
+                ));
+            }
+            graph_auth(&config.api)?
+        } else {
+            // Mailgun authenticates with HTTP Basic credentials for the user `api`, Postmark with
+            // its own header and SparkPost with the bare key in `Authorization`
+            let default_scheme = match provider {
+                ApiProvider::Mailgun => ApiAuthScheme::Basic,
+                ApiProvider::Postmark | ApiProvider::Sparkpost => ApiAuthScheme::Header,
+                ApiProvider::Generic
+                | ApiProvider::Sendgrid
+                | ApiProvider::Ses
+                | ApiProvider::Graph => ApiAuthScheme::Bearer,
+            };
+
+            // A custom auth header implies the header scheme unless a scheme is set explicitly
+            let scheme = match (config.api.api_auth_scheme, &config.api.api_auth_header) {
+                (Some(ApiAuthScheme::Header) | None, Some(_)) => ApiAuthScheme::Header,
+                (Some(_), Some(_)) => {
+                    return Err(BackendError::InvalidConfiguration(
+                        "API configuration conflict: SENDMAIL_API_AUTH_HEADER can only be used with the header authentication scheme"
+                            .to_string(),
+                    ));
+                }
+                (scheme, None) => scheme.unwrap_or(default_scheme),
+            };
+            match scheme {
+                ApiAuthScheme::Bearer => ApiAuth::Bearer,
+                ApiAuthScheme::Basic => {
+                    let user = match &config.api.api_user {
+                        Some(user) => user.clone(),
+                        None if is_mailgun => api::mailgun::BASIC_AUTH_USER.to_string(),
+                        None => {
+                            return Err(BackendError::InvalidConfiguration(
+                                "API configuration incomplete: SENDMAIL_API_USER must be set for the basic authentication scheme"
+                                    .to_string(),
+                            ));
+                        }
+                    };
+                    ApiAuth::Basic { user }
+                }
+                ApiAuthScheme::Header => {
+                    let name = match &config.api.api_auth_header {
+                        Some(name) => name.clone(),
+                        None if is_postmark => api::postmark::AUTH_HEADER.to_string(),
+                        None if is_sparkpost => api::sparkpost::AUTH_HEADER.to_string(),
+                        None => {
+                            return Err(BackendError::InvalidConfiguration(
+                                "API configuration incomplete: SENDMAIL_API_AUTH_HEADER must be set for the header authentication scheme"
+                                    .to_string(),
+                            ));
+                        }
+                    };
+                    ApiAuth::Header { name }
+                }
+            }
+        };
+
+        let format_supported = match provider {
+            ApiProvider::Generic => true,
+            ApiProvider::Mailgun => config.api.api_format != ApiFormat::Multipart,
+            ApiProvider::Sendgrid
+            | ApiProvider::Postmark
+            | ApiProvider::Ses
+            | ApiProvider::Graph
+            | ApiProvider::Sparkpost => config.api.api_format == ApiFormat::Raw,
+        };
+        if !format_supported {
+            return Err(BackendError::InvalidConfiguration(
+                "API configuration conflict: SENDMAIL_API_FORMAT can only be used with the generic provider, or set to `mailgunv3` with the mailgun provider"
+                    .to_string(),
             ));
         }
 
         info!("Using REST API backend");
-        let url = config.api.api_url.as_ref().unwrap().clone();
         let sender = config.api.api_sender.as_ref().unwrap();
         let Ok(sender_email) = Address::from_str(sender) else {
-            return Err(report!("Invalid default sender address: {}", sender));
+            return Err(BackendError::InvalidConfiguration(format!(
+                "invalid SENDMAIL_API_SENDER: '{sender}' is not an email address"
+            )));
         };
-        let token = config.api.api_token.as_ref().unwrap().clone();
+        let graph_mailbox = config
+            .api
+            .graph_mailbox
+            .clone()
+            .unwrap_or_else(|| sender_email.clone());
+        let mut suffix = config.api.api_url_suffix.clone();
+        if is_mailgun {
+            let domain = config
+                .api
+                .api_domain
+                .clone()
+                .unwrap_or_else(|| sender_email.domain().to_string());
+            debug!("API backend: Mailgun domain={domain}");
+            let path = if config.api.api_format == ApiFormat::MailgunV3 {
+                api::mailgun::form_messages_path(&domain)
+            } else {
+                api::mailgun::messages_path(&domain)
+            };
+            suffix = format!("{}{path}", suffix.trim_end_matches('/'));
+        } else if is_postmark {
+            suffix = format!(
+                "{}{}",
+                suffix.trim_end_matches('/'),
+                api::postmark::EMAIL_PATH
+            );
+        } else if is_ses {
+            suffix = format!(
+                "{}{}",
+                suffix.trim_end_matches('/'),
+                api::ses::SEND_EMAIL_PATH
+            );
+        } else if is_graph {
+            suffix = format!(
+                "{}{}",
+                suffix.trim_end_matches('/'),
+                api::graph::send_mail_path(&graph_mailbox)
+            );
+        } else if is_sparkpost {
+            suffix = format!(
+                "{}{}",
+                suffix.trim_end_matches('/'),
+                api::sparkpost::TRANSMISSIONS_PATH
+            );
+        }
+        let url = api::endpoint_url(
+            config.api.api_url.as_ref().unwrap(),
+            &config.api.api_url_prefix,
+            &suffix,
+        )
+        .map_err(invalid_setting("SENDMAIL_API_URL"))?;
+        let token = if is_graph {
+            config.api.graph_token.clone()
+        } else {
+            config.api.api_token.clone()
+        }
+        .unwrap_or_default();
 
         debug!("API backend: url={url}");
         debug!("API backend: default sender={sender_email}");
 
-        return Ok(Box::new(ApiBackend::new(url, sender_email, token)?));
+        debug!("API backend: auth={auth:?}");
+
+        let mut backend = ApiBackend::new(url, sender_email, token)
+            .map_err(invalid_setting("SENDMAIL_API_URL"))?
+            .with_auth(auth)
+            .map_err(invalid_setting("SENDMAIL_API_AUTH_HEADER"))?
+            .with_error_message_limit(config.api.api_error_max_len)
+            .with_rate_limit_retries(
+                config.api.api_rate_limit_retries,
+                Duration::from_secs(config.api.api_rate_limit_max_wait_secs),
+            )
+            .with_follow_redirects(config.api.api_follow_redirects)
+            .with_pool_max_idle_per_host(config.api.api_pool_max_idle_per_host);
+        if let Some(secret) = &config.api.api_signing_secret {
+            backend = backend
+                .with_request_signing(api::signing::RequestSigning {
+                    secret: secret.clone(),
+                    header: config.api.api_signing_header.clone(),
+                })
+                .map_err(invalid_setting("SENDMAIL_API_SIGNING_SECRET"))?;
+        }
+        if let Some(proxy) = &config.api.api_proxy {
+            backend = backend
+                .with_proxy(proxy, &config.api.api_no_proxy)
+                .map_err(invalid_setting("SENDMAIL_API_PROXY"))?;
+        }
+        if provider != ApiProvider::Generic {
+            backend = backend.with_provider(provider);
+        }
+        if is_graph {
+            backend = backend.with_graph_mailbox(graph_mailbox);
+        }
+        if is_sparkpost && config.api.api_sparkpost_sandbox {
+            backend = backend.with_sparkpost_sandbox();
+        }
+        if config.api.api_format != ApiFormat::Raw {
+            backend = backend.with_format(config.api.api_format);
+        }
+        if config.api.api_idempotency_header != config::DEFAULT_IDEMPOTENCY_HEADER {
+            let header = &config.api.api_idempotency_header;
+            backend = backend
+                .with_idempotency_header((!header.is_empty()).then(|| header.clone()))
+                .map_err(invalid_setting("SENDMAIL_API_IDEMPOTENCY_HEADER"))?;
+        }
+        if config.api.api_recipients_in != ApiRecipientsIn::Query {
+            backend = backend.with_recipients_in(config.api.api_recipients_in);
+        }
+        if config.api.api_per_recipient {
+            backend = backend.with_per_recipient();
+        }
+        if config.api.api_compress == ApiCompression::Gzip {
+            backend = backend.with_compression(config.api.api_compress_min_bytes);
+        }
+        if let Some(ca_file) = &config.api.api_ca_file {
+            backend = backend
+                .with_ca_file(Path::new(ca_file))
+                .map_err(invalid_setting("SENDMAIL_API_CA_FILE"))?;
+        }
+        if config.api.api_tls_insecure {
+            backend = backend
+                .with_insecure_tls()
+                .map_err(invalid_setting("SENDMAIL_API_TLS_INSECURE"))?;
+        }
+
+        return Ok(Box::new(backend));
     }
 
     // No backend configured - return error
-    Err(report!(
-        "No backend configured. Please see sendmail --help for configuration options."
+    Err(BackendError::InvalidConfiguration(
+        "No backend configured. Please see sendmail --help for configuration options.".to_string(),
+    ))
+}
+
+/// Map the error of a backend that cannot be set up with the value of `variable` to
+/// [`BackendError::InvalidConfiguration`], keeping the first line of the error.
+fn invalid_setting(variable: &'static str) -> impl FnOnce(Report) -> BackendError {
+    move |report| {
+        let message = report.to_string();
+        let reason = message.lines().next().unwrap_or_default().trim();
+        BackendError::InvalidConfiguration(format!("invalid {variable}: {reason}"))
+    }
+}
+
+/// SigV4 authentication for the SES provider from the `AWS_*` variables
+fn aws_sigv4_auth(config: &ApiBackendConfig) -> Result<ApiAuth, BackendError> {
+    let (Some(access_key_id), Some(secret_access_key), Some(region)) = (
+        &config.aws_access_key_id,
+        &config.aws_secret_access_key,
+        &config.aws_region,
+    ) else {
+        return Err(BackendError::InvalidConfiguration(
+            "API configuration incomplete: AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_REGION must be set for the SES provider"
+                .to_string(),
+        ));
+    };
+    debug!("API backend: SES region={region}");
+    Ok(ApiAuth::AwsSigV4 {
+        credentials: api::sigv4::AwsCredentials {
+            access_key_id: access_key_id.clone(),
+            secret_access_key: secret_access_key.clone(),
+            session_token: config.aws_session_token.clone(),
+            region: region.clone(),
+        },
+        service: api::ses::SERVICE.to_string(),
+    })
+}
+
+/// Authentication for the Graph provider: the access token from `SENDMAIL_GRAPH_TOKEN`, or
+/// tokens requested with the client credentials from the `SENDMAIL_GRAPH_*` variables
+fn graph_auth(config: &ApiBackendConfig) -> Result<ApiAuth, BackendError> {
+    if config.graph_token.is_some() {
+        return Ok(ApiAuth::Bearer);
+    }
+    let (Some(tenant_id), Some(client_id), Some(client_secret)) = (
+        &config.graph_tenant_id,
+        &config.graph_client_id,
+        &config.graph_client_secret,
+    ) else {
+        return Err(BackendError::InvalidConfiguration(
+            "API configuration incomplete: SENDMAIL_GRAPH_TOKEN or SENDMAIL_GRAPH_TENANT_ID, SENDMAIL_GRAPH_CLIENT_ID and SENDMAIL_GRAPH_CLIENT_SECRET must be set for the Graph provider"
+                .to_string(),
+        ));
+    };
+    let authority_url = config
+        .graph_authority_url
+        .as_deref()
+        .unwrap_or(api::graph::DEFAULT_AUTHORITY_URL);
+    Ok(ApiAuth::ClientCredentials(
+        api::graph::ClientCredentials::new(
+            authority_url,
+            tenant_id,
+            client_id.clone(),
+            client_secret.clone(),
+        ),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(args: &[&str], envs: &[(&str, &str)]) -> Result<(), String> {
+        let args: Vec<String> = std::iter::once("sendmail")
+            .chain(args.iter().copied())
+            .chain(["recipient@example.com"])
+            .map(String::from)
+            .collect();
+        let envs: Vec<(String, String)> = envs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let cli_args = crate::args::parse_cli_args(&args, &envs).unwrap();
+        validate_config(&cli_args.backend_config).map_err(|e| match e {
+            BackendError::InvalidConfiguration(detail) => detail,
+            e => panic!("unexpected error: {e}"),
+        })
+    }
+
+    #[test]
+    fn test_validate_config_accepts_valid_settings() {
+        validate(&["--file-path", "out.eml"], &[]).unwrap();
+        validate(&["--relay-host", "smtp.example.com"], &[]).unwrap();
+        validate(
+            &[],
+            &[
+                ("SENDMAIL_API_URL", "https://api.example.com/send"),
+                ("SENDMAIL_API_SENDER", "sender@example.com"),
+                ("SENDMAIL_API_TOKEN", "token"),
+            ],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_validate_config_file_path() {
+        let missing = std::env::temp_dir()
+            .join("wasix_sendmail_missing_directory")
+            .join("out.eml");
+        let error = validate(&["--file-path", missing.to_str().unwrap()], &[]).unwrap_err();
+        assert!(
+            error.starts_with("SENDMAIL_FILE_PATH: the directory"),
+            "{error}"
+        );
+
+        let error = validate(
+            &["--file-path", "out.eml"],
+            &[("SENDMAIL_FILE_PATH_EXTRA", missing.to_str().unwrap())],
+        )
+        .unwrap_err();
+        assert!(error.starts_with("SENDMAIL_FILE_PATH_EXTRA:"), "{error}");
+        validate(
+            &["--file-path", missing.to_str().unwrap()],
+            &[("SENDMAIL_FILE_CREATE_DIR", "1")],
+        )
+        .unwrap();
+
+        // In the per-message mode the path is the directory
+        let dir = missing.parent().unwrap().to_str().unwrap();
+        let mode = ("SENDMAIL_FILE_MODE", "per-message");
+        let error = validate(&["--file-path", dir], &[mode]).unwrap_err();
+        assert!(error.contains("SENDMAIL_FILE_CREATE_DIR"), "{error}");
+        validate(
+            &["--file-path", dir],
+            &[mode, ("SENDMAIL_FILE_CREATE_DIR", "1")],
+        )
+        .unwrap();
+        let temp_dir = std::env::temp_dir();
+        validate(&["--file-path", temp_dir.to_str().unwrap()], &[mode]).unwrap();
+    }
+
+    #[test]
+    fn test_validate_config_smtp_relay() {
+        let error = validate(&["--relay-host", " "], &[]).unwrap_err();
+        assert_eq!(error, "SENDMAIL_RELAY_HOST is empty");
+
+        let error = validate(
+            &["--relay-host", "smtp.example.com"],
+            &[("SENDMAIL_RELAY_AUTH_MECHANISM", "external")],
+        )
+        .unwrap_err();
+        assert!(error.contains("requires a client certificate"), "{error}");
+
+        let error = validate(
+            &["--relay-host", "smtp.example.com"],
+            &[
+                ("SENDMAIL_RELAY_AUTH_MECHANISM", "external"),
+                ("SENDMAIL_RELAY_PROTO", "plain"),
+                ("SENDMAIL_RELAY_CLIENT_CERT", "client.pem"),
+                ("SENDMAIL_RELAY_CLIENT_KEY", "client.key"),
+            ],
+        )
+        .unwrap_err();
+        assert!(error.contains("SENDMAIL_RELAY_PROTO is plain"), "{error}");
+
+        let error = validate(
+            &["--relay-host", "smtp.example.com"],
+            &[("SENDMAIL_RELAY_DSN_NOTIFY", "never,failure")],
+        )
+        .unwrap_err();
+        assert!(error.starts_with("SENDMAIL_RELAY_DSN_NOTIFY:"), "{error}");
+        validate(
+            &["--relay-host", "smtp.example.com"],
+            &[("SENDMAIL_RELAY_DSN_NOTIFY", "success,failure")],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_validate_config_smtp_relay_port_and_pairs() {
+        // These cannot be set on the command line, but a config built in code can have them
+        let args = [
+            "sendmail",
+            "--relay-host",
+            "smtp.example.com",
+            "recipient@example.com",
+        ]
+        .map(String::from);
+        let mut config = crate::args::parse_cli_args(&args, &[])
+            .unwrap()
+            .backend_config;
+        config.smtp_relay.relay_port = 0;
+        let error = validate_config(&config).unwrap_err().to_string();
+        assert!(error.contains("SENDMAIL_RELAY_PORT"), "{error}");
+
+        config.smtp_relay.relay_port = 587;
+        config.smtp_relay.relay_user = Some("user".to_string());
+        let error = validate_config(&config).unwrap_err().to_string();
+        assert!(error.contains("SENDMAIL_RELAY_PASS"), "{error}");
+
+        config.smtp_relay.relay_user = None;
+        config.smtp_relay.relay_client_key = Some("client.key".to_string());
+        let error = validate_config(&config).unwrap_err().to_string();
+        assert!(error.contains("SENDMAIL_RELAY_CLIENT_CERT"), "{error}");
+    }
+
+    #[test]
+    fn test_validate_config_api() {
+        let error = validate(
+            &[],
+            &[
+                ("SENDMAIL_API_URL", "ftp://api.example.com/send"),
+                ("SENDMAIL_API_SENDER", "sender@example.com"),
+                ("SENDMAIL_API_TOKEN", "token"),
+            ],
+        )
+        .unwrap_err();
+        assert!(error.starts_with("invalid SENDMAIL_API_URL:"), "{error}");
+
+        let error = validate(
+            &[],
+            &[
+                ("SENDMAIL_API_URL", "https://api.example.com/send"),
+                ("SENDMAIL_API_SENDER", "not an address"),
+                ("SENDMAIL_API_TOKEN", "token"),
+            ],
+        )
+        .unwrap_err();
+        assert!(error.starts_with("invalid SENDMAIL_API_SENDER:"), "{error}");
+    }
+
+    #[test]
+    fn test_validate_config_api_ca_file_with_insecure_tls() {
+        // clap rejects the combination, but a config built in code can have it
+        let args = ["sendmail", "recipient@example.com"].map(String::from);
+        let envs = [
+            ("SENDMAIL_API_URL", "https://api.example.com/send"),
+            ("SENDMAIL_API_SENDER", "sender@example.com"),
+            ("SENDMAIL_API_TOKEN", "token"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        let mut config = crate::args::parse_cli_args(&args, &envs)
+            .unwrap()
+            .backend_config;
+        config.api.api_ca_file = Some("ca.pem".to_string());
+        config.api.api_tls_insecure = true;
+        let error = validate_config(&config).unwrap_err().to_string();
+        assert!(error.contains("SENDMAIL_API_TLS_INSECURE"), "{error}");
+    }
+
+    #[test]
+    fn test_create_from_config_returns_invalid_configuration() {
+        let args = ["sendmail", "--relay-host", " ", "recipient@example.com"].map(String::from);
+        let cli_args = crate::args::parse_cli_args(&args, &[]).unwrap();
+        let result = create_from_config(&cli_args.backend_config);
+        let Err(BackendError::InvalidConfiguration(detail)) = result else {
+            panic!("an empty relay host should be rejected");
+        };
+        assert_eq!(detail, "SENDMAIL_RELAY_HOST is empty");
+    }
+
+    #[test]
+    fn test_create_from_config_rejects_unusable_settings() {
+        let create = |envs: &[(&str, &str)]| {
+            let args = ["sendmail", "recipient@example.com"].map(String::from);
+            let envs: Vec<(String, String)> = envs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let cli_args = crate::args::parse_cli_args(&args, &envs).unwrap();
+            match create_from_config(&cli_args.backend_config) {
+                Err(BackendError::InvalidConfiguration(detail)) => detail,
+                Err(e) => panic!("unexpected error: {e}"),
+                Ok(_) => panic!("the configuration should be rejected"),
+            }
+        };
+
+        let detail = create(&[
+            ("SENDMAIL_API_URL", "https://api.example.com/send"),
+            ("SENDMAIL_API_SENDER", "sender@example.com"),
+            ("SENDMAIL_API_TOKEN", "token"),
+            ("SENDMAIL_API_PROXY", "not a url"),
+        ]);
+        assert!(
+            detail.starts_with("invalid SENDMAIL_API_PROXY:"),
+            "{detail}"
+        );
+
+        let missing = std::env::temp_dir().join("wasix_sendmail_missing_api_ca.pem");
+        let detail = create(&[
+            ("SENDMAIL_API_URL", "https://api.example.com/send"),
+            ("SENDMAIL_API_SENDER", "sender@example.com"),
+            ("SENDMAIL_API_TOKEN", "token"),
+            ("SENDMAIL_API_CA_FILE", missing.to_str().unwrap()),
+        ]);
+        assert!(
+            detail.starts_with("invalid SENDMAIL_API_CA_FILE:"),
+            "{detail}"
+        );
+        assert!(detail.contains("Failed to read API CA file"), "{detail}");
+    }
+}
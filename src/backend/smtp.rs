@@ -1,20 +1,70 @@
+mod helo;
+
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::io::Read;
+use std::net::Ipv6Addr;
+use std::path::Path;
+use std::time::Duration;
+
 use lettre::{
-    Address, SmtpTransport, Transport,
-    address::Envelope,
+    Address,
     transport::smtp::{
         authentication::{Credentials, Mechanism},
-        client::{CertificateStore, Tls, TlsParameters},
+        client::{Certificate, CertificateStore, Identity, SmtpConnection, Tls, TlsParameters},
+        commands::{Data, Ehlo, Mail, Quit, Rcpt},
+        extension::{ClientId, Extension, MailBodyParameter, MailParameter, RcptParameter},
+        response::{Code, Response, Severity},
     },
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use rootcause::prelude::*;
+use rustls_pki_types::{CertificateDer, pem::PemObject};
+use url::Host;
 
-use crate::args::SmtpRelayProtocol;
+use crate::args::{DsnNotify, DsnRet, SmtpAuthMechanism, SmtpRelayProtocol};
+use crate::parser;
 
-use super::EmailBackend;
+use super::config::DEFAULT_SMTP_TIMEOUT;
+use super::{BackendError, EmailBackend, SendReceipt, format_sender};
+use helo::HeloSession;
 
 pub struct SmtpBackend {
-    transport: SmtpTransport,
+    host: String,
+    port: u16,
+    tls_mode: SmtpRelayProtocol,
+    /// Server name sent in the TLS handshake and verified against the certificate, if it differs
+    /// from `host`
+    tls_sni_host: Option<String>,
+    /// Client certificate presented to the relay
+    identity: Option<Identity>,
+    /// DER encoded CA certificates trusted in addition to the built-in roots
+    extra_roots: Vec<Vec<u8>>,
+    /// TLS parameters, built when the backend is configured rather than per connection: every
+    /// connection shares their rustls `ClientConfig` and with it the session cache, so later
+    /// connections to the relay resume the TLS session instead of a full handshake
+    tls: Tls,
+    credentials: Option<Credentials>,
+    /// User name of `credentials`, which lettre does not expose
+    username: Option<String>,
+    auth_mechanism: SmtpAuthMechanism,
+    hello_name: ClientId,
+    /// Greet with `HELO` right away instead of trying `EHLO` first
+    force_helo: bool,
+    /// Wrap body lines that are too long for SMTP instead of refusing the message
+    wrap_long_lines: bool,
+    /// Send the message with `BDAT` instead of `DATA` when the relay announces `CHUNKING`
+    use_bdat: bool,
+    /// Timeout for connecting to and talking with the relay
+    timeout: Duration,
+    /// Timeout for connecting up to the greeting, if it differs from `timeout`
+    connect_timeout: Option<Duration>,
+    /// Largest message in bytes sent to the relay, on top of the limit the relay announces
+    max_message_size: Option<u64>,
+    /// `NOTIFY=` values for every recipient, empty to decide by the message
+    dsn_notify: Vec<DsnNotify>,
+    /// `RET=` value for `MAIL FROM`
+    dsn_ret: Option<DsnRet>,
 }
 
 pub enum TlsMode {
@@ -37,66 +87,1169 @@ impl SmtpBackend {
         if host.is_empty() {
             return Err(report!("No SMTP relay host specified"));
         }
+        let host = parse_relay_host(&host)?;
 
-        let tls_params = TlsParameters::builder(host.clone())
-            .certificate_store(CertificateStore::Default)
-            .build_rustls()
-            .map_err(|e| {
-                report!("Failed to build certificate store: {e}").attach(format!("Host: {host}"))
-            })?;
+        let username = credentials.as_ref().map(|(username, _)| username.clone());
+        let credentials = if let Some((username, password)) = credentials {
+            debug!("SMTP relay backend: using authentication");
+            Some(Credentials::new(username, password))
+        } else {
+            debug!(
+                "SMTP relay backend: not using authentication because no username or password was provided"
+            );
+            None
+        };
+
+        let mut backend = Self {
+            host,
+            port,
+            tls_mode,
+            tls_sni_host: None,
+            identity: None,
+            extra_roots: Vec::new(),
+            tls: Tls::None,
+            credentials,
+            username,
+            auth_mechanism: SmtpAuthMechanism::Auto,
+            hello_name: ClientId::default(),
+            force_helo: false,
+            wrap_long_lines: false,
+            use_bdat: false,
+            timeout: DEFAULT_SMTP_TIMEOUT,
+            connect_timeout: None,
+            max_message_size: None,
+            dsn_notify: Vec::new(),
+            dsn_ret: None,
+        };
+        backend.tls = backend.build_tls()?;
+        Ok(backend)
+    }
+
+    /// Select the authentication mechanism. The default is [`SmtpAuthMechanism::Auto`].
+    #[must_use]
+    pub fn with_auth_mechanism(mut self, mechanism: SmtpAuthMechanism) -> Self {
+        self.auth_mechanism = mechanism;
+        self
+    }
+
+    /// Greet the relay with `HELO` instead of `EHLO`.
+    ///
+    /// Without `EHLO` the relay offers no extensions, so this only works without TLS and
+    /// authentication. Relays that reject `EHLO` are greeted with `HELO` even without this.
+    #[must_use]
+    pub fn with_force_helo(mut self) -> Self {
+        debug!("SMTP relay backend: greeting with HELO");
+        self.force_helo = true;
+        self
+    }
+
+    /// Wrap body lines longer than [`MAX_LINE_LENGTH`] octets, which SMTP does not allow.
+    ///
+    /// Lines are broken after a space where possible, leaving the space at the end of the line
+    /// as a soft line break of `format=flowed` (RFC 3676). Without this, messages with such lines
+    /// are refused before the relay is contacted.
+    #[must_use]
+    pub fn with_wrap_long_lines(mut self) -> Self {
+        debug!("SMTP relay backend: wrapping long body lines");
+        self.wrap_long_lines = true;
+        self
+    }
+
+    /// Send the message in chunks with `BDAT` (RFC 3030) when the relay announces `CHUNKING`.
+    ///
+    /// The chunks carry their length, so the message is sent as it is instead of being
+    /// dot-stuffed. Relays without `CHUNKING` and messages that are not valid UTF-8 still use
+    /// `DATA`.
+    #[must_use]
+    pub fn with_bdat(mut self) -> Self {
+        debug!("SMTP relay backend: using BDAT when the relay supports CHUNKING");
+        self.use_bdat = true;
+        self
+    }
+
+    /// Require the relay host to be an IPv6 address, to catch a host name or IPv4 address
+    /// configured by mistake.
+    pub fn with_ipv6_host(self) -> Result<Self, Report> {
+        if self.host.parse::<Ipv6Addr>().is_err() {
+            return Err(report!("SMTP relay host is not an IPv6 address")
+                .attach(format!("Host: {}", self.host)));
+        }
+        Ok(self)
+    }
+
+    /// Time out connecting to the relay and each command after `timeout`. The default is
+    /// [`DEFAULT_SMTP_TIMEOUT`].
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Time out connecting to the relay, including its greeting and the reply to `EHLO`, after
+    /// `timeout` instead of the command timeout.
+    ///
+    /// A short connect timeout gives up quickly on an unreachable relay or one that drops
+    /// connection attempts, while slow commands like `DATA` still get the full command timeout.
+    #[must_use]
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        debug!(
+            "SMTP relay backend: connect timeout of {}ms",
+            timeout.as_millis()
+        );
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Refuse to send messages larger than `limit` bytes, including the headers added on the
+    /// way. The relay is not contacted for such messages.
+    #[must_use]
+    pub fn with_max_message_size(mut self, limit: u64) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    /// Request delivery status notifications for `notify` on every recipient (RFC 3461).
+    ///
+    /// By default, bulk and junk mail asks for no notifications at all (`NOTIFY=NEVER`) and
+    /// other mail leaves it to the relay. Fails if `notify` combines [`DsnNotify::Never`] with
+    /// other values. The parameters are only sent to relays that announce `DSN`.
+    pub fn with_dsn_notify(mut self, notify: Vec<DsnNotify>) -> Result<Self, Report> {
+        if notify.len() > 1 && notify.contains(&DsnNotify::Never) {
+            return Err(report!(
+                "DSN notify 'never' cannot be combined with other values"
+            ));
+        }
+        debug!("SMTP relay backend: requesting DSN notify={notify:?}");
+        self.dsn_notify = notify;
+        Ok(self)
+    }
+
+    /// Ask for delivery status notifications that return `ret` of the message (RFC 3461).
+    #[must_use]
+    pub fn with_dsn_ret(mut self, ret: DsnRet) -> Self {
+        self.dsn_ret = Some(ret);
+        self
+    }
+
+    /// The `NOTIFY=` values for the recipients of a message with the header section `head`.
+    fn dsn_notify_for(&self, head: &str) -> Vec<DsnNotify> {
+        if !self.dsn_notify.is_empty() {
+            return self.dsn_notify.clone();
+        }
+        let headers = parser::parse_email_headers(head);
+        if parser::extract_precedence(&headers).is_low() {
+            // Nobody reads bounces of newsletters and notifications
+            vec![DsnNotify::Never]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Check `raw_email` against the `SIZE` the relay announced and the configured limit.
+    ///
+    /// If both limits are set, the smaller one applies.
+    pub fn check_size_limit(
+        raw_email: &[u8],
+        server_size_limit: Option<u64>,
+        config_limit: Option<u64>,
+    ) -> Result<(), BackendError> {
+        let limit = match (server_size_limit, config_limit) {
+            (Some(server), Some(config)) => server.min(config),
+            (Some(limit), None) | (None, Some(limit)) => limit,
+            (None, None) => return Ok(()),
+        };
+        if raw_email.len() as u64 > limit {
+            return Err(BackendError::MessageTooLarge {
+                size: raw_email.len(),
+                limit: Some(usize::try_from(limit).unwrap_or(usize::MAX)),
+                detail: None,
+            });
+        }
+        Ok(())
+    }
 
-        let tls = match tls_mode {
+    /// Use `sni_host` instead of the relay host as the TLS server name.
+    ///
+    /// This is needed when the relay is reached through a name that is not on its certificate,
+    /// e.g. an internal gateway hostname in front of a shared SMTP server.
+    pub fn with_tls_sni_host(mut self, sni_host: String) -> Result<Self, Report> {
+        if sni_host.is_empty() {
+            return Err(report!("Empty TLS SNI host specified for the SMTP relay"));
+        }
+        debug!("SMTP relay backend: using TLS server name {sni_host}");
+        self.tls_sni_host = Some(sni_host);
+        self.tls = self.build_tls()?;
+        Ok(self)
+    }
+
+    /// Present a client certificate to the relay (mutual TLS).
+    ///
+    /// `cert_path` and `key_path` point to PEM files with the certificate and its private key.
+    pub fn with_client_certificate(
+        mut self,
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> Result<Self, Report> {
+        let read = |kind: &str, path: &Path| {
+            std::fs::read(path).map_err(|e| {
+                report!("Failed to read SMTP client {kind}: {e}")
+                    .attach(format!("Path: {}", path.display()))
+            })
+        };
+        let cert_pem = read("certificate", cert_path)?;
+        let key_pem = read("key", key_path)?;
+
+        // lettre's rustls backend expects the certificate in DER form
+        let cert_der = CertificateDer::pem_slice_iter(&cert_pem)
+            .next()
+            .ok_or_else(|| report!("SMTP client certificate file does not contain a certificate"))
+            .and_then(|cert| {
+                cert.map_err(|e| report!("Failed to parse SMTP client certificate: {e}"))
+            })
+            .map_err(|report| report.attach(format!("Path: {}", cert_path.display())))?;
+        let identity = Identity::from_pem(&cert_der, &key_pem).map_err(|e| {
+            report!("Failed to load SMTP client key: {e}")
+                .attach(format!("Path: {}", key_path.display()))
+        })?;
+
+        debug!(
+            "SMTP relay backend: using client certificate {}",
+            cert_path.display()
+        );
+        self.identity = Some(identity);
+        self.tls = self.build_tls()?;
+        Ok(self)
+    }
+
+    /// Trust the CA certificates found in the PEM files of `dir` in addition to the built-in
+    /// root certificates.
+    ///
+    /// Files that do not contain certificates (like a README) are skipped. Fails if the directory
+    /// cannot be read or contains no certificate at all.
+    pub fn with_ca_dir(mut self, dir: &Path) -> Result<Self, Report> {
+        let entries = std::fs::read_dir(dir).map_err(|e| {
+            report!("Failed to read SMTP CA directory: {e}")
+                .attach(format!("Path: {}", dir.display()))
+        })?;
+
+        let mut paths = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut roots = Vec::new();
+        for path in paths {
+            let Ok(pem) = std::fs::read(&path) else {
+                debug!(
+                    "SMTP relay backend: skipping unreadable file {}",
+                    path.display()
+                );
+                continue;
+            };
+            let certificates = CertificateDer::pem_slice_iter(&pem)
+                .filter_map(Result::ok)
+                .map(|cert| cert.to_vec())
+                .collect::<Vec<_>>();
+            debug!(
+                "SMTP relay backend: loaded {} CA certificate(s) from {}",
+                certificates.len(),
+                path.display()
+            );
+            roots.extend(certificates);
+        }
+        if roots.is_empty() {
+            return Err(
+                report!("SMTP CA directory does not contain any certificates")
+                    .attach(format!("Path: {}", dir.display())),
+            );
+        }
+
+        self.extra_roots.extend(roots);
+        self.tls = self.build_tls()?;
+        Ok(self)
+    }
+
+    fn build_tls(&self) -> Result<Tls, Report> {
+        let server_name = self.tls_sni_host.as_ref().unwrap_or(&self.host);
+        let mut builder = TlsParameters::builder(server_name.clone())
+            .certificate_store(CertificateStore::Default);
+        if let Some(identity) = &self.identity {
+            builder = builder.identify_with(identity.clone());
+        }
+        for der in &self.extra_roots {
+            let certificate = Certificate::from_der(der.clone())
+                .map_err(|e| report!("Failed to load CA certificate: {e}"))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        let tls_params = builder.build_rustls().map_err(|e| {
+            report!("Failed to build certificate store: {e}").attach(format!("Host: {server_name}"))
+        })?;
+
+        Ok(match self.tls_mode {
             SmtpRelayProtocol::Plain => Tls::None,
             SmtpRelayProtocol::Tls => Tls::Wrapper(tls_params),
             SmtpRelayProtocol::StartTls => Tls::Required(tls_params),
             SmtpRelayProtocol::Opportunistic => Tls::Opportunistic(tls_params),
-        };
+        })
+    }
 
-        let mut transport = SmtpTransport::relay(&host)
-            .map_err(|e| report!("Failed to build transport: {e}").attach(format!("Host: {host}")))?
-            .port(port)
-            .tls(tls);
+    /// Connect to the relay, greet it, upgrade to TLS and authenticate as configured, then quit.
+    ///
+    /// This finds an unreachable relay or rejected credentials without sending a message. The
+    /// capabilities the relay announced are logged.
+    pub fn verify_connection(&self) -> Result<(), BackendError> {
+        let mut connection = self.connect()?;
+        info!(
+            "SMTP relay backend: connected to {}, {}",
+            self.server(),
+            connection.capabilities()
+        );
+        connection.quit();
+        Ok(())
+    }
 
-        if let Some((username, password)) = credentials {
-            debug!("SMTP relay backend: using authentication");
-            let credentials = Credentials::new(username, password);
-            transport = transport
-                .authentication(vec![Mechanism::Plain, Mechanism::Login])
-                .credentials(credentials);
+    /// The relay as `host:port` for messages, with the brackets an IPv6 address needs there
+    fn server(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
         } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// Open a connection to the relay, upgrading to TLS and authenticating as configured.
+    ///
+    /// If the relay rejects `EHLO` with a permanent error, the connection is retried with `HELO`.
+    fn connect(&self) -> Result<Box<dyn MailSession>, BackendError> {
+        if self.force_helo {
+            return self.connect_helo();
+        }
+
+        let server = self.server();
+        let wrapper_tls = match &self.tls {
+            Tls::Wrapper(tls_params) => Some(tls_params),
+            _ => None,
+        };
+
+        let connection = SmtpConnection::connect(
+            (self.host.as_str(), self.port),
+            Some(self.connect_timeout.unwrap_or(self.timeout)),
+            &self.hello_name,
+            wrapper_tls,
+            None,
+        );
+        let refused = connection
+            .as_ref()
+            .err()
+            .and_then(lettre::transport::smtp::Error::status)
+            .filter(|&code| refuses_service(code));
+        let mut connection = match (connection, refused) {
+            (Ok(connection), _) => connection,
+            // Greeting with HELO instead would be refused as well
+            (Err(e), Some(code)) => {
+                return Err(refused_service(
+                    code,
+                    format!("{server} refused service: {e}"),
+                ));
+            }
+            // A HELO session cannot use implicit TLS
+            (Err(e), None) if e.is_permanent() && wrapper_tls.is_none() => {
+                warn!("SMTP relay backend: EHLO was rejected ({e}), falling back to HELO");
+                return self.connect_helo();
+            }
+            (Err(e), None) => {
+                let report = report!("Failed to connect to SMTP relay: {e}")
+                    .attach(format!("Server: {server}"))
+                    .into_dynamic();
+                return Err(connect_error(report, e.is_permanent()));
+            }
+        };
+        if self.connect_timeout.is_some() {
+            connection.set_timeout(Some(self.timeout)).map_err(|e| {
+                report!("Failed to set the SMTP command timeout: {e}")
+                    .attach(format!("Server: {server}"))
+            })?;
+        }
+
+        let starttls_params = match &self.tls {
+            Tls::Opportunistic(tls_params) if connection.can_starttls() => Some(tls_params),
+            Tls::Required(tls_params) => Some(tls_params),
+            _ => None,
+        };
+        if let Some(tls_params) = starttls_params {
+            connection
+                .starttls(tls_params, &self.hello_name)
+                .map_err(|e| {
+                    report!("Failed to start TLS: {e}").attach(format!("Server: {server}"))
+                })?;
+        }
+
+        // lettre does not keep the value of SIZE or unknown keywords like DSN, so ask again.
+        // This has to happen before AUTH, as a new EHLO resets the session on some relays.
+        let extensions = connection
+            .command(Ehlo::new(self.hello_name.clone()))
+            .map(|response| EhloExtensions::parse(&response))
+            .map_err(|e| {
+                report!("Failed to query the SMTP extensions: {e}")
+                    .attach(format!("Server: {server}"))
+            })?;
+
+        if self.auth_mechanism == SmtpAuthMechanism::External {
+            // The TLS handshake already presented the identity, so there is no AUTH command
+            if self.identity.is_none() {
+                return Err(BackendError::SmtpAuthFailed(
+                    "a client certificate is required for EXTERNAL authentication".to_string(),
+                ));
+            }
+            debug!("SMTP relay backend: authenticated by the client certificate");
+        } else if let Some(credentials) = &self.credentials {
+            let accepted: &[Mechanism] = match self.auth_mechanism {
+                SmtpAuthMechanism::Auto => &[Mechanism::Plain, Mechanism::Login],
+                SmtpAuthMechanism::Plain => &[Mechanism::Plain],
+                SmtpAuthMechanism::Login => &[Mechanism::Login],
+                SmtpAuthMechanism::External => unreachable!("handled above"),
+            };
+            let Some(mechanism) = connection.server_info().get_auth_mechanism(accepted) else {
+                return Err(BackendError::SmtpAuthFailed(format!(
+                    "{server} does not offer a supported mechanism ({})",
+                    super::value_name(&self.auth_mechanism)
+                )));
+            };
+            debug!("SMTP relay backend: authenticating with {mechanism}");
+            connection.auth(&[mechanism], credentials).map_err(|e| {
+                BackendError::SmtpAuthFailed(format!("{server} rejected {mechanism}: {e}"))
+            })?;
+        }
+
+        Ok(Box::new(EsmtpSession {
+            connection,
+            extensions,
+        }))
+    }
+
+    /// Open a plain session greeted with `HELO`, for relays that do not support ESMTP.
+    fn connect_helo(&self) -> Result<Box<dyn MailSession>, BackendError> {
+        let server = self.server();
+        // STARTTLS and AUTH are ESMTP extensions, so they are not available after HELO
+        if matches!(self.tls, Tls::Wrapper(_) | Tls::Required(_)) {
+            return Err(report!("Failed to start TLS: not supported after HELO")
+                .attach(format!("Server: {server}"))
+                .attach(format!("Protocol: {:?}", self.tls_mode))
+                .into());
+        }
+        if self.credentials.is_some() {
+            return Err(report!("Failed to authenticate: not supported after HELO")
+                .attach(format!("Server: {server}"))
+                .into());
+        }
+
+        let connect_timeout = self.connect_timeout.unwrap_or(self.timeout);
+        let mut greeting = None;
+        let session = HeloSession::connect(
+            &self.host,
+            self.port,
+            connect_timeout,
+            self.timeout,
+            &self.hello_name,
+            &mut greeting,
+        );
+        let banner = greeting.as_ref().map(|greeting| {
+            let text = greeting.message().collect::<Vec<_>>().join(" ");
+            format!("{} {text}", greeting.code())
+        });
+        if let Some(banner) = &banner {
+            // lettre does not hand out the greeting of ESMTP sessions, only this one is known
+            debug!("SMTP relay backend: greeting: {banner}");
+        }
+        let refused = greeting
+            .map(|greeting| greeting.code())
+            .filter(|&code| refuses_service(code));
+        match (session, refused) {
+            (Ok(session), _) => Ok(Box::new(session)),
+            (Err(_), Some(code)) => Err(refused_service(
+                code,
+                format!("{server} refused service: {}", banner.unwrap_or_default()),
+            )),
+            (Err(e), None) => {
+                let report = report!("Failed to connect to SMTP relay: {e}")
+                    .attach(format!("Server: {server}"))
+                    .into_dynamic();
+                Err(connect_error(report, e.rejected))
+            }
+        }
+    }
+
+    /// Run the mail transaction on an established connection.
+    fn transaction(
+        &self,
+        connection: &mut dyn MailSession,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        raw_email: &[u8],
+        dsn_notify: &[DsnNotify],
+    ) -> Result<SendReceipt, BackendError> {
+        let extensions = connection.extensions();
+        // A `SIZE` of 0 announces the extension without a fixed limit (RFC 1870, section 4)
+        Self::check_size_limit(
+            raw_email,
+            extensions.size.filter(|&size| size > 0),
+            self.max_message_size,
+        )?;
+
+        let mut mail_parameters = Vec::new();
+        if extensions.size.is_some() {
+            mail_parameters.push(MailParameter::Size(raw_email.len()));
+        }
+        let mut rcpt_parameters = Vec::new();
+        if extensions.dsn {
+            if let Some(ret) = self.dsn_ret {
+                mail_parameters.push(MailParameter::Other {
+                    keyword: "RET".to_string(),
+                    value: Some(ret.as_str().to_string()),
+                });
+            }
+            if !dsn_notify.is_empty() {
+                let notify: Vec<&str> = dsn_notify.iter().map(|notify| notify.as_str()).collect();
+                rcpt_parameters.push(RcptParameter::Other {
+                    keyword: "NOTIFY".to_string(),
+                    value: Some(notify.join(",")),
+                });
+            }
+        } else if !dsn_notify.is_empty() || self.dsn_ret.is_some() {
             debug!(
-                "SMTP relay backend: not using authentication because no username or password was provided"
+                "SMTP relay backend: the relay does not support DSN, not requesting notifications"
             );
         }
+        let has_non_ascii_address = envelope_from
+            .into_iter()
+            .chain(envelope_to.iter().copied())
+            .any(|address| !AsRef::<str>::as_ref(address).is_ascii());
+        if has_non_ascii_address {
+            if !connection.supports_feature(Extension::SmtpUtfEight) {
+                return Err(report!(
+                    "Envelope contains non-ASCII addresses but the server does not support SMTPUTF8"
+                )
+                .into());
+            }
+            mail_parameters.push(MailParameter::SmtpUtfEight);
+        }
+        if !raw_email.is_ascii() {
+            if !connection.supports_feature(Extension::EightBitMime) {
+                return Err(report!(
+                    "Message contains non-ASCII characters but the server does not support 8BITMIME"
+                )
+                .into());
+            }
+            mail_parameters.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+        }
 
-        let transport = transport.build();
+        connection
+            .command(&Mail::new(envelope_from.cloned(), mail_parameters))
+            .map_err(|e| {
+                let report = report!("Sender rejected: {e}")
+                    .attach(format!("Envelope from: {}", format_sender(envelope_from)));
+                e.backend_error(report.into_dynamic(), self.timeout)
+            })?;
 
-        Ok(Self { transport })
+        // Issue every RCPT TO so a single bad recipient does not block the others
+        let mut accepted = Vec::new();
+        let mut rejected = Vec::new();
+        let mut all_transient = true;
+        for recipient in envelope_to {
+            match connection.command(&Rcpt::new((*recipient).clone(), rcpt_parameters.clone())) {
+                Ok(_) => accepted.push(recipient.to_string()),
+                Err(e) if e.rejected => {
+                    debug!("SMTP relay backend: recipient {recipient} rejected: {e}");
+                    all_transient &= e.transient;
+                    rejected.push((recipient.to_string(), e.to_string()));
+                }
+                Err(e) => {
+                    let report = report!("Failed to send mail: {e}")
+                        .attach(format!("Recipient: {recipient}"));
+                    return Err(e.backend_error(report.into_dynamic(), self.timeout));
+                }
+            }
+        }
+
+        if accepted.is_empty() {
+            let mut report = report!("All recipients were rejected");
+            for (recipient, response) in &rejected {
+                report = report.attach(format!("Rejected: {recipient}: {response}"));
+            }
+            // Recipients that were only deferred may be accepted when trying again later
+            return Err(if all_transient {
+                BackendError::Unavailable(report.into_dynamic())
+            } else {
+                report.into()
+            });
+        }
+
+        // lettre only sends commands that are text, so BDAT chunks have to be valid UTF-8
+        let use_bdat = self.use_bdat && extensions.chunking;
+        let response = if use_bdat && std::str::from_utf8(raw_email).is_ok() {
+            send_bdat(
+                connection,
+                &with_final_line_break(raw_email),
+                BDAT_CHUNK_SIZE,
+            )
+        } else {
+            if use_bdat {
+                debug!("SMTP relay backend: the message is not valid UTF-8, sending it with DATA");
+            }
+            connection
+                .command(&Data)
+                .and_then(|_| connection.message(raw_email))
+        }
+        .map_err(|e| {
+            e.backend_error(
+                report!("Failed to send mail: {e}").into_dynamic(),
+                self.timeout,
+            )
+        })?;
+        let message_id = response.message().find_map(queue_id);
+
+        if rejected.is_empty() {
+            Ok(SendReceipt {
+                message_id,
+                path: None,
+            })
+        } else {
+            Err(BackendError::PartialDelivery { accepted, rejected })
+        }
+    }
+}
+
+/// Whether a reply with `code` means that the server does not offer service at all
+/// (RFC 5321, sections 3.1 and 4.2.3), so that trying again with `HELO` is pointless
+fn refuses_service(code: Code) -> bool {
+    matches!(u16::from(code), 421 | 554)
+}
+
+/// Error for a failed connection to the relay.
+///
+/// Unless the relay rejected the session for good, the failure is a network error or a transient
+/// reply, which may clear up when retrying later.
+fn connect_error(report: Report, permanent: bool) -> BackendError {
+    if permanent {
+        BackendError::Failed(report)
+    } else {
+        BackendError::Unavailable(report)
+    }
+}
+
+/// Error for a relay that refused the session with a reply with `code`.
+///
+/// A `421` announces that the service is not available right now (RFC 5321, section 3.8), so
+/// sending may succeed later, unlike after a `554`.
+fn refused_service(code: Code, detail: String) -> BackendError {
+    if code.severity == Severity::TransientNegativeCompletion {
+        BackendError::Unavailable(report!("{detail}").into_dynamic())
+    } else {
+        BackendError::SmtpProtocolError(detail)
+    }
+}
+
+/// Failure of a single SMTP command
+#[derive(Debug)]
+struct CommandError {
+    message: String,
+    /// Whether the server answered with a negative reply, as opposed to the session failing
+    rejected: bool,
+    /// Whether the reply was a transient one (`4xx`)
+    transient: bool,
+    /// Whether the session failed because the relay did not answer in time
+    timed_out: bool,
+}
+
+impl CommandError {
+    fn failed(error: impl Display) -> Self {
+        Self {
+            message: error.to_string(),
+            rejected: false,
+            transient: false,
+            timed_out: false,
+        }
+    }
+
+    /// The error of a mail transaction that failed with this, described by `report`
+    ///
+    /// Transient replies and sessions that failed are temporary, so the message is tried again
+    /// later; only a permanent reply fails the message.
+    fn backend_error(&self, report: Report, timeout: Duration) -> BackendError {
+        if self.timed_out {
+            BackendError::Timeout(timeout)
+        } else if self.transient || !self.rejected {
+            BackendError::Unavailable(report)
+        } else {
+            BackendError::Failed(report)
+        }
+    }
+
+    /// Error for a negative reply, worded like the errors of lettre
+    fn from_response(response: &Response) -> Self {
+        let transient = response.code().severity == Severity::TransientNegativeCompletion;
+        let kind = if transient { "transient" } else { "permanent" };
+        let text = response.message().collect::<Vec<_>>().join(" ");
+        Self {
+            message: format!("{kind} error ({}): {text}", response.code()),
+            rejected: true,
+            transient,
+            timed_out: false,
+        }
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for CommandError {
+    fn from(error: lettre::transport::smtp::Error) -> Self {
+        Self {
+            rejected: error.is_permanent() || error.is_transient(),
+            transient: error.is_transient(),
+            timed_out: error.is_timeout(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// The commands a mail transaction needs, implemented by ESMTP connections and by
+/// [`HeloSession`]
+trait MailSession {
+    /// Whether the server announced `extension`
+    fn supports_feature(&self, extension: Extension) -> bool;
+    /// The server name and extensions it announced, for logs
+    fn capabilities(&self) -> String;
+    /// The extensions with parameters lettre does not keep, like the value of `SIZE`
+    fn extensions(&self) -> EhloExtensions;
+    fn command(&mut self, command: &dyn Display) -> Result<Response, CommandError>;
+    /// Send the message content after `DATA` and return the final reply
+    fn message(&mut self, message: &[u8]) -> Result<Response, CommandError>;
+    /// Send one chunk of the message with `BDAT` (RFC 3030) and return the reply
+    fn bdat(&mut self, chunk: &[u8], last: bool) -> Result<Response, CommandError>;
+    fn quit(&mut self);
+    fn abort(&mut self);
+}
+
+/// An ESMTP connection with the extensions the relay announced when it was opened
+struct EsmtpSession {
+    connection: SmtpConnection,
+    extensions: EhloExtensions,
+}
+
+impl MailSession for EsmtpSession {
+    fn supports_feature(&self, extension: Extension) -> bool {
+        self.connection.server_info().supports_feature(extension)
+    }
+
+    fn capabilities(&self) -> String {
+        self.connection.server_info().to_string()
+    }
+
+    fn extensions(&self) -> EhloExtensions {
+        self.extensions
+    }
+
+    fn command(&mut self, command: &dyn Display) -> Result<Response, CommandError> {
+        Ok(self.connection.command(command)?)
+    }
+
+    fn message(&mut self, message: &[u8]) -> Result<Response, CommandError> {
+        Ok(self.connection.message(message)?)
+    }
+
+    fn bdat(&mut self, chunk: &[u8], last: bool) -> Result<Response, CommandError> {
+        let chunk = std::str::from_utf8(chunk).map_err(CommandError::failed)?;
+        Ok(self.connection.command(Bdat { chunk, last })?)
+    }
+
+    fn quit(&mut self) {
+        let _ = self.connection.command(Quit);
+    }
+
+    fn abort(&mut self) {
+        self.connection.abort();
+    }
+}
+
+/// Extensions announced in a reply to `EHLO` that lettre's `ServerInfo` does not cover
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct EhloExtensions {
+    /// The `SIZE` the server announces (RFC 1870), 0 if it has no fixed limit, or `None` if it
+    /// does not support the extension
+    size: Option<u64>,
+    /// Whether the server accepts delivery status notification requests (RFC 3461)
+    dsn: bool,
+    /// Whether the server accepts the message in chunks with `BDAT` (RFC 3030)
+    chunking: bool,
+}
+
+impl EhloExtensions {
+    fn parse(response: &Response) -> Self {
+        let mut extensions = Self::default();
+        // The first line is the server name, the extensions follow
+        for line in response.message().skip(1) {
+            let mut words = line.split_whitespace();
+            let Some(keyword) = words.next() else {
+                continue;
+            };
+            if keyword.eq_ignore_ascii_case("SIZE") {
+                extensions.size =
+                    Some(words.next().and_then(|size| size.parse().ok()).unwrap_or(0));
+            } else if keyword.eq_ignore_ascii_case("DSN") {
+                extensions.dsn = true;
+            } else if keyword.eq_ignore_ascii_case("CHUNKING") {
+                extensions.chunking = true;
+            }
+        }
+        extensions
+    }
+}
+
+/// Largest chunk of the message sent with a single `BDAT` command
+const BDAT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// A `BDAT` command together with its chunk, as lettre can only send the two as one command
+struct Bdat<'a> {
+    chunk: &'a str,
+    last: bool,
+}
+
+impl Display for Bdat<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let last = if self.last { " LAST" } else { "" };
+        write!(f, "BDAT {}{last}\r\n{}", self.chunk.len(), self.chunk)
+    }
+}
+
+/// Send `data` in `BDAT` chunks of at most `chunk_size` bytes, the last one marked `LAST`, and
+/// return the reply to the last chunk.
+///
+/// Chunks end on UTF-8 character boundaries, so every chunk of a UTF-8 message is valid UTF-8
+/// on its own. An empty message is sent as `BDAT 0 LAST`.
+fn send_bdat(
+    connection: &mut dyn MailSession,
+    data: &[u8],
+    chunk_size: usize,
+) -> Result<Response, CommandError> {
+    let chunk_size = chunk_size.max(1);
+    let mut rest = data;
+    loop {
+        let mut end = chunk_size.min(rest.len());
+        // Continuation bytes of a UTF-8 sequence look like 0b10xxxxxx
+        while end < rest.len() && end > 0 && rest[end] & 0xC0 == 0x80 {
+            end -= 1;
+        }
+        if end == 0 {
+            end = chunk_size.min(rest.len());
+        }
+        let (chunk, next) = rest.split_at(end);
+        let response = connection.bdat(chunk, next.is_empty())?;
+        if next.is_empty() {
+            return Ok(response);
+        }
+        rest = next;
+    }
+}
+
+/// `DATA` ends the message on a line of its own, `BDAT` sends it exactly as it is, so make sure
+/// the last line is complete.
+fn with_final_line_break(raw_email: &[u8]) -> Cow<'_, [u8]> {
+    if raw_email.is_empty() || raw_email.ends_with(b"\n") {
+        Cow::Borrowed(raw_email)
+    } else {
+        let mut terminated = raw_email.to_vec();
+        terminated.extend_from_slice(b"\r\n");
+        Cow::Owned(terminated)
+    }
+}
+
+/// Extract the queue ID from a response line like `Ok: queued as 4BfQ2x1v3Zz9` (Postfix) or
+/// `OK id=1rXyZa-000Abc-2D` (Exim).
+fn queue_id(line: &str) -> Option<String> {
+    let lowercase = line.to_ascii_lowercase();
+    let start = ["queued as ", "id="]
+        .iter()
+        .find_map(|marker| lowercase.find(marker).map(|index| index + marker.len()))?;
+    let id = line[start..]
+        .split_whitespace()
+        .next()?
+        .trim_matches(|c: char| matches!(c, '<' | '>' | '(' | ')' | ',' | '.'));
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// Mark bulk and junk mail (by its `Precedence:` header) as non-urgent for the relay.
+///
+/// `Priority: non-urgent` (RFC 2156) and `X-Priority: 5` are added unless the message already
+/// carries priority headers of its own.
+fn add_priority_headers(raw_email: &str) -> Cow<'_, str> {
+    let headers = parser::parse_email_headers(raw_email);
+    let has_priority =
+        parser::has_header(&headers, "Priority") || parser::has_header(&headers, "X-Priority");
+    if !parser::extract_precedence(&headers).is_low() || has_priority {
+        return Cow::Borrowed(raw_email);
+    }
+    debug!("SMTP relay backend: marking bulk message as non-urgent");
+    Cow::Owned(format!(
+        "Priority: non-urgent\r\nX-Priority: 5\r\n{raw_email}"
+    ))
+}
+
+/// Longest line SMTP allows, without the CRLF (RFC 5321, section 4.5.3.1.6)
+pub const MAX_LINE_LENGTH: usize = 998;
+
+/// The body of `raw_email`, after the empty line that ends the header section, and the number
+/// of its first line. Empty if the message has no body.
+fn body_of(raw_email: &[u8]) -> (&[u8], usize) {
+    let mut offset = 0;
+    for (index, line) in raw_email.split_inclusive(|&byte| byte == b'\n').enumerate() {
+        offset += line.len();
+        if line == b"\n" || line == b"\r\n" {
+            return (&raw_email[offset..], index + 2);
+        }
+    }
+    (&[], 0)
+}
+
+/// Fail if a line of the body of `raw_email` is longer than SMTP allows.
+///
+/// The headers are folded by whoever wrote them, so only the body is checked.
+fn check_line_length(raw_email: &[u8]) -> Result<(), BackendError> {
+    let (body, first_line) = body_of(raw_email);
+    let too_long = body
+        .split(|&byte| byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line).len())
+        .enumerate()
+        .find(|&(_, length)| length > MAX_LINE_LENGTH);
+    match too_long {
+        Some((index, length)) => Err(report!(
+            "Line {} of the message is {length} octets long, SMTP allows at most {MAX_LINE_LENGTH}",
+            first_line + index
+        )
+        .attach("Set SENDMAIL_WRAP_LONG_LINES=1 to wrap long lines")
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Break the body lines of `raw_email` that are longer than SMTP allows into shorter lines.
+///
+/// Every line that is broken ends with a space, so readers of `format=flowed` messages join the
+/// pieces again. Lines are broken after an existing space where possible; otherwise a space is
+/// added, without splitting a UTF-8 sequence.
+fn wrap_long_lines(raw_email: &[u8]) -> Cow<'_, [u8]> {
+    let (body, _) = body_of(raw_email);
+    let head = &raw_email[..raw_email.len() - body.len()];
+    let is_long = |line: &[u8]| line.strip_suffix(b"\r").unwrap_or(line).len() > MAX_LINE_LENGTH;
+    if !body.split(|&byte| byte == b'\n').any(is_long) {
+        return Cow::Borrowed(raw_email);
+    }
+
+    let mut wrapped = head.to_vec();
+    for line in body.split_inclusive(|&byte| byte == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        let eol = &line[content.len()..];
+        if content.len() <= MAX_LINE_LENGTH {
+            wrapped.extend_from_slice(line);
+            continue;
+        }
+        // The last line may have no ending, its soft line breaks take that of the headers
+        let crlf = eol == b"\r\n" || (eol.is_empty() && head.ends_with(b"\r\n"));
+        let soft_eol: &[u8] = if crlf { b"\r\n" } else { b"\n" };
+        let mut rest = content;
+        while rest.len() > MAX_LINE_LENGTH {
+            match rest[..MAX_LINE_LENGTH]
+                .iter()
+                .rposition(|&byte| byte == b' ')
+            {
+                Some(space) if space > 0 => {
+                    wrapped.extend_from_slice(&rest[..=space]);
+                    rest = &rest[space + 1..];
+                }
+                _ => {
+                    let mut split = MAX_LINE_LENGTH - 1;
+                    while split > 1 && rest[split] & 0xC0 == 0x80 {
+                        split -= 1;
+                    }
+                    wrapped.extend_from_slice(&rest[..split]);
+                    wrapped.push(b' ');
+                    rest = &rest[split..];
+                }
+            }
+            wrapped.extend_from_slice(soft_eol);
+        }
+        wrapped.extend_from_slice(rest);
+        wrapped.extend_from_slice(eol);
+    }
+    debug!("SMTP relay backend: wrapped body lines longer than {MAX_LINE_LENGTH} octets");
+    Cow::Owned(wrapped)
+}
+
+impl SmtpBackend {
+    /// Send a message that is not necessarily valid UTF-8, like a body with raw 8-bit data.
+    fn send_bytes(
+        &self,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        raw_email: &[u8],
+        dsn_notify: &[DsnNotify],
+    ) -> Result<SendReceipt, BackendError> {
+        if envelope_to.is_empty() {
+            return Err(
+                report!("Failed to create envelope: missing destination address")
+                    .attach(format!("Envelope from: {}", format_sender(envelope_from)))
+                    .into(),
+            );
+        }
+
+        let raw_email = if self.wrap_long_lines {
+            wrap_long_lines(raw_email)
+        } else {
+            check_line_length(raw_email)?;
+            Cow::Borrowed(raw_email)
+        };
+        Self::check_size_limit(&raw_email, None, self.max_message_size)?;
+
+        let mut connection = self.connect()?;
+        let result = self.transaction(
+            &mut *connection,
+            envelope_from,
+            envelope_to,
+            &raw_email,
+            dsn_notify,
+        );
+        if result.is_ok() || matches!(result, Err(BackendError::PartialDelivery { .. })) {
+            connection.quit();
+        } else {
+            connection.abort();
+        }
+        result
     }
 }
 
 impl EmailBackend for SmtpBackend {
     fn send(
         &self,
-        envelope_from: &Address,
+        envelope_from: Option<&Address>,
         envelope_to: &[&Address],
         raw_email: &str,
-    ) -> Result<(), Report> {
-        let raw_email_bytes = raw_email.as_bytes();
+    ) -> Result<SendReceipt, BackendError> {
+        let dsn_notify = self.dsn_notify_for(raw_email);
+        let raw_email = add_priority_headers(raw_email);
+        self.send_bytes(
+            envelope_from,
+            envelope_to,
+            raw_email.as_bytes(),
+            &dsn_notify,
+        )
+    }
 
-        let lettre_envelope_to = envelope_to.iter().map(|e| (*e).clone()).collect::<Vec<_>>();
-        let lettre_envelope_from = envelope_from.clone();
-        let lettre_envelope = Envelope::new(Some(lettre_envelope_from), lettre_envelope_to)
-            .map_err(|e| {
-                report!("Failed to create envelope: {e}")
-                    .attach(format!("Envelope from: {envelope_from}"))
-                    .attach(format!("Envelope to: {envelope_to:?}"))
-            })?;
+    fn send_stream(
+        &self,
+        envelope_from: Option<&Address>,
+        envelope_to: &[&Address],
+        head: &str,
+        body: &mut dyn Read,
+    ) -> Result<SendReceipt, BackendError> {
+        // The body is sent as it is, so it does not need to be valid UTF-8
+        let dsn_notify = self.dsn_notify_for(head);
+        let mut raw_email = add_priority_headers(head).into_owned().into_bytes();
+        body.read_to_end(&mut raw_email)?;
+        self.send_bytes(envelope_from, envelope_to, &raw_email, &dsn_notify)
+    }
 
-        self.transport
-            .send_raw(&lettre_envelope, raw_email_bytes)
-            .map_err(|e| report!("Failed to send mail: {e}"))?;
-        Ok(())
+    fn describe(&self) -> String {
+        let mut description = format!(
+            "smtp relay={} tls={}",
+            self.server(),
+            super::value_name(&self.tls_mode)
+        );
+        if let Some(sni_host) = &self.tls_sni_host {
+            description.push_str(&format!(" sni={sni_host}"));
+        }
+        if self.auth_mechanism == SmtpAuthMechanism::External {
+            description.push_str(" auth=external");
+        } else if let Some(username) = &self.username {
+            description.push_str(&format!(
+                " user={username} password=*** auth={}",
+                super::value_name(&self.auth_mechanism)
+            ));
+        }
+        if self.identity.is_some() {
+            description.push_str(" client-cert=yes");
+        }
+        if !self.extra_roots.is_empty() {
+            description.push_str(&format!(" extra-roots={}", self.extra_roots.len()));
+        }
+        if self.force_helo {
+            description.push_str(" helo=yes");
+        }
+        if self.wrap_long_lines {
+            description.push_str(" wrap-long-lines=yes");
+        }
+        if self.use_bdat {
+            description.push_str(" bdat=yes");
+        }
+        if self.timeout != DEFAULT_SMTP_TIMEOUT {
+            description.push_str(&format!(" timeout={}s", self.timeout.as_secs()));
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            description.push_str(&format!(" connect-timeout={}s", connect_timeout.as_secs()));
+        }
+        if let Some(limit) = self.max_message_size {
+            description.push_str(&format!(" max-size={limit}"));
+        }
+        if !self.dsn_notify.is_empty() {
+            let notify: Vec<String> = self.dsn_notify.iter().map(super::value_name).collect();
+            description.push_str(&format!(" dsn-notify={}", notify.join(",")));
+        }
+        if let Some(ret) = &self.dsn_ret {
+            description.push_str(&format!(" dsn-ret={}", super::value_name(ret)));
+        }
+        description
+    }
+
+    fn verify(&self) -> Result<String, BackendError> {
+        self.verify_connection()?;
+        let mut summary = format!("connected to {}", self.server());
+        if self.auth_mechanism == SmtpAuthMechanism::External {
+            summary.push_str(" and authenticated with the client certificate");
+        } else if let Some(username) = &self.username {
+            summary.push_str(&format!(" and authenticated as {username}"));
+        }
+        Ok(summary)
+    }
+}
+
+/// Check the relay host and return it in the form used to connect.
+///
+/// The host must be a domain name, an IPv4 address or an IPv6 address. IPv6 addresses are
+/// accepted with or without the brackets of URLs and SMTP address literals, and returned
+/// without them.
+fn parse_relay_host(host: &str) -> Result<String, Report> {
+    // `url::Host` only takes IPv6 addresses in brackets
+    let bracketed = if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{host}]")
+    } else {
+        host.to_string()
+    };
+    match Host::parse(&bracketed) {
+        Ok(Host::Ipv6(address)) => Ok(address.to_string()),
+        Ok(Host::Ipv4(_) | Host::Domain(_)) => Ok(host.to_string()),
+        Err(e) => {
+            let mut report =
+                report!("Invalid SMTP relay host: {e}").attach(format!("Host: {host}"));
+            if host.contains(':') {
+                report = report.attach("Hint: set the port with SENDMAIL_RELAY_PORT".to_string());
+            }
+            Err(report)
+        }
     }
 }
 
@@ -117,4 +1270,332 @@ mod tests {
         // The default sender should be username@localhost
         assert_eq!(default_sender.domain(), "localhost");
     }
+
+    #[test]
+    fn test_queue_id() {
+        assert_eq!(
+            queue_id("2.0.0 Ok: queued as 4BfQ2x1v3Zz9").as_deref(),
+            Some("4BfQ2x1v3Zz9")
+        );
+        assert_eq!(
+            queue_id("OK id=1rXyZa-000Abc-2D").as_deref(),
+            Some("1rXyZa-000Abc-2D")
+        );
+        assert_eq!(
+            queue_id("2.0.0 <ABC123@mail.example.com> Queued mail for delivery").as_deref(),
+            None
+        );
+        assert_eq!(queue_id("2.0.0 Ok: queued as").as_deref(), None);
+    }
+
+    #[test]
+    fn test_ehlo_extensions() {
+        let ehlo = |reply: &str| EhloExtensions::parse(&reply.parse().unwrap()).size;
+        assert_eq!(
+            ehlo("250-mail.example.com\r\n250-SIZE 10240000\r\n250 8BITMIME\r\n"),
+            Some(10_240_000)
+        );
+        assert_eq!(ehlo("250-mail.example.com\r\n250 size\r\n"), Some(0));
+        assert_eq!(ehlo("250-mail.example.com\r\n250 8BITMIME\r\n"), None);
+        // The server name is not an extension
+        assert_eq!(ehlo("250 SIZE\r\n"), None);
+
+        let dsn = |reply: &str| EhloExtensions::parse(&reply.parse().unwrap()).dsn;
+        assert!(dsn("250-mail.example.com\r\n250-DSN\r\n250 8BITMIME\r\n"));
+        assert!(!dsn("250-mail.example.com\r\n250 8BITMIME\r\n"));
+
+        let chunking = |reply: &str| EhloExtensions::parse(&reply.parse().unwrap()).chunking;
+        assert!(chunking("250-mail.example.com\r\n250 CHUNKING\r\n"));
+        assert!(!chunking("250-mail.example.com\r\n250 8BITMIME\r\n"));
+    }
+
+    /// Session that records the `BDAT` chunks it is sent
+    #[derive(Default)]
+    struct BdatRecorder {
+        chunks: Vec<(String, bool)>,
+    }
+
+    impl MailSession for BdatRecorder {
+        fn supports_feature(&self, _extension: Extension) -> bool {
+            false
+        }
+
+        fn capabilities(&self) -> String {
+            String::new()
+        }
+
+        fn extensions(&self) -> EhloExtensions {
+            EhloExtensions::default()
+        }
+
+        fn command(&mut self, command: &dyn Display) -> Result<Response, CommandError> {
+            unreachable!("unexpected command: {command}")
+        }
+
+        fn message(&mut self, _message: &[u8]) -> Result<Response, CommandError> {
+            unreachable!("unexpected DATA")
+        }
+
+        fn bdat(&mut self, chunk: &[u8], last: bool) -> Result<Response, CommandError> {
+            let chunk = String::from_utf8(chunk.to_vec()).map_err(CommandError::failed)?;
+            self.chunks.push((chunk, last));
+            Ok("250 2.0.0 Ok\r\n".parse().unwrap())
+        }
+
+        fn quit(&mut self) {}
+
+        fn abort(&mut self) {}
+    }
+
+    #[test]
+    fn test_send_bdat_chunks() {
+        let chunks = |data: &str, chunk_size| {
+            let mut session = BdatRecorder::default();
+            send_bdat(&mut session, data.as_bytes(), chunk_size).unwrap();
+            session.chunks
+        };
+        let chunk = |chunk: &str, last| (chunk.to_string(), last);
+
+        assert_eq!(chunks("", 4), [chunk("", true)]);
+        assert_eq!(chunks("abcd", 4), [chunk("abcd", true)]);
+        assert_eq!(
+            chunks("abcdefghij", 4),
+            [
+                chunk("abcd", false),
+                chunk("efgh", false),
+                chunk("ij", true)
+            ]
+        );
+        // `ö` takes two bytes and is not split between chunks
+        assert_eq!(
+            chunks("abcödef", 4),
+            [chunk("abc", false), chunk("öde", false), chunk("f", true)]
+        );
+    }
+
+    #[test]
+    fn test_with_final_line_break() {
+        assert_eq!(&*with_final_line_break(b"Body\r\n"), b"Body\r\n");
+        assert_eq!(&*with_final_line_break(b"Body"), b"Body\r\n");
+    }
+
+    #[test]
+    fn test_check_size_limit() {
+        let message = [b'x'; 100];
+        assert!(SmtpBackend::check_size_limit(&message, None, None).is_ok());
+        assert!(SmtpBackend::check_size_limit(&message, Some(100), Some(1000)).is_ok());
+        for (server, config) in [(Some(99), None), (None, Some(99)), (Some(1000), Some(99))] {
+            let error = SmtpBackend::check_size_limit(&message, server, config).unwrap_err();
+            assert!(
+                matches!(
+                    error,
+                    BackendError::MessageTooLarge {
+                        size: 100,
+                        limit: Some(99),
+                        detail: None,
+                    }
+                ),
+                "{server:?} {config:?}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_line_length() {
+        let line = "x".repeat(MAX_LINE_LENGTH);
+        let message = format!("Subject: {line}{line}\r\n\r\n{line}\r\n{line}");
+        assert!(check_line_length(message.as_bytes()).is_ok());
+
+        let message = format!("Subject: Test\r\n\r\nShort\r\n{line}x\r\n");
+        let error = check_line_length(message.as_bytes()).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Line 4 of the message is 999 octets long"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_wrap_long_lines() {
+        let short = "Subject: Test\r\n\r\nShort\r\n";
+        assert!(matches!(
+            wrap_long_lines(short.as_bytes()),
+            Cow::Borrowed(_)
+        ));
+
+        // Broken after the last space that fits
+        let words = "word ".repeat(400);
+        let message = format!("Subject: Test\r\n\r\n{words}\r\nEnd\r\n");
+        let wrapped = String::from_utf8(wrap_long_lines(message.as_bytes()).into_owned()).unwrap();
+        let lines: Vec<&str> = wrapped.split("\r\n").collect();
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_LENGTH));
+        assert_eq!(lines[2].len(), 995);
+        assert!(lines[2].ends_with(' '));
+        assert_eq!(lines[2..5].concat(), words);
+        assert_eq!(lines[5], "End");
+
+        // Without spaces a space is added, and multi-byte characters stay intact
+        let body = "\u{e9}".repeat(1000);
+        let message = format!("Subject: Test\n\n{body}");
+        let wrapped = String::from_utf8(wrap_long_lines(message.as_bytes()).into_owned()).unwrap();
+        let lines: Vec<&str> = wrapped.lines().skip(2).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_LENGTH));
+        let joined: String = lines
+            .iter()
+            .map(|line| line.trim_end_matches(' '))
+            .collect();
+        assert_eq!(joined, body);
+    }
+
+    #[test]
+    fn test_add_priority_headers() {
+        assert_eq!(
+            add_priority_headers("Precedence: bulk\r\n\r\nBody"),
+            "Priority: non-urgent\r\nX-Priority: 5\r\nPrecedence: bulk\r\n\r\nBody"
+        );
+        assert!(matches!(
+            add_priority_headers("Subject: Test\r\n\r\nBody"),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(
+            add_priority_headers("Precedence: junk\r\nX-Priority: 3\r\n\r\nBody"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_smtp_backend_tls_sni_host() {
+        let backend = SmtpBackend::new(
+            "mail-gateway.internal.corp".to_string(),
+            465,
+            SmtpRelayProtocol::Tls,
+            None,
+        )
+        .unwrap()
+        .with_tls_sni_host("smtp.company.com".to_string())
+        .unwrap();
+
+        assert_eq!(backend.host, "mail-gateway.internal.corp");
+        let Tls::Wrapper(tls_params) = &backend.tls else {
+            panic!("expected implicit TLS");
+        };
+        assert_eq!(tls_params.domain(), "smtp.company.com");
+    }
+
+    #[test]
+    fn test_smtp_backend_relay_host() {
+        let new = |host: &str| {
+            SmtpBackend::new(host.to_string(), 25, SmtpRelayProtocol::Plain, None)
+                .map(|backend| backend.server())
+        };
+        assert_eq!(new("mail.example.com").unwrap(), "mail.example.com:25");
+        assert_eq!(new("192.0.2.1").unwrap(), "192.0.2.1:25");
+        // IPv6 addresses connect without brackets and are shown with them
+        assert_eq!(new("2001:db8::1").unwrap(), "[2001:db8::1]:25");
+        assert_eq!(new("[2001:DB8:0::1]").unwrap(), "[2001:db8::1]:25");
+        let backend =
+            SmtpBackend::new("[::1]".to_string(), 25, SmtpRelayProtocol::Plain, None).unwrap();
+        assert_eq!(backend.host, "::1");
+
+        for host in [
+            "mail.example.com:25",
+            "mail example.com",
+            "[2001:db8::1",
+            "[mail]",
+        ] {
+            assert!(new(host).is_err(), "{host}");
+        }
+    }
+
+    #[test]
+    fn test_smtp_backend_ipv6_host_required() {
+        let new = |host: &str| {
+            SmtpBackend::new(host.to_string(), 25, SmtpRelayProtocol::Plain, None).unwrap()
+        };
+        assert!(new("2001:db8::1").with_ipv6_host().is_ok());
+        assert!(new("192.0.2.1").with_ipv6_host().is_err());
+        assert!(new("mail.example.com").with_ipv6_host().is_err());
+    }
+
+    #[test]
+    fn test_smtp_backend_ca_dir_without_certificates() {
+        let backend = SmtpBackend::new(
+            "smtp.example.com".to_string(),
+            465,
+            SmtpRelayProtocol::Tls,
+            None,
+        )
+        .unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "wasix_sendmail_empty_ca_dir_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("README"), "no certificates here").unwrap();
+
+        let err = backend
+            .with_ca_dir(&dir)
+            .err()
+            .expect("a directory without certificates should be rejected");
+        assert!(format!("{err}").contains("does not contain any certificates"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_smtp_backend_ca_dir_keeps_ssl_cert_dir() {
+        let backend = SmtpBackend::new(
+            "smtp.example.com".to_string(),
+            465,
+            SmtpRelayProtocol::Tls,
+            None,
+        )
+        .unwrap();
+        let before = std::env::var_os("SSL_CERT_DIR");
+        let missing = std::env::temp_dir().join("wasix_sendmail_missing_ca_dir");
+        assert!(backend.with_ca_dir(&missing).is_err());
+        // The process environment is never modified
+        assert_eq!(std::env::var_os("SSL_CERT_DIR"), before);
+    }
+
+    #[test]
+    fn test_smtp_backend_client_certificate_missing_file() {
+        let backend = SmtpBackend::new(
+            "smtp.example.com".to_string(),
+            465,
+            SmtpRelayProtocol::Tls,
+            None,
+        )
+        .unwrap();
+        let missing = std::env::temp_dir().join("wasix_sendmail_missing_client_cert.pem");
+        let err = backend
+            .with_client_certificate(&missing, &missing)
+            .err()
+            .expect("a missing certificate should be rejected");
+        assert!(format!("{err}").contains("Failed to read SMTP client certificate"));
+    }
+
+    #[test]
+    fn test_smtp_backend_client_certificate_invalid_pem() {
+        let backend = SmtpBackend::new(
+            "smtp.example.com".to_string(),
+            465,
+            SmtpRelayProtocol::Tls,
+            None,
+        )
+        .unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "wasix_sendmail_invalid_client_cert_{}.pem",
+            std::process::id()
+        ));
+        std::fs::write(&path, "not a certificate").unwrap();
+        let err = backend
+            .with_client_certificate(&path, &path)
+            .err()
+            .expect("an invalid certificate should be rejected");
+        assert!(format!("{err}").contains("does not contain a certificate"));
+        let _ = std::fs::remove_file(&path);
+    }
 }
@@ -0,0 +1,193 @@
+//! Minimal SMTP session greeted with `HELO` instead of `EHLO` (RFC 5321, section 4.1.1.1).
+//!
+//! lettre always greets with `EHLO`, so legacy servers that reject it are talked to directly.
+//! Without `EHLO` the server announces no extensions: there is no STARTTLS, AUTH, 8BITMIME or
+//! SMTPUTF8, and the session always runs over plain TCP.
+
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use lettre::transport::smtp::{
+    commands::Quit,
+    extension::{ClientId, Extension},
+    response::Response,
+};
+
+use super::{CommandError, EhloExtensions, MailSession};
+
+/// Connect to the first address of `host` that accepts a connection within `timeout`.
+fn connect_any(host: &str, port: u16, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{host} has no addresses"),
+        )
+    }))
+}
+
+/// Upper bound for the size of a single reply
+const MAX_RESPONSE_BYTES: usize = 100_000;
+
+pub(super) struct HeloSession {
+    stream: BufReader<TcpStream>,
+}
+
+impl HeloSession {
+    /// Connect to `host:port`, read the greeting and send `HELO`.
+    ///
+    /// Connecting and the greeting are limited by `connect_timeout`, every later command by
+    /// `timeout`. The greeting is stored in `greeting` as soon as it is read, also if it refuses
+    /// the session.
+    pub(super) fn connect(
+        host: &str,
+        port: u16,
+        connect_timeout: Duration,
+        timeout: Duration,
+        hello_name: &ClientId,
+        greeting: &mut Option<Response>,
+    ) -> Result<Self, CommandError> {
+        let stream = connect_any(host, port, connect_timeout).map_err(CommandError::failed)?;
+        let set_timeout = |stream: &TcpStream, timeout| {
+            stream
+                .set_read_timeout(Some(timeout))
+                .and_then(|()| stream.set_write_timeout(Some(timeout)))
+                .map_err(CommandError::failed)
+        };
+        set_timeout(&stream, connect_timeout)?;
+        let mut session = Self {
+            stream: BufReader::new(stream),
+        };
+        let reply = session.read_reply()?;
+        let refused = (!reply.is_positive()).then(|| CommandError::from_response(&reply));
+        *greeting = Some(reply);
+        if let Some(error) = refused {
+            return Err(error);
+        }
+        set_timeout(session.stream.get_ref(), timeout)?;
+        session.command(&format!("HELO {hello_name}\r\n"))?;
+        Ok(session)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), CommandError> {
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(data)
+            .and_then(|()| stream.flush())
+            .map_err(CommandError::failed)
+    }
+
+    /// Read a reply and fail if it is negative.
+    fn read_response(&mut self) -> Result<Response, CommandError> {
+        let response = self.read_reply()?;
+        if response.is_positive() {
+            Ok(response)
+        } else {
+            Err(CommandError::from_response(&response))
+        }
+    }
+
+    /// Read a reply, positive or not.
+    fn read_reply(&mut self) -> Result<Response, CommandError> {
+        let mut buffer = String::new();
+        loop {
+            let start = buffer.len();
+            if self
+                .stream
+                .read_line(&mut buffer)
+                .map_err(CommandError::failed)?
+                == 0
+            {
+                return Err(CommandError::failed("incomplete response"));
+            }
+            if buffer.len() > MAX_RESPONSE_BYTES {
+                return Err(CommandError::failed("SMTP response too large"));
+            }
+            // Every line but the last of a multiline reply has a `-` after the code
+            if buffer.as_bytes().get(start + 3) != Some(&b'-') {
+                break;
+            }
+        }
+        buffer.parse().map_err(CommandError::failed)
+    }
+}
+
+/// Double every `.` at the start of a line, so the message cannot end the data early
+/// (RFC 5321, section 4.5.2).
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(message.len());
+    let mut line_start = true;
+    for &byte in message {
+        if line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        line_start = byte == b'\n';
+    }
+    stuffed
+}
+
+impl MailSession for HeloSession {
+    fn supports_feature(&self, _extension: Extension) -> bool {
+        false
+    }
+
+    fn capabilities(&self) -> String {
+        "greeted with HELO, no extensions".to_string()
+    }
+
+    fn extensions(&self) -> EhloExtensions {
+        EhloExtensions::default()
+    }
+
+    fn command(&mut self, command: &dyn Display) -> Result<Response, CommandError> {
+        self.write(command.to_string().as_bytes())?;
+        self.read_response()
+    }
+
+    fn message(&mut self, message: &[u8]) -> Result<Response, CommandError> {
+        let mut data = dot_stuff(message);
+        data.extend_from_slice(b"\r\n.\r\n");
+        self.write(&data)?;
+        self.read_response()
+    }
+
+    fn bdat(&mut self, chunk: &[u8], last: bool) -> Result<Response, CommandError> {
+        let last = if last { " LAST" } else { "" };
+        let mut data = format!("BDAT {}{last}\r\n", chunk.len()).into_bytes();
+        data.extend_from_slice(chunk);
+        self.write(&data)?;
+        self.read_response()
+    }
+
+    fn quit(&mut self) {
+        let _ = self.command(&Quit);
+    }
+
+    fn abort(&mut self) {
+        let _ = self.command(&Quit);
+        let _ = self.stream.get_ref().shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dot_stuff() {
+        assert_eq!(
+            dot_stuff(b".start\r\nmid.dle\r\n..two\n.\n"),
+            b"..start\r\nmid.dle\r\n...two\n..\n"
+        );
+        assert_eq!(dot_stuff(b"no dots"), b"no dots");
+    }
+}
@@ -0,0 +1,279 @@
+use base64::{Engine, prelude::BASE64_STANDARD};
+use log::debug;
+
+use crate::parser;
+
+/// Unstructured header fields whose non-ASCII values are encoded by [`encode_unstructured_headers`]
+const UNSTRUCTURED_HEADERS: &[&str] = &["Subject", "Comments", "Content-Description"];
+
+/// Maximum length of a single encoded word (RFC 2047 section 2)
+const MAX_ENCODED_WORD_LEN: usize = 75;
+
+const ENCODED_WORD_PREFIX: &str = "=?UTF-8?Q?";
+const ENCODED_WORD_SUFFIX: &str = "?=";
+
+/// Encode text as one or more RFC 2047 `Q` encoded words.
+///
+/// Words are separated by a folding whitespace (`line_ending` followed by a space) so every line
+/// stays within the length limit. Multi-byte characters are never split across words.
+#[must_use]
+pub fn encode_rfc2047_q(text: &str, line_ending: &str) -> String {
+    let max_payload = MAX_ENCODED_WORD_LEN - ENCODED_WORD_PREFIX.len() - ENCODED_WORD_SUFFIX.len();
+    let mut words = Vec::new();
+    let mut payload = String::new();
+
+    for c in text.chars() {
+        let mut encoded = String::new();
+        if c == ' ' {
+            encoded.push('_');
+        } else if c.is_ascii_graphic() && !matches!(c, '=' | '?' | '_') {
+            encoded.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("={byte:02X}"));
+            }
+        }
+
+        if payload.len() + encoded.len() > max_payload {
+            words.push(std::mem::take(&mut payload));
+        }
+        payload.push_str(&encoded);
+    }
+    words.push(payload);
+
+    words
+        .iter()
+        .map(|payload| format!("{ENCODED_WORD_PREFIX}{payload}{ENCODED_WORD_SUFFIX}"))
+        .collect::<Vec<_>>()
+        .join(&format!("{line_ending} "))
+}
+
+/// Encode non-ASCII values of unstructured header fields (like `Subject`) as RFC 2047 encoded
+/// words. Other header fields and the body are left untouched.
+#[must_use]
+pub fn encode_unstructured_headers(raw_email: &str) -> String {
+    parser::replace_matching_header_values(
+        raw_email,
+        |name| {
+            UNSTRUCTURED_HEADERS
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name))
+        },
+        |name, value, line_ending| {
+            if value.is_ascii() {
+                return None;
+            }
+            debug!("Encoding non-ASCII {name}: header");
+            let folding = if line_ending.is_empty() {
+                "\r\n"
+            } else {
+                line_ending
+            };
+            Some(encode_rfc2047_q(value, folding))
+        },
+    )
+}
+
+/// Decode the RFC 2047 encoded words in a header value.
+///
+/// `B` and `Q` encoded words in UTF-8, US-ASCII and ISO-8859-1 are decoded, whitespace between
+/// adjacent encoded words is dropped. Anything that cannot be decoded is kept as it is.
+#[must_use]
+pub fn decode_rfc2047(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+    let mut after_encoded_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        let decoded = candidate.get(2..).and_then(|word| {
+            // `charset?encoding?text?=`, the text itself may not contain `?`
+            let (charset, word) = word.split_once('?')?;
+            let (encoding, word) = word.split_once('?')?;
+            let (text, _) = word.split_once("?=")?;
+            let len = 2 + charset.len() + 1 + encoding.len() + 1 + text.len() + 2;
+            if charset.contains(char::is_whitespace) || text.contains(char::is_whitespace) {
+                return None;
+            }
+            decode_encoded_word(charset, encoding, text).map(|decoded| (decoded, len))
+        });
+        match decoded {
+            Some((decoded, len)) => {
+                if !(after_encoded_word && before.trim().is_empty()) {
+                    output.push_str(before);
+                }
+                output.push_str(&decoded);
+                rest = &candidate[len..];
+                after_encoded_word = true;
+            }
+            None => {
+                output.push_str(before);
+                output.push_str("=?");
+                rest = &candidate[2..];
+                after_encoded_word = false;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+fn decode_encoded_word(charset: &str, encoding: &str, text: &str) -> Option<String> {
+    let bytes = if encoding.eq_ignore_ascii_case("B") {
+        BASE64_STANDARD.decode(text).ok()?
+    } else if encoding.eq_ignore_ascii_case("Q") {
+        decode_quoted_printable(text.replace('_', " ").as_bytes())
+    } else {
+        return None;
+    };
+    // Ignore an RFC 2231 language suffix like `UTF-8*en`
+    let charset = charset.split('*').next().unwrap_or_default();
+    if charset.eq_ignore_ascii_case("UTF-8") || charset.eq_ignore_ascii_case("US-ASCII") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        Some(bytes.iter().map(|&b| char::from(b)).collect())
+    } else {
+        None
+    }
+}
+
+/// Decode quoted-printable data (RFC 2045 section 6.7).
+///
+/// Soft line breaks are removed and `=XX` escapes are replaced by their byte; malformed escapes
+/// are kept literally.
+#[must_use]
+pub fn decode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != b'=' {
+            output.push(data[i]);
+            i += 1;
+            continue;
+        }
+        let rest = &data[i + 1..];
+        if rest.starts_with(b"\r\n") {
+            i += 3;
+        } else if rest.starts_with(b"\n") {
+            i += 2;
+        } else if let Some(byte) = rest
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            output.push(byte);
+            i += 3;
+        } else {
+            output.push(b'=');
+            i += 1;
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_rfc2047() {
+        assert_eq!(decode_rfc2047("Plain subject"), "Plain subject");
+        assert_eq!(
+            decode_rfc2047(&encode_rfc2047_q(&"Grüße ".repeat(12), "\r\n")),
+            "Grüße ".repeat(12)
+        );
+        assert_eq!(decode_rfc2047("=?utf-8?B?S8O2bG4=?= Hbf"), "Köln Hbf");
+        assert_eq!(decode_rfc2047("=?ISO-8859-1?Q?caf=E9?="), "café");
+        assert_eq!(
+            decode_rfc2047("=?KOI8-R?Q?abc?= x =?"),
+            "=?KOI8-R?Q?abc?= x =?"
+        );
+    }
+
+    #[test]
+    fn test_decode_quoted_printable() {
+        assert_eq!(
+            decode_quoted_printable(b"Gr=C3=BC=C3=9Fe, soft=\r\nbreak =3D ok=\n!"),
+            "Grüße, softbreak = ok!".as_bytes()
+        );
+        assert_eq!(
+            decode_quoted_printable(b"broken =ZZ end="),
+            b"broken =ZZ end="
+        );
+    }
+
+    /// Decode a sequence of UTF-8 `Q` encoded words separated by folding whitespace
+    fn decode_rfc2047_q(encoded: &str) -> String {
+        let mut bytes = Vec::new();
+        for word in encoded.split_whitespace() {
+            let payload = word
+                .strip_prefix(ENCODED_WORD_PREFIX)
+                .and_then(|w| w.strip_suffix(ENCODED_WORD_SUFFIX))
+                .expect("should be an encoded word");
+            let mut chars = payload.bytes();
+            while let Some(b) = chars.next() {
+                match b {
+                    b'_' => bytes.push(b' '),
+                    b'=' => {
+                        let hex = [chars.next().unwrap(), chars.next().unwrap()];
+                        let hex = std::str::from_utf8(&hex).unwrap();
+                        bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                    }
+                    b => bytes.push(b),
+                }
+            }
+        }
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_encode_rfc2047_q_round_trip() {
+        let subject = "Grüße aus Köln — 100% = ok?";
+        let encoded = encode_rfc2047_q(subject, "\r\n");
+        assert!(encoded.is_ascii());
+        assert!(encoded.starts_with("=?UTF-8?Q?"));
+        assert_eq!(decode_rfc2047_q(&encoded), subject);
+    }
+
+    #[test]
+    fn test_encode_rfc2047_q_splits_long_text() {
+        let subject = "ä".repeat(40);
+        let encoded = encode_rfc2047_q(&subject, "\r\n");
+        for word in encoded.split("\r\n ") {
+            assert!(word.len() <= MAX_ENCODED_WORD_LEN);
+        }
+        assert!(encoded.contains("\r\n "));
+        assert_eq!(decode_rfc2047_q(&encoded), subject);
+    }
+
+    #[test]
+    fn test_encode_unstructured_headers_utf8_subject() {
+        let email = "From: sender@example.com\nSubject: Héllo wörld\nTo: a@example.com\n\nBödy";
+        let result = encode_unstructured_headers(email);
+
+        let subject_line = result.lines().find(|l| l.starts_with("Subject: ")).unwrap();
+        let value = subject_line.strip_prefix("Subject: ").unwrap();
+        assert_eq!(decode_rfc2047_q(value), "Héllo wörld");
+        assert!(result.contains("From: sender@example.com\n"));
+        assert!(result.contains("To: a@example.com\n"));
+        // The body is not touched
+        assert!(result.ends_with("\n\nBödy"));
+    }
+
+    #[test]
+    fn test_encode_unstructured_headers_folded_subject() {
+        let email = "Subject: Grüße\r\n aus Köln\r\n\r\nBody";
+        let result = encode_unstructured_headers(email);
+        let (headers, body) = result.split_once("\r\n\r\n").unwrap();
+        let value = headers.strip_prefix("Subject: ").unwrap();
+        assert_eq!(decode_rfc2047_q(value), "Grüße aus Köln");
+        assert_eq!(body, "Body");
+    }
+
+    #[test]
+    fn test_encode_unstructured_headers_leaves_ascii_untouched() {
+        let email = "Subject: Plain subject\nX-Other: Ünicode\n\nBody";
+        assert_eq!(encode_unstructured_headers(email), email);
+    }
+}
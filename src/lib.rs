@@ -1,11 +1,19 @@
-use std::io::{Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant, SystemTime};
 pub mod args;
 pub mod backend;
+mod date;
+pub mod encoding;
 pub mod logger;
 pub mod parser;
 
-use lettre::Address;
-use log::info;
+use lettre::{
+    Address,
+    message::{Mailbox, Mailboxes},
+};
+use log::{debug, error, info, warn};
 use rootcause::{
     hooks::{
         Hooks,
@@ -15,81 +23,832 @@ use rootcause::{
 };
 use uuid::Uuid;
 
-use crate::args::{SendmailArgs, parse_cli_args};
+use crate::args::{
+    BackendConfig, DisplayNameUpdate, EnvelopeSender, OperatingMode, SendmailArgs, parse_cli_args,
+};
+use crate::backend::{BackendError, EmailBackend, SendReceipt};
+
+/// Evaluate `$body` inside a `tracing` span named `$name` if the `tracing` feature is enabled.
+macro_rules! phase {
+    ($name:literal, $body:block) => {{
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($name).entered();
+        $body
+    }};
+}
+
+/// Exit code for general errors
+pub const EX_FAILURE: i32 = 1;
+
+/// Exit code for an invalid backend configuration (`EX_USAGE` from sysexits.h)
+pub const EX_USAGE: i32 = 64;
+
+/// Exit code for messages that cannot be accepted as they are (`EX_DATAERR` from sysexits.h)
+pub const EX_DATAERR: i32 = 65;
+
+/// Exit code for errors that may go away when retrying later (`EX_TEMPFAIL` from sysexits.h)
+pub const EX_TEMPFAIL: i32 = 75;
+
+/// Exit code for a failed send, for use with [`std::process::ExitCode`].
+///
+/// Errors that may go away when retrying later map to [`EX_TEMPFAIL`], settings and credentials
+/// that need fixing to [`EX_USAGE`], messages the backend cannot accept to [`EX_DATAERR`] and
+/// everything else to [`EX_FAILURE`].
+impl From<&BackendError> for u8 {
+    fn from(error: &BackendError) -> Self {
+        let exit_code = match error {
+            BackendError::Unavailable(_)
+            | BackendError::RateLimited { .. }
+            | BackendError::Timeout(_) => EX_TEMPFAIL,
+            BackendError::SmtpAuthFailed(_)
+            | BackendError::Unauthorized(_)
+            | BackendError::InvalidConfiguration(_) => EX_USAGE,
+            BackendError::MessageTooLarge { .. } => EX_DATAERR,
+            BackendError::Failed(_)
+            | BackendError::PartialDelivery { .. }
+            | BackendError::SmtpProtocolError(_) => EX_FAILURE,
+        };
+        exit_code as u8
+    }
+}
+
+/// Error returned by [`run_sendmail_err`]: a report and the exit code it maps to
+#[derive(Debug)]
+pub struct SendmailError {
+    report: Report,
+    exit_code: i32,
+}
+
+impl SendmailError {
+    /// Exit code sendmail terminates with for this error
+    #[must_use]
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+
+    #[must_use]
+    pub fn into_report(self) -> Report {
+        self.report
+    }
+}
+
+impl std::fmt::Display for SendmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.report)
+    }
+}
+
+impl From<Report> for SendmailError {
+    fn from(report: Report) -> Self {
+        Self {
+            report,
+            exit_code: EX_FAILURE,
+        }
+    }
+}
+
+impl From<std::io::Error> for SendmailError {
+    fn from(error: std::io::Error) -> Self {
+        Report::from(error).into()
+    }
+}
+
+impl From<parser::ParseError> for SendmailError {
+    fn from(error: parser::ParseError) -> Self {
+        Report::from(error).into()
+    }
+}
 
 /// Run sendmail and return an error report
 pub fn run_sendmail_err(
-    stdin: &mut dyn Read,
-    _stdout: &mut dyn Write,
-    _stderr: &mut dyn Write,
+    stdin: impl Read + Send + 'static,
+    stdout: &mut dyn Write,
+    stderr: &mut dyn Write,
     cli_args: &SendmailArgs,
-) -> Result<(), Report> {
-    logger::init_logger(cli_args.verbosity);
+) -> Result<(), SendmailError> {
+    logger::init_logger(
+        cli_args.log_verbosity(),
+        cli_args.log_file.as_deref(),
+        cli_args
+            .log_file_max_size_mb
+            .map(|megabytes| megabytes.saturating_mul(1024 * 1024)),
+    );
+    for warning in &cli_args.long_option_warnings {
+        warn!("{warning}");
+    }
+
+    if cli_args.show_backend {
+        let backend = create_backend(&cli_args.backend_config)?;
+        writeln!(stdout, "{}", backend.describe())?;
+        return Ok(());
+    }
+
+    if cli_args.verify_backend {
+        return verify_backend(stdout, &cli_args.backend_config);
+    }
+
+    if cli_args.always_succeed {
+        warn!("SENDMAIL_ALWAYS_SUCCEED is set: delivery failures will be reported as success");
+    }
+
+    let mode_description = match cli_args.mode {
+        OperatingMode::Deliver => None,
+        OperatingMode::Smtp => Some("SMTP mode"),
+        OperatingMode::PrintQueue => Some("printing the mail queue"),
+        OperatingMode::Daemon | OperatingMode::DaemonForeground => Some("daemon mode"),
+    };
+    if let Some(description) = mode_description {
+        return Err(report!(
+            "Unsupported operating mode {}: {description} is not supported, only -bm is available",
+            cli_args.mode.flag()
+        )
+        .into());
+    }
 
     // Fail early if no recipients specified and not reading from headers
     if !cli_args.read_recipients_from_headers && cli_args.recipients.is_empty() {
-        return Err(report!("No recipients specified"));
+        return Err(report!("No recipients specified").into());
     }
 
-    let backend = backend::create_from_config(&cli_args.backend_config)?;
+    let backend: Arc<dyn EmailBackend> = create_backend(&cli_args.backend_config)?.into();
 
-    let mut raw_email = String::new();
-    stdin.read_to_string(&mut raw_email)?;
+    let mut stdin: Box<dyn Read> = match cli_args.timeout_secs {
+        Some(secs) => Box::new(DeadlineReader::spawn(
+            stdin,
+            Instant::now() + timeout_budget(Duration::from_secs(secs)).0,
+        )),
+        None => Box::new(stdin),
+    };
+    let result = if cli_args.batch {
+        send_batch(&mut stdin, stdout, stderr, cli_args, &backend)
+    } else {
+        send_message(&mut stdin, stdout, cli_args, &backend)
+    };
 
-    let headers = parser::parse_email_headers(&raw_email);
+    match result {
+        Err(e) if cli_args.always_succeed => {
+            warn!(
+                "Delivery failed, exiting with success because SENDMAIL_ALWAYS_SUCCEED is set: {e}"
+            );
+            write!(
+                stderr,
+                "Delivery failed, exiting with success because SENDMAIL_ALWAYS_SUCCEED is set: {e}"
+            )?;
+            Ok(())
+        }
+        result => result,
+    }
+}
 
-    // Extract recipients from headers if requested
-    let recipients: Vec<Address> = if cli_args.read_recipients_from_headers {
-        info!("Reading recipients from email headers");
-        let mut header_recipients = Vec::new();
-        for header_name in &["To", "Cc", "Bcc"] {
-            for value in parser::header_values(&headers, header_name) {
-                let addrs = parser::parse_mailboxes_header(value)?;
-                header_recipients.extend(addrs);
-            }
+/// Write `recipients` to the file at `path`, one per line, replacing its content.
+///
+/// The list is only informational, so a failure is logged and the message still sent.
+fn write_envelope_to_file(path: &Path, recipients: &[Address]) {
+    let list: String = recipients
+        .iter()
+        .map(|recipient| format!("{recipient}\n"))
+        .collect();
+    match std::fs::write(path, list) {
+        Ok(()) => debug!(
+            "Wrote {} envelope recipient(s) to {}",
+            recipients.len(),
+            path.display()
+        ),
+        Err(e) => warn!(
+            "Failed to write the envelope recipients to {}: {e}",
+            path.display()
+        ),
+    }
+}
+
+/// Read one message from `stdin` and send it through `backend`.
+fn send_message(
+    stdin: &mut dyn Read,
+    stdout: &mut dyn Write,
+    cli_args: &SendmailArgs,
+    backend: &Arc<dyn EmailBackend>,
+) -> Result<(), SendmailError> {
+    // Only the header section is buffered, the body is streamed to the backend
+    let (mut head, body_start) = read_header_section(stdin, cli_args.max_message_size)?;
+    let mut mbox_sender = None;
+    if cli_args.strip_mbox_from
+        && let Some((sender, rest)) = split_mbox_from_line(&head)
+    {
+        debug!("Removing the mbox From line of the message");
+        mbox_sender = sender.parse::<Address>().ok();
+        head = rest.to_string();
+    }
+    let mut body: Box<dyn Read + '_> = match cli_args.max_message_size {
+        // The size is only known once the whole message was read
+        Some(limit) => Box::new(std::io::Cursor::new(read_body_with_limit(
+            &head, body_start, stdin, limit,
+        )?)),
+        None => Box::new(std::io::Cursor::new(body_start).chain(stdin)),
+    };
+    if cli_args.normalize_crlf {
+        body = Box::new(CrlfReader::new(body));
+    }
+
+    let headers = phase!("parse_headers", {
+        let parse = if cli_args.strict {
+            parser::parse_email_headers_strict
+        } else {
+            parser::parse_email_headers_with_limits
+        };
+        let headers = parse(&head, cli_args.max_headers, cli_args.max_header_value_len)?;
+        check_singleton_headers(&headers, cli_args.strict)?;
+        headers
+    });
+
+    let received = parser::extract_received_chain(&headers);
+    if !received.is_empty() {
+        debug!("Message passed through {} host(s) before", received.len());
+        // Hosts that send as the default sender are named after its domain
+        let local = backend.default_sender().domain().to_string();
+        if received.iter().any(|hop| {
+            hop.by.eq_ignore_ascii_case(&local)
+                || hop
+                    .from
+                    .as_deref()
+                    .is_some_and(|from| from.eq_ignore_ascii_case(&local))
+        }) {
+            debug!("Message already passed through {local}, it may be in a relay loop");
         }
-        header_recipients
+    }
+
+    // A resent message is addressed by its most recent Resent-* block instead of To/Cc/Bcc/From
+    let resent = if cli_args.resent {
+        parser::resent_block(&headers)
     } else {
-        cli_args.recipients.clone()
+        &[]
     };
+    if !resent.is_empty() {
+        info!("Using the Resent-* header fields of the message");
+    }
+
+    let case_sensitive_local = !cli_args.dedup_ignore_local_case;
+    let recipients = phase!("extract_recipients", {
+        // Extract recipients from headers if requested
+        let recipients: Vec<Address> = if cli_args.read_recipients_from_headers {
+            info!("Reading recipients from email headers");
+            let (fields, names) = if resent.is_empty() {
+                (headers.as_slice(), ["To", "Cc", "Bcc"])
+            } else {
+                (resent, ["Resent-To", "Resent-Cc", "Resent-Bcc"])
+            };
+            let mut header_recipients = Vec::new();
+            for header_name in names {
+                for value in parser::header_values(fields, header_name) {
+                    let addrs = parser::parse_mailboxes_deduplicated(value, case_sensitive_local)?;
+                    header_recipients.extend(addrs);
+                }
+            }
+            header_recipients
+        } else {
+            cli_args.recipients.clone()
+        };
+        // SMTP needs ASCII domains, so international domain names are sent as A-labels
+        let recipients = recipients
+            .into_iter()
+            .map(parser::encode_address_domain)
+            .collect::<Result<Vec<_>, _>>()?;
+        parser::dedup_addresses(recipients, case_sensitive_local)
+    });
 
     // Check again in case the recipients were read from headers
     if recipients.is_empty() {
-        return Err(report!("No recipients specified"));
+        return Err(report!("No recipients specified").into());
     }
 
-    // Extract From address from headers
+    // The archive copy is only added to the envelope, so no header of the message names it
+    let recipients = match &cli_args.archive_bcc {
+        Some(archive) => {
+            info!("Sending a copy to the archive address {archive}");
+            let mut recipients = recipients;
+            recipients.push(parser::encode_address_domain(archive.clone())?);
+            parser::dedup_addresses(recipients, case_sensitive_local)
+        }
+        None => recipients,
+    };
+    if let Some(path) = &cli_args.envelope_to_file {
+        write_envelope_to_file(path, &recipients);
+    }
+
+    // Extract From addresses from headers
     let header_from = parser::header_values(&headers, "From")
         .next()
-        .and_then(|value| parser::parse_mailbox_header(value).ok());
+        .and_then(|value| parser::parse_mailboxes_header(value).ok())
+        .unwrap_or_default();
+
+    // RFC 5322 requires a Sender: header if there is more than one author
+    let needs_sender = header_from.len() > 1 && !parser::has_header(&headers, "Sender");
+    if needs_sender && cli_args.strict {
+        return Err(
+            report!("Multiple addresses in the From: header require a Sender: header")
+                .attach(format!("From: {}", format_address_list(&header_from)))
+                .into(),
+        );
+    }
+
+    let resent_from = parser::header_values(resent, "Resent-From")
+        .next()
+        .and_then(|value| parser::parse_mailboxes_header(value).ok())
+        .unwrap_or_default();
+
+    let default_from = || {
+        resent_from
+            .first()
+            .or(header_from.first())
+            .cloned()
+            .unwrap_or_else(|| backend.default_sender())
+    };
+    let mut envelope_from = match &cli_args.from {
+        Some(EnvelopeSender::Address(address)) => Some(address.clone()),
+        Some(EnvelopeSender::Null) => {
+            info!("Sending with the null envelope sender");
+            None
+        }
+        None => Some(mbox_sender.unwrap_or_else(default_from)),
+    };
+    if let (Some(address), Some(domain)) = (&mut envelope_from, &cli_args.masquerade_domain) {
+        *address = masquerade_address(address, domain)?;
+        info!("Masquerading envelope sender as {address}");
+    }
+
+    let head = phase!("generate_headers", {
+        // Generated headers need an address even if the envelope sender is null
+        let header_sender = envelope_from.clone().unwrap_or_else(default_from);
+        let mut missing_headers = generate_missing_headers(
+            &headers,
+            &header_sender,
+            cli_args.fullname.as_deref(),
+            cli_args.date_timezone,
+        );
+        if needs_sender {
+            info!("Adding Sender: header for multiple From: addresses");
+            missing_headers.push(format!("Sender: {header_sender}"));
+        }
+        if let Some(subject) = &cli_args.subject {
+            if parser::has_header(&headers, "Subject") {
+                debug!("Ignoring --subject because the message already has a Subject: header");
+            } else {
+                missing_headers.push(format!("Subject: {}", encode_subject(subject)));
+            }
+        }
+        if let Some(submitter) = &cli_args.submitter {
+            if parser::has_header(&headers, "X-Submitted-By") {
+                debug!("Keeping the X-Submitted-By: header of the message");
+            } else {
+                missing_headers.push(format!("X-Submitted-By: {submitter}"));
+            }
+        }
+        if let Some(priority) = cli_args.priority {
+            if parser::has_header(&headers, "Precedence") {
+                debug!("Ignoring --priority because the message already has a Precedence: header");
+            } else if let Some(precedence) = priority.precedence() {
+                // Backends derive the delivery priority from this header
+                missing_headers.push(format!("Precedence: {precedence}"));
+            }
+        }
+        let mut head = prepend_headers(&head, &missing_headers);
+        if let (Some(update), Some(fullname)) =
+            (cli_args.from_header_display_name_only, &cli_args.fullname)
+            && parser::has_header(&headers, "From")
+        {
+            head = set_from_display_name(&head, fullname, update);
+        }
+        if !resent.is_empty() {
+            let missing_resent_headers =
+                generate_missing_resent_headers(resent, &header_sender, cli_args.date_timezone);
+            head = insert_resent_headers(&head, &missing_resent_headers);
+        }
+        if let (true, Some(domain)) = (cli_args.masquerade_header, &cli_args.masquerade_domain) {
+            head = masquerade_from_header(&head, domain);
+        }
+        if cli_args.encode_headers {
+            head = encoding::encode_unstructured_headers(&head);
+        }
+        // Generated headers end with CRLF, so mixing them into an LF message needs this as well
+        if cli_args.normalize_crlf {
+            head = normalize_to_crlf(&head);
+        }
+        head
+    });
 
-    let envelope_from = cli_args
-        .from
-        .clone()
-        .or(header_from)
-        .unwrap_or_else(|| backend.default_sender());
+    // Generated headers above keep the Unicode form of the sender domain
+    let envelope_from = envelope_from
+        .map(parser::encode_address_domain)
+        .transpose()?;
 
-    let missing_headers =
-        generate_missing_headers(&headers, &envelope_from, cli_args.fullname.as_deref());
-    let raw_email = prepend_headers(&raw_email, &missing_headers);
+    // lettre accepts some addresses that SMTP servers reject, so check the envelope strictly
+    for address in envelope_from.iter().chain(&recipients) {
+        parser::validate_envelope_address(address)?;
+    }
 
     let recipients_refs: Vec<&Address> = recipients.iter().collect();
-    backend.send(&envelope_from, &recipients_refs, &raw_email)?;
-    Ok(())
+    let sent = phase!("send", {
+        match cli_args.timeout_secs {
+            Some(secs) => {
+                // The backend runs on a thread that may outlive this call, so it gets its own copy
+                let mut buffered = Vec::new();
+                body.read_to_end(&mut buffered)?;
+                let timeout = timeout_budget(Duration::from_secs(secs)).1;
+                send_with_timeout(
+                    Arc::clone(backend),
+                    envelope_from.clone(),
+                    recipients.clone(),
+                    head.clone(),
+                    buffered,
+                    timeout,
+                )
+            }
+            None => backend.send_stream(envelope_from.as_ref(), &recipients_refs, &head, &mut body),
+        }
+    });
+    match sent {
+        Ok(receipt) => {
+            if let Some(path) = receipt.path {
+                // Print the file so scripts can pick up the message
+                writeln!(stdout, "{}", path.display())?;
+                return Ok(());
+            }
+            match receipt.message_id {
+                Some(message_id) => {
+                    info!("Message accepted with id {message_id}");
+                    // Print the id so scripts can correlate the submission with provider logs
+                    writeln!(stdout, "{message_id}")?;
+                }
+                // Without an id from the backend, the Message-ID is the best reference
+                None => {
+                    let headers = parser::parse_email_headers(&head);
+                    if let Some(message_id) = parser::header_values(&headers, "Message-ID").next() {
+                        writeln!(stdout, "{}", message_id.trim())?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        Err(e) => {
+            let exit_code = i32::from(u8::from(&e));
+            let report = match e {
+                BackendError::Failed(report)
+                | BackendError::Unavailable(report)
+                | BackendError::Unauthorized(report) => report,
+                e @ (BackendError::SmtpAuthFailed(_)
+                | BackendError::SmtpProtocolError(_)
+                | BackendError::InvalidConfiguration(_)
+                | BackendError::MessageTooLarge { .. }) => report!("{e}").into_dynamic(),
+                e @ BackendError::Timeout(_) => report!("{e}")
+                    .attach(format!(
+                        "Time limit set by SENDMAIL_TIMEOUT_SECS: {}s",
+                        cli_args.timeout_secs.unwrap_or_default()
+                    ))
+                    .into_dynamic(),
+                BackendError::RateLimited {
+                    report,
+                    retry_after,
+                } => {
+                    match retry_after {
+                        Some(retry_after) => error!(
+                            "Rate limited by the backend, retry after {}s",
+                            retry_after.as_secs()
+                        ),
+                        None => error!("Rate limited by the backend"),
+                    }
+                    report
+                }
+                BackendError::PartialDelivery { accepted, rejected } => {
+                    for address in &accepted {
+                        info!("Delivered to {address}");
+                    }
+                    let mut report = report!(
+                        "Message was not delivered to {} of {} recipient(s)",
+                        rejected.len(),
+                        rejected.len() + accepted.len()
+                    );
+                    for (address, response) in &rejected {
+                        error!("Rejected recipient {address}: {response}");
+                        report = report.attach(format!("Rejected: {address}: {response}"));
+                    }
+                    report.into_dynamic()
+                }
+            };
+            Err(SendmailError { report, exit_code })
+        }
+    }
 }
 
-pub fn run_sendmail(
+/// Split the time limit of `--timeout` into the time for reading stdin and for sending.
+///
+/// Reading gets 10% but at least 5 seconds, and never more than `total`. Sending gets 90%.
+fn timeout_budget(total: Duration) -> (Duration, Duration) {
+    let read = (total / 10).max(total.min(Duration::from_secs(5)));
+    let send = total * 9 / 10;
+    (read, send)
+}
+
+/// Send a message on another thread and give up waiting for it after `timeout`.
+///
+/// The thread cannot be stopped, so a hanging backend keeps running in the background until the
+/// process exits.
+fn send_with_timeout(
+    backend: Arc<dyn EmailBackend>,
+    envelope_from: Option<Address>,
+    recipients: Vec<Address>,
+    head: String,
+    body: Vec<u8>,
+    timeout: Duration,
+) -> Result<SendReceipt, BackendError> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let recipients: Vec<&Address> = recipients.iter().collect();
+        let result = backend.send_stream(
+            envelope_from.as_ref(),
+            &recipients,
+            &head,
+            &mut body.as_slice(),
+        );
+        // Nobody is listening anymore if the send timed out
+        let _ = sender.send(result);
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            warn!(
+                "Sending did not finish within {}ms, giving up",
+                timeout.as_millis()
+            );
+            Err(BackendError::Timeout(timeout))
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(report!("Sending stopped without a result").into())
+        }
+    }
+}
+
+/// Reader that reads another reader on a worker thread and fails with
+/// [`std::io::ErrorKind::TimedOut`] once `deadline` has passed.
+///
+/// A read that blocks, e.g. on a stdin that is left open but never written to, only blocks the
+/// worker. The worker cannot be stopped, so it keeps waiting in the background until the
+/// process exits.
+struct DeadlineReader {
+    chunks: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+    deadline: Instant,
+}
+
+impl DeadlineReader {
+    /// Start reading `inner` on a worker thread.
+    fn spawn(mut inner: impl Read + Send + 'static, deadline: Instant) -> Self {
+        // One chunk in flight is enough to keep the worker busy without buffering the message
+        let (sender, chunks) = mpsc::sync_channel(1);
+        std::thread::spawn(move || {
+            let mut buffer = vec![0; READ_CHUNK_SIZE];
+            loop {
+                let chunk = match inner.read(&mut buffer) {
+                    // Dropping the sender tells the reader that the input ended
+                    Ok(0) => return,
+                    Ok(read) => Ok(buffer[..read].to_vec()),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                // Nobody is listening anymore if reading timed out
+                if sender.send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Self {
+            chunks,
+            chunk: Vec::new(),
+            position: 0,
+            deadline,
+        }
+    }
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let timed_out = || {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Timed out reading the message from stdin",
+            )
+        };
+        // A producer that trickles the message in is stopped as well
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(timed_out());
+        }
+        if self.position == self.chunk.len() {
+            match self.chunks.recv_timeout(remaining) {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => return Err(timed_out()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Send every message of a batch read from `stdin` on its own.
+///
+/// The messages are read one at a time, so only the message being sent is buffered. A failing
+/// message does not stop the others. The batch fails if any message failed, with
+/// [`EX_TEMPFAIL`] if all failures were temporary.
+fn send_batch(
     stdin: &mut dyn Read,
     stdout: &mut dyn Write,
     stderr: &mut dyn Write,
+    cli_args: &SendmailArgs,
+    backend: &Arc<dyn EmailBackend>,
+) -> Result<(), SendmailError> {
+    let mut batch = BatchReader::new(
+        BufReader::new(stdin),
+        cli_args.batch_delimiter.as_bytes(),
+        cli_args.max_message_size,
+    );
+
+    let mut count = 0;
+    let mut failed = 0;
+    let mut all_temporary = true;
+    while let Some(message) = batch.next_message()? {
+        count += 1;
+        let result = message.and_then(|message| {
+            send_message(
+                &mut std::io::Cursor::new(message),
+                stdout,
+                cli_args,
+                backend,
+            )
+        });
+        match result {
+            Ok(()) => info!("Message {count} of the batch sent"),
+            Err(e) => {
+                error!("Message {count} of the batch failed: {e}");
+                let reason = e.to_string();
+                writeln!(
+                    stderr,
+                    "Message {count} failed: {}",
+                    reason.lines().next().unwrap_or_default()
+                )?;
+                failed += 1;
+                all_temporary &= e.exit_code() == EX_TEMPFAIL;
+            }
+        }
+    }
+
+    if count == 0 {
+        return Err(report!("No messages in the batch").into());
+    }
+    if failed == 0 {
+        info!("Sent a batch of {count} message(s)");
+        return Ok(());
+    }
+    Err(SendmailError {
+        report: report!("{failed} of {count} message(s) failed").into_dynamic(),
+        exit_code: if all_temporary {
+            EX_TEMPFAIL
+        } else {
+            EX_FAILURE
+        },
+    })
+}
+
+/// Splits the input of a batch into messages, reading one message at a time.
+///
+/// A line starting with `delimiter` (an mbox `From ` line by default) starts a new message and
+/// is not part of it. Text before the first delimiter line is a message of its own, unless it
+/// is blank. The empty line that separates mbox messages is removed.
+struct BatchReader<R> {
+    input: R,
+    delimiter: Vec<u8>,
+    /// Size limit of a message, larger messages are skipped instead of buffered
+    limit: Option<u64>,
+    /// Whether the end of the input was reached
+    finished: bool,
+}
+
+impl<R: BufRead> BatchReader<R> {
+    fn new(input: R, delimiter: &[u8], limit: Option<u64>) -> Self {
+        Self {
+            input,
+            delimiter: delimiter.to_vec(),
+            limit,
+            finished: false,
+        }
+    }
+
+    /// Read the next message that is not blank, or `None` at the end of the input.
+    ///
+    /// A message larger than the limit gives the error of [`message_too_large`], and the batch
+    /// continues with the message after it.
+    fn next_message(&mut self) -> std::io::Result<Option<Result<Vec<u8>, SendmailError>>> {
+        while !self.finished {
+            let mut message = Vec::new();
+            let mut too_large = false;
+            loop {
+                let start = message.len();
+                // Enough of the line to tell a delimiter line, and of a message one byte more
+                // than the limit allows
+                let max = match self.limit {
+                    _ if too_large => self.delimiter.len(),
+                    Some(limit) => usize::try_from(limit)
+                        .unwrap_or(usize::MAX)
+                        .saturating_sub(start)
+                        .saturating_add(1)
+                        .max(self.delimiter.len()),
+                    None => usize::MAX,
+                };
+                if !read_line_start(&mut self.input, &mut message, max)? {
+                    self.finished = true;
+                    break;
+                }
+                if message[start..].starts_with(&self.delimiter) {
+                    message.truncate(start);
+                    break;
+                }
+                if too_large {
+                    message.clear();
+                } else if self.limit.is_some_and(|limit| message.len() as u64 > limit) {
+                    too_large = true;
+                    message.clear();
+                }
+            }
+
+            if let Some(limit) = self.limit.filter(|_| too_large) {
+                return Ok(Some(Err(message_too_large(limit))));
+            }
+            if message.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            if message.ends_with(b"\r\n\r\n") {
+                message.truncate(message.len() - 2);
+            } else if message.ends_with(b"\n\n") {
+                message.truncate(message.len() - 1);
+            }
+            return Ok(Some(Ok(message)));
+        }
+        Ok(None)
+    }
+}
+
+/// Append the next line of `input` to `buffer`, but at most `max` bytes of it; the rest of a
+/// longer line is skipped.
+///
+/// Returns `false` at the end of the input.
+fn read_line_start(
+    input: &mut impl BufRead,
+    buffer: &mut Vec<u8>,
+    max: usize,
+) -> std::io::Result<bool> {
+    let mut kept = 0;
+    let mut read_any = false;
+    loop {
+        let available = match input.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if available.is_empty() {
+            return Ok(read_any);
+        }
+        read_any = true;
+        let (len, end_of_line) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+        let keep = len.min(max - kept);
+        buffer.extend_from_slice(&available[..keep]);
+        kept += keep;
+        input.consume(len);
+        if end_of_line {
+            return Ok(true);
+        }
+    }
+}
+
+pub fn run_sendmail(
+    stdin: impl Read + Send + 'static,
+    stdout: &mut dyn Write,
+    stderr: &mut dyn Write,
     args: &[String],
     envs: &[(String, String)],
 ) -> i32 {
-    let cli_args = match parse_cli_args(args, envs) {
+    let cli_args = match phase!("parse_args", { parse_cli_args(args, envs) }) {
         Ok(args) => args,
         Err(e) => {
             write!(stderr, "{e}").unwrap();
-            return 1;
+            return EX_FAILURE;
         }
     };
 
@@ -98,7 +857,7 @@ pub fn run_sendmail(
     hook.report_header = "";
     hook.report_node_standalone_formatting =
         NodeConfig::new(("", "\n"), ("", "\n"), ("", "\n"), ("", "\n"), "  ");
-    let hooks = if cli_args.verbosity == 0 {
+    let hooks = if cli_args.log_verbosity() == 0 {
         Hooks::new_without_locations()
     } else {
         Hooks::new()
@@ -107,41 +866,217 @@ pub fn run_sendmail(
 
     match run_sendmail_err(stdin, stdout, stderr, &cli_args) {
         Ok(()) => 0,
-        Err(mut e) => {
-            if cli_args.verbosity == 0 {
+        Err(e) => {
+            let exit_code = e.exit_code();
+            let mut e = e.into_report();
+            if cli_args.log_verbosity() == 0 {
                 let attachments = e.attachments_mut();
                 while !attachments.is_empty() {
                     attachments.pop();
                 }
             }
             write!(stderr, "{e}").unwrap();
-            1
+            exit_code
         }
     }
 }
 
+/// Create the backend selected by `config`, failing with [`EX_USAGE`] if a setting is invalid.
+fn create_backend(config: &BackendConfig) -> Result<Box<dyn EmailBackend>, SendmailError> {
+    // The settings are validated by create_from_config
+    backend::create_from_config(config).map_err(|e| {
+        let exit_code = i32::from(u8::from(&e));
+        let report = match e {
+            BackendError::Failed(report) => report,
+            BackendError::InvalidConfiguration(detail) => {
+                report!("sendmail: configuration error: {detail}").into_dynamic()
+            }
+            e => report!("{e}").into_dynamic(),
+        };
+        SendmailError { report, exit_code }
+    })
+}
+
+/// Check the backend selected by `config` without sending a message and print a report to
+/// `stdout`.
+///
+/// Fails if the check fails, with the exit code the error maps to.
+fn verify_backend(stdout: &mut dyn Write, config: &BackendConfig) -> Result<(), SendmailError> {
+    let backend = create_backend(config)?;
+    writeln!(stdout, "backend: {}", backend.describe())?;
+    match backend.verify() {
+        Ok(summary) => {
+            writeln!(stdout, "status: ok ({summary})")?;
+            Ok(())
+        }
+        Err(e) => {
+            // The full error goes to stderr like that of any other failure
+            let message = e.to_string();
+            writeln!(
+                stdout,
+                "status: failed ({})",
+                message.lines().next().unwrap_or_default()
+            )?;
+            let exit_code = i32::from(u8::from(&e));
+            let report = match e {
+                BackendError::RateLimited { report, .. }
+                | BackendError::Failed(report)
+                | BackendError::Unavailable(report)
+                | BackendError::Unauthorized(report) => report,
+                BackendError::PartialDelivery { .. }
+                | BackendError::SmtpAuthFailed(_)
+                | BackendError::SmtpProtocolError(_)
+                | BackendError::Timeout(_)
+                | BackendError::InvalidConfiguration(_)
+                | BackendError::MessageTooLarge { .. } => report!("{message}").into_dynamic(),
+            };
+            Err(SendmailError { report, exit_code })
+        }
+    }
+}
+
+/// Size of the chunks read from stdin while looking for the end of the header section
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Read from `stdin` until the end of the header section.
+///
+/// Returns the header section including the empty line that terminates it, and any bytes that
+/// were read past it. If the message has no empty line, the whole message is returned as the
+/// header section.
+///
+/// Fails with [`EX_DATAERR`] as soon as more than `limit` bytes were read, before the header
+/// section is checked for UTF-8.
+fn read_header_section(
+    stdin: &mut dyn Read,
+    limit: Option<u64>,
+) -> Result<(String, Vec<u8>), SendmailError> {
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0; READ_CHUNK_SIZE];
+    let mut scanned = 0;
+
+    let head_len = loop {
+        let read = match stdin.read(&mut chunk) {
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if read == 0 {
+            break buffer.len();
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(limit) = limit
+            && buffer.len() as u64 > limit
+        {
+            return Err(message_too_large(limit));
+        }
+
+        if let Some(end) = find_header_end(&buffer, scanned) {
+            break end;
+        }
+        // Keep a few bytes so separators split across chunks are still found
+        scanned = buffer.len().saturating_sub(3);
+    };
+
+    let body_start = buffer.split_off(head_len);
+    let head = String::from_utf8(buffer)
+        .map_err(|e| report!("Message headers are not valid UTF-8: {e}"))?;
+    Ok((head, body_start))
+}
+
+/// Split a leading mbox `From ` line (`From <sender> <date>`) off the header section `head`.
+///
+/// Returns the sender of the line and the header section after it, or `None` if `head` does
+/// not start with such a line. The sender is not necessarily an address, mbox files also use
+/// e.g. `MAILER-DAEMON`.
+fn split_mbox_from_line(head: &str) -> Option<(&str, &str)> {
+    let line_end = head.find('\n').map_or(head.len(), |index| index + 1);
+    let (line, rest) = head.split_at(line_end);
+    let (sender, _) = line.strip_prefix("From ")?.split_once(' ')?;
+    if sender.is_empty() || sender.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((sender, rest))
+}
+
+/// Read the rest of the message after the header section `head`.
+///
+/// Fails with [`EX_DATAERR`] as soon as the whole message is larger than `limit` bytes.
+fn read_body_with_limit(
+    head: &str,
+    mut body: Vec<u8>,
+    stdin: &mut dyn Read,
+    limit: u64,
+) -> Result<Vec<u8>, SendmailError> {
+    let read = (head.len() + body.len()) as u64;
+    if read <= limit {
+        // One byte more than allowed is enough to notice that the message is too large
+        stdin.take(limit - read + 1).read_to_end(&mut body)?;
+    }
+
+    let size = (head.len() + body.len()) as u64;
+    if size > limit {
+        return Err(message_too_large(limit));
+    }
+    Ok(body)
+}
+
+/// The error for a message larger than SENDMAIL_MAX_MESSAGE_SIZE
+fn message_too_large(limit: u64) -> SendmailError {
+    SendmailError {
+        report: report!("Message is too large: the limit is {limit} bytes")
+            .attach("Limit set by SENDMAIL_MAX_MESSAGE_SIZE")
+            .into_dynamic(),
+        exit_code: EX_DATAERR,
+    }
+}
+
+/// Find the end of the header section (after the first empty line) starting at byte `from`.
+fn find_header_end(buffer: &[u8], from: usize) -> Option<usize> {
+    // A message can start with the empty line if it has no headers at all
+    if from == 0 {
+        if buffer.starts_with(b"\n") {
+            return Some(1);
+        }
+        if buffer.starts_with(b"\r\n") {
+            return Some(2);
+        }
+    }
+    (from..buffer.len()).find_map(|i| {
+        let rest = &buffer[i..];
+        if rest.starts_with(b"\n\n") {
+            Some(i + 2)
+        } else if rest.starts_with(b"\n\r\n") {
+            Some(i + 3)
+        } else {
+            None
+        }
+    })
+}
+
 /// Generate missing required headers (From:, Date:, Message-ID:) based on existing headers.
-/// Returns a vector of header strings to add.
+/// Returns a vector of header strings to add. The date is given in the time zone
+/// `date_timezone` minutes east of UTC.
 fn generate_missing_headers(
     headers: &[parser::HeaderField],
     from: &Address,
     fullname: Option<&str>,
+    date_timezone: i32,
 ) -> Vec<String> {
     let mut headers_to_add = Vec::new();
 
     if !parser::has_header(headers, "From") {
         let from_header = match fullname {
-            Some(name) => {
-                let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
-                format!("From: \"{escaped}\" <{from}>")
-            }
+            Some(name) => format!("From: {}", format_named_address(name, from)),
             None => format!("From: {from}"),
         };
         headers_to_add.push(from_header);
     }
 
     if !parser::has_header(headers, "Date") {
-        headers_to_add.push(format!("Date: {}", format_rfc5322_date()));
+        headers_to_add.push(format!(
+            "Date: {}",
+            format_rfc5322_date(SystemTime::now(), date_timezone)
+        ));
     }
 
     if !parser::has_header(headers, "Message-ID") {
@@ -151,6 +1086,169 @@ fn generate_missing_headers(
     headers_to_add
 }
 
+/// Format an address with a display name as `"name" <address>`, escaping the name.
+fn format_named_address(name: &str, address: &Address) -> String {
+    let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{escaped}\" <{address}>")
+}
+
+/// Put `fullname` as display name on the address in the From header of the message.
+///
+/// A From header with several addresses is left alone, as is a display name it already has
+/// unless `update` is [`DisplayNameUpdate::Replace`].
+fn set_from_display_name(raw_email: &str, fullname: &str, update: DisplayNameUpdate) -> String {
+    parser::replace_header_values(raw_email, "From", |value| {
+        let mailboxes: Mailboxes = value.parse().ok()?;
+        let mut mailboxes = mailboxes.into_iter();
+        let (Some(mailbox), None) = (mailboxes.next(), mailboxes.next()) else {
+            debug!("Not setting the display name of a From header without exactly one address");
+            return None;
+        };
+        if mailbox.name.is_some() && update == DisplayNameUpdate::Keep {
+            debug!("Keeping the display name of the From header");
+            return None;
+        }
+        Some(format_named_address(fullname, &mailbox.email))
+    })
+}
+
+/// Format a subject given on the command line as a header value, encoding non-ASCII text as
+/// RFC 2047 encoded words.
+fn encode_subject(subject: &str) -> String {
+    // Line breaks would end the header field
+    let subject = subject.split_whitespace().collect::<Vec<_>>().join(" ");
+    if subject.is_ascii() {
+        subject
+    } else {
+        encoding::encode_rfc2047_q(&subject, "\r\n")
+    }
+}
+
+/// Replace the domain of an address, keeping the local part.
+fn masquerade_address(address: &Address, domain: &str) -> Result<Address, Report> {
+    Address::new(address.user(), domain).map_err(|e| {
+        report!("Failed to masquerade address: {e}")
+            .attach(format!("Address: {address}"))
+            .attach(format!("Domain: {domain}"))
+    })
+}
+
+/// Replace the domain of all addresses in the From: header, keeping display names and local
+/// parts. Headers that cannot be parsed are left unchanged.
+fn masquerade_from_header(raw_email: &str, domain: &str) -> String {
+    parser::replace_header_values(raw_email, "From", |value| {
+        let mailboxes: Mailboxes = value.parse().ok()?;
+        let masqueraded = mailboxes
+            .into_iter()
+            .map(|mailbox| {
+                let email = masquerade_address(&mailbox.email, domain).ok()?;
+                Some(Mailbox::new(mailbox.name, email))
+            })
+            .collect::<Option<Mailboxes>>()?;
+        Some(masqueraded.to_string())
+    })
+}
+
+/// Format addresses as a comma-separated list.
+fn format_address_list(addresses: &[Address]) -> String {
+    addresses
+        .iter()
+        .map(Address::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Header fields that may occur at most once in a message (RFC 5322 section 3.6)
+const SINGLETON_HEADERS: &[&str] = &[
+    "Date",
+    "From",
+    "Sender",
+    "Reply-To",
+    "To",
+    "Cc",
+    "Bcc",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "Subject",
+];
+
+/// Warn about header fields that occur more than once although RFC 5322 allows only one.
+///
+/// In strict mode duplicates are an error.
+fn check_singleton_headers(headers: &[parser::HeaderField], strict: bool) -> Result<(), Report> {
+    let duplicates: Vec<String> = SINGLETON_HEADERS
+        .iter()
+        .filter(|name| parser::has_multiple(headers, name))
+        .map(|name| format!("{name} ({} times)", parser::count_header(headers, name)))
+        .collect();
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+
+    let duplicates = duplicates.join(", ");
+    if strict {
+        return Err(
+            report!("Message contains duplicate header fields that may occur only once")
+                .attach(format!("Duplicates: {duplicates}")),
+        );
+    }
+    warn!("Message contains duplicate header fields that may occur only once: {duplicates}");
+    Ok(())
+}
+
+/// Generate the fields RFC 5322 expects in every resend block that `resent` is missing:
+/// `Resent-From`, `Resent-Date` and `Resent-Message-ID`.
+fn generate_missing_resent_headers(
+    resent: &[parser::HeaderField],
+    from: &Address,
+    date_timezone: i32,
+) -> Vec<String> {
+    let mut headers_to_add = Vec::new();
+
+    if !parser::has_header(resent, "Resent-From") {
+        headers_to_add.push(format!("Resent-From: {from}"));
+    }
+
+    if !parser::has_header(resent, "Resent-Date") {
+        headers_to_add.push(format!(
+            "Resent-Date: {}",
+            format_rfc5322_date(SystemTime::now(), date_timezone)
+        ));
+    }
+
+    if !parser::has_header(resent, "Resent-Message-ID") {
+        headers_to_add.push(format!("Resent-Message-ID: {}", generate_message_id(from)));
+    }
+
+    headers_to_add
+}
+
+/// Insert `headers` right before the first `Resent-*` field, so they join the most recent
+/// resend block. Without such a field they are prepended.
+fn insert_resent_headers(raw_email: &str, headers: &[String]) -> String {
+    if headers.is_empty() {
+        return raw_email.to_string();
+    }
+
+    let mut offset = 0;
+    for line in raw_email.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            break;
+        }
+        let is_resent = !line.starts_with([' ', '\t'])
+            && line
+                .split_once(':')
+                .is_some_and(|(name, _)| parser::is_resent_field(name.trim()));
+        if is_resent {
+            let (before, after) = raw_email.split_at(offset);
+            return format!("{before}{}\r\n{after}", headers.join("\r\n"));
+        }
+        offset += line.len();
+    }
+    prepend_headers(raw_email, headers)
+}
+
 /// Prepend headers to the raw email content.
 /// Headers are inserted at the top of the email (before other headers).
 fn prepend_headers(raw_email: &str, headers: &[String]) -> String {
@@ -161,21 +1259,96 @@ fn prepend_headers(raw_email: &str, headers: &[String]) -> String {
     }
 }
 
-/// Format current date/time in RFC 5322 format using lettre's Date API.
-fn format_rfc5322_date() -> String {
-    use lettre::message::{Mailbox, MessageBuilder};
-    let dummy: Mailbox = "nobody@localhost".parse().unwrap();
-    let message = MessageBuilder::new()
-        .from(dummy.clone())
-        .to(dummy)
-        .date_now()
-        .body(String::new())
-        .unwrap();
-    String::from_utf8_lossy(&message.formatted())
-        .lines()
-        .find_map(|line| line.strip_prefix("Date: "))
-        .map(|s| s.trim().to_string())
-        .expect("Date header not found in formatted message")
+/// Rewrite every `\n` that is not preceded by `\r` to `\r\n`.
+///
+/// Existing CRLF line endings are kept as they are.
+fn normalize_to_crlf(s: &str) -> String {
+    let mut normalized = String::with_capacity(s.len());
+    let mut after_cr = false;
+    for c in s.chars() {
+        if c == '\n' && !after_cr {
+            normalized.push('\r');
+        }
+        after_cr = c == '\r';
+        normalized.push(c);
+    }
+    normalized
+}
+
+/// Reader that applies [`normalize_to_crlf`] to the bytes of another reader while streaming
+struct CrlfReader<R> {
+    inner: R,
+    /// The last byte read from `inner` was `\r`, even if it ended the previous chunk
+    after_cr: bool,
+    chunk: Vec<u8>,
+    /// Normalized bytes of the last chunk, handed out from `position`
+    normalized: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> CrlfReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            after_cr: false,
+            chunk: vec![0; READ_CHUNK_SIZE],
+            normalized: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for CrlfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.normalized.len() {
+            let read = self.inner.read(&mut self.chunk)?;
+            self.normalized.clear();
+            self.position = 0;
+            for &byte in &self.chunk[..read] {
+                if byte == b'\n' && !self.after_cr {
+                    self.normalized.push(b'\r');
+                }
+                self.after_cr = byte == b'\r';
+                self.normalized.push(byte);
+            }
+        }
+        let remaining = &self.normalized[self.position..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Format `time` as an RFC 5322 date in the time zone `offset` minutes east of UTC, e.g.
+/// `Mon, 01 Jan 2024 14:00:00 +0200`.
+fn format_rfc5322_date(time: SystemTime, offset: i32) -> String {
+    // The epoch was a Thursday
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let seconds = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs() as i64
+        + i64::from(offset) * 60;
+    let (days, seconds) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+    let (year, month, day) = date::civil_from_days(days);
+
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.unsigned_abs();
+    format!(
+        "{}, {day:02} {} {year:04} {:02}:{:02}:{:02} {sign}{:02}{:02}",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        MONTHS[month as usize - 1],
+        seconds / 3_600,
+        seconds % 3_600 / 60,
+        seconds % 60,
+        offset / 60,
+        offset % 60
+    )
 }
 
 /// Generate a unique Message-ID header value using UUID format: <UUID@domain>
@@ -189,10 +1362,20 @@ fn generate_message_id(from: &Address) -> String {
 mod tests {
     use lettre::Address;
 
-    use super::{generate_missing_headers, prepend_headers};
-    use crate::backend::{EmailBackend, FileBackend};
+    use super::{
+        BatchReader, CrlfReader, DeadlineReader, EX_DATAERR, EX_FAILURE, EX_TEMPFAIL, EX_USAGE,
+        find_header_end, format_rfc5322_date, generate_missing_headers,
+        generate_missing_resent_headers, insert_resent_headers, normalize_to_crlf, prepend_headers,
+        read_header_section, send_with_timeout, set_from_display_name, split_mbox_from_line,
+        timeout_budget,
+    };
+    use crate::args::DisplayNameUpdate;
+    use crate::backend::{BackendError, EmailBackend, FileBackend};
     use crate::parser::parse_email_headers;
+    use std::io::Read;
     use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
 
     #[test]
     fn test_file_backend() {
@@ -202,16 +1385,225 @@ mod tests {
             "From: sender@example.com\nTo: recipient@example.com\nSubject: Test\n\nTest body";
         let from = Address::from_str("sender@example.com").unwrap();
         let to = Address::from_str("recipient@example.com").unwrap();
-        assert!(backend.send(&from, &[&to], raw_email).is_ok());
+        assert!(backend.send(Some(&from), &[&to], raw_email).is_ok());
         let _ = std::fs::remove_file(&temp_file);
     }
 
+    #[test]
+    fn test_find_header_end() {
+        assert_eq!(find_header_end(b"Subject: Test\n\nBody", 0), Some(15));
+        assert_eq!(find_header_end(b"Subject: Test\r\n\r\nBody", 0), Some(17));
+        assert_eq!(find_header_end(b"\r\nBody", 0), Some(2));
+        assert_eq!(find_header_end(b"Subject: Test\nBody", 0), None);
+    }
+
+    #[test]
+    fn test_exit_code_of_backend_error() {
+        let exit_code = |error: BackendError| i32::from(u8::from(&error));
+        assert_eq!(
+            exit_code(BackendError::Failed(
+                rootcause::report!("rejected").into_dynamic()
+            )),
+            EX_FAILURE
+        );
+        assert_eq!(
+            exit_code(BackendError::SmtpProtocolError("554 go away".to_string())),
+            EX_FAILURE
+        );
+        assert_eq!(
+            exit_code(BackendError::Timeout(Duration::from_secs(1))),
+            EX_TEMPFAIL
+        );
+        assert_eq!(
+            exit_code(BackendError::RateLimited {
+                report: rootcause::report!("429").into_dynamic(),
+                retry_after: None,
+            }),
+            EX_TEMPFAIL
+        );
+        assert_eq!(
+            exit_code(BackendError::SmtpAuthFailed("535".to_string())),
+            EX_USAGE
+        );
+        assert_eq!(
+            exit_code(BackendError::Unavailable(
+                rootcause::report!("503").into_dynamic()
+            )),
+            EX_TEMPFAIL
+        );
+        assert_eq!(
+            exit_code(BackendError::Unauthorized(
+                rootcause::report!("401").into_dynamic()
+            )),
+            EX_USAGE
+        );
+        assert_eq!(
+            exit_code(BackendError::InvalidConfiguration(
+                "SENDMAIL_API_TOKEN must be set".to_string()
+            )),
+            EX_USAGE
+        );
+        assert_eq!(
+            exit_code(BackendError::MessageTooLarge {
+                size: 2,
+                limit: Some(1),
+                detail: None,
+            }),
+            EX_DATAERR
+        );
+        assert_eq!(
+            exit_code(BackendError::MessageTooLarge {
+                size: 2,
+                limit: None,
+                detail: Some("Message exceeds 1B limit".to_string()),
+            }),
+            EX_DATAERR
+        );
+    }
+
+    #[test]
+    fn test_split_mbox_from_line() {
+        let head = "From sender@example.com Thu Jan  1 00:00:00 2026\nSubject: Test\n\n";
+        assert_eq!(
+            split_mbox_from_line(head),
+            Some(("sender@example.com", "Subject: Test\n\n"))
+        );
+        // Without the option the line would be taken for a header field with a broken name
+        assert_eq!(
+            parse_email_headers(head)[0].name,
+            "From sender@example.com Thu Jan  1 00"
+        );
+
+        assert_eq!(split_mbox_from_line("From: sender@example.com\n\n"), None);
+        assert_eq!(split_mbox_from_line("From  sender@example.com\n\n"), None);
+        assert_eq!(split_mbox_from_line("Subject: From a b\n\n"), None);
+    }
+
+    #[test]
+    fn test_read_header_section_across_chunks() {
+        let header = format!("X-Long: {}\r\n", "a".repeat(super::READ_CHUNK_SIZE - 9));
+        let email = format!("{header}\r\nBody");
+        let mut stdin = std::io::Cursor::new(email.into_bytes());
+
+        let (head, body_start) = read_header_section(&mut stdin, None).unwrap();
+        assert_eq!(head, format!("{header}\r\n"));
+        assert_eq!(body_start, b"Body");
+    }
+
+    #[test]
+    fn test_read_header_section_without_body() {
+        let mut stdin = std::io::Cursor::new(b"Subject: Test\nBody content".to_vec());
+        let (head, body_start) = read_header_section(&mut stdin, None).unwrap();
+        assert_eq!(head, "Subject: Test\nBody content");
+        assert!(body_start.is_empty());
+    }
+
+    #[test]
+    fn test_read_header_section_stops_at_limit() {
+        let header = format!("X-Long: {}\r\n", "a".repeat(super::READ_CHUNK_SIZE));
+        let mut stdin = std::io::Cursor::new(header.repeat(4).into_bytes());
+
+        let Err(error) = read_header_section(&mut stdin, Some(1000)) else {
+            panic!("header section above the limit should be rejected");
+        };
+        assert_eq!(error.exit_code, super::EX_DATAERR);
+        // Only the first chunk was read
+        assert_eq!(stdin.position(), super::READ_CHUNK_SIZE as u64);
+    }
+
+    #[test]
+    fn test_format_rfc5322_date() {
+        use lettre::message::header::{Date, Header};
+
+        // 2024-01-01 12:00:00 UTC
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_110_400);
+        assert_eq!(
+            format_rfc5322_date(time, 0),
+            "Mon, 01 Jan 2024 12:00:00 +0000"
+        );
+        assert_eq!(
+            format_rfc5322_date(time, 120),
+            "Mon, 01 Jan 2024 14:00:00 +0200"
+        );
+        assert_eq!(
+            format_rfc5322_date(time, -750),
+            "Sun, 31 Dec 2023 23:30:00 -1230"
+        );
+
+        // Read the local time back as if it were UTC, then undo the offset
+        for offset in [0, 120, -300, 330, -750, 840] {
+            let date = format_rfc5322_date(time, offset);
+            let sign = if offset < 0 { '-' } else { '+' };
+            let suffix = format!(
+                " {sign}{:02}{:02}",
+                offset.unsigned_abs() / 60,
+                offset.unsigned_abs() % 60
+            );
+            let local = date.strip_suffix(&suffix).unwrap();
+            let local = SystemTime::from(Date::parse(&format!("{local} +0000")).unwrap());
+            let offset = Duration::from_secs(u64::from(offset.unsigned_abs()) * 60);
+            let parsed = if sign == '-' {
+                local + offset
+            } else {
+                local - offset
+            };
+            assert_eq!(parsed, time, "{date}");
+        }
+    }
+
+    #[test]
+    fn test_format_rfc5322_date_matches_lettre() {
+        use lettre::message::header::{Date, Headers};
+
+        // The example date of RFC 7231
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(
+            format_rfc5322_date(time, 0),
+            "Sun, 06 Nov 1994 08:49:37 +0000"
+        );
+
+        // Generated dates used to be taken from a message built with lettre, and must not change
+        for seconds in [
+            0,
+            784_111_777,
+            951_782_400,   // 2000-02-29, a leap day in a year divisible by 400
+            1_709_164_799, // 2024-02-28 23:59:59
+            1_709_251_200, // 2024-03-01
+            1_735_689_599, // 2024-12-31 23:59:59
+            4_107_542_400, // 2100-03-01, after the 28th of February of a year without leap day
+        ] {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+            let mut headers = Headers::new();
+            headers.set(Date::new(time));
+            assert_eq!(
+                Some(format_rfc5322_date(time, 0).as_str()),
+                headers.get_raw("Date"),
+                "{seconds}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_missing_headers_in_timezone() {
+        let headers = parse_email_headers("Subject: Test\n\nBody");
+        let from = Address::from_str("sender@example.com").unwrap();
+        let missing = generate_missing_headers(&headers, &from, None, -300);
+        let date = missing.iter().find(|header| header.starts_with("Date: "));
+        assert!(date.unwrap().ends_with(" -0500"), "{missing:?}");
+
+        let missing = generate_missing_resent_headers(&headers, &from, 120);
+        let date = missing
+            .iter()
+            .find(|header| header.starts_with("Resent-Date: "));
+        assert!(date.unwrap().ends_with(" +0200"), "{missing:?}");
+    }
+
     #[test]
     fn test_add_missing_headers_all_missing() {
         let raw_email = "Subject: Test\n\nBody content";
         let headers = parse_email_headers(raw_email);
         let from = Address::from_str("sender@example.com").unwrap();
-        let missing = generate_missing_headers(&headers, &from, None);
+        let missing = generate_missing_headers(&headers, &from, None, 0);
         let result = prepend_headers(raw_email, &missing);
 
         assert!(result.contains("From: sender@example.com"));
@@ -226,7 +1618,7 @@ mod tests {
         let raw_email = "From: existing@example.com\nSubject: Test\n\nBody";
         let headers = parse_email_headers(raw_email);
         let from = Address::from_str("sender@example.com").unwrap();
-        let missing = generate_missing_headers(&headers, &from, None);
+        let missing = generate_missing_headers(&headers, &from, None, 0);
         let result: String = prepend_headers(raw_email, &missing);
 
         // Should not add From header since it exists
@@ -236,12 +1628,30 @@ mod tests {
         assert!(result.contains("Message-ID:"));
     }
 
+    #[test]
+    fn test_add_missing_resent_headers_joins_resent_block() {
+        let raw_email = "Received: from relay\nResent-From: resender@example.com\nResent-To: new@example.com\nFrom: author@example.com\n\nBody";
+        let headers = parse_email_headers(raw_email);
+        let from = Address::from_str("resender@example.com").unwrap();
+        let missing = generate_missing_resent_headers(&headers, &from, 0);
+        assert_eq!(missing.len(), 2);
+        assert!(missing[0].starts_with("Resent-Date: "));
+        assert!(missing[1].starts_with("Resent-Message-ID: <"));
+
+        let result = insert_resent_headers(raw_email, &missing);
+        let lines: Vec<&str> = result.lines().collect();
+        assert_eq!(lines[0], "Received: from relay");
+        assert!(lines[1].starts_with("Resent-Date: "));
+        assert!(lines[2].starts_with("Resent-Message-ID: "));
+        assert_eq!(lines[3], "Resent-From: resender@example.com");
+    }
+
     #[test]
     fn test_add_missing_headers_date_exists() {
         let raw_email = "Date: Mon, 1 Jan 2024 12:00:00 +0000\nSubject: Test\n\nBody";
         let headers = parse_email_headers(raw_email);
         let from = Address::from_str("sender@example.com").unwrap();
-        let missing = generate_missing_headers(&headers, &from, None);
+        let missing = generate_missing_headers(&headers, &from, None, 0);
         let result = prepend_headers(raw_email, &missing);
 
         assert!(result.contains("From: sender@example.com"));
@@ -256,7 +1666,7 @@ mod tests {
         let raw_email = "Message-ID: <test@example.com>\nSubject: Test\n\nBody";
         let headers = parse_email_headers(raw_email);
         let from = Address::from_str("sender@example.com").unwrap();
-        let missing = generate_missing_headers(&headers, &from, None);
+        let missing = generate_missing_headers(&headers, &from, None, 0);
         let result = prepend_headers(raw_email, &missing);
 
         assert!(result.contains("From: sender@example.com"));
@@ -271,7 +1681,7 @@ mod tests {
         let raw_email = "Subject: Test\nBody content";
         let headers = parse_email_headers(raw_email);
         let from = Address::from_str("sender@example.com").unwrap();
-        let missing = generate_missing_headers(&headers, &from, None);
+        let missing = generate_missing_headers(&headers, &from, None, 0);
         let result = prepend_headers(raw_email, &missing);
 
         assert!(result.contains("From: sender@example.com"));
@@ -284,7 +1694,7 @@ mod tests {
         let raw_email = "Subject: Test\n\nBody content";
         let headers = parse_email_headers(raw_email);
         let from = Address::from_str("sender@example.com").unwrap();
-        let missing = generate_missing_headers(&headers, &from, Some("John Doe"));
+        let missing = generate_missing_headers(&headers, &from, Some("John Doe"), 0);
         let result = prepend_headers(raw_email, &missing);
 
         assert!(result.contains("From: \"John Doe\" <sender@example.com>"));
@@ -297,9 +1707,222 @@ mod tests {
         let raw_email = "Subject: Test\n\nBody content";
         let headers = parse_email_headers(raw_email);
         let from = Address::from_str("sender@example.com").unwrap();
-        let missing = generate_missing_headers(&headers, &from, Some("John \"Johnny\" Doe"));
+        let missing = generate_missing_headers(&headers, &from, Some("John \"Johnny\" Doe"), 0);
         let result = prepend_headers(raw_email, &missing);
 
         assert!(result.contains("From: \"John \\\"Johnny\\\" Doe\" <sender@example.com>"));
     }
+
+    #[test]
+    fn test_set_from_display_name_on_bare_address() {
+        let raw_email = "From: sender@example.com\nSubject: Test\n\nBody";
+        let result = set_from_display_name(raw_email, "John Doe", DisplayNameUpdate::Keep);
+        assert_eq!(
+            result,
+            "From: \"John Doe\" <sender@example.com>\nSubject: Test\n\nBody"
+        );
+    }
+
+    #[test]
+    fn test_set_from_display_name_existing_name() {
+        let raw_email = "From: Someone Else <sender@example.com>\r\n\r\nBody";
+        let kept = set_from_display_name(raw_email, "John Doe", DisplayNameUpdate::Keep);
+        assert_eq!(kept, raw_email);
+
+        let replaced = set_from_display_name(raw_email, "John Doe", DisplayNameUpdate::Replace);
+        assert_eq!(
+            replaced,
+            "From: \"John Doe\" <sender@example.com>\r\n\r\nBody"
+        );
+    }
+
+    #[test]
+    fn test_set_from_display_name_multiple_addresses() {
+        let raw_email = "From: a@example.com, b@example.com\n\nBody";
+        let result = set_from_display_name(raw_email, "John Doe", DisplayNameUpdate::Replace);
+        assert_eq!(result, raw_email);
+    }
+
+    #[test]
+    fn test_normalize_to_crlf() {
+        assert_eq!(normalize_to_crlf("a\nb\n\nc"), "a\r\nb\r\n\r\nc");
+        // Existing CRLF is not doubled and a lone CR is left alone
+        assert_eq!(normalize_to_crlf("a\r\nb\nc\r\n"), "a\r\nb\r\nc\r\n");
+        assert_eq!(normalize_to_crlf("a\rb\r\r\n"), "a\rb\r\r\n");
+        assert_eq!(normalize_to_crlf("\u{e4}\n"), "\u{e4}\r\n");
+    }
+
+    #[test]
+    fn test_crlf_reader_across_chunk_boundaries() {
+        // A reader that returns one byte at a time splits every CRLF
+        struct ByteReader<'a>(&'a [u8]);
+        impl std::io::Read for ByteReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let Some((first, rest)) = self.0.split_first() else {
+                    return Ok(0);
+                };
+                buf[0] = *first;
+                self.0 = rest;
+                Ok(1)
+            }
+        }
+
+        let input = "line 1\r\nline 2\nline 3\r\n\n";
+        let mut output = String::new();
+        CrlfReader::new(ByteReader(input.as_bytes()))
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, normalize_to_crlf(input));
+        assert_eq!(output, "line 1\r\nline 2\r\nline 3\r\n\r\n");
+
+        // Output buffers smaller than a normalized chunk
+        let mut reader = CrlfReader::new(std::io::Cursor::new(b"a\nb\n".to_vec()));
+        let mut output = Vec::new();
+        let mut buf = [0; 1];
+        while reader.read(&mut buf).unwrap() == 1 {
+            output.push(buf[0]);
+        }
+        assert_eq!(output, b"a\r\nb\r\n");
+    }
+
+    /// The messages of a batch, with `None` for those over `limit`
+    fn split_batch(input: &[u8], delimiter: &[u8], limit: Option<u64>) -> Vec<Option<Vec<u8>>> {
+        let mut batch = BatchReader::new(input, delimiter, limit);
+        let mut messages = Vec::new();
+        while let Some(message) = batch.next_message().unwrap() {
+            messages.push(message.ok());
+        }
+        messages
+    }
+
+    #[test]
+    fn test_split_batch() {
+        let input = b"From alice@example.com Thu Jan  1 00:00:00 2026\nSubject: One\n\nBody\n>From here\n\nFrom bob@example.com Thu Jan  1 00:00:01 2026\nFrom: bob@example.com\nSubject: Two\n\nBody\n";
+        let messages = split_batch(input, b"From ", None);
+        assert_eq!(
+            messages,
+            [
+                Some(b"Subject: One\n\nBody\n>From here\n".to_vec()),
+                Some(b"From: bob@example.com\nSubject: Two\n\nBody\n".to_vec()),
+            ]
+        );
+
+        // Text before the first delimiter is a message, blank text is not
+        assert_eq!(
+            split_batch(b"Subject: A\n\nBody\n--\nSubject: B\n", b"--", None),
+            [
+                Some(b"Subject: A\n\nBody\n".to_vec()),
+                Some(b"Subject: B\n".to_vec())
+            ]
+        );
+        assert!(split_batch(b"\nFrom x\n\n", b"From ", None).is_empty());
+    }
+
+    #[test]
+    fn test_split_batch_skips_messages_over_limit() {
+        let large = format!(
+            "From a\nSubject: Large\n\n{}\nFrom b\nSubject: B\n",
+            "x".repeat(1000)
+        );
+        assert_eq!(
+            split_batch(large.as_bytes(), b"From ", Some(100)),
+            [None, Some(b"Subject: B\n".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_timeout_budget() {
+        assert_eq!(
+            timeout_budget(Duration::from_secs(100)),
+            (Duration::from_secs(10), Duration::from_secs(90))
+        );
+        // Reading always gets at least 5 seconds
+        assert_eq!(
+            timeout_budget(Duration::from_secs(10)),
+            (Duration::from_secs(5), Duration::from_secs(9))
+        );
+        // But never more than the whole time limit
+        assert_eq!(
+            timeout_budget(Duration::from_secs(2)),
+            (Duration::from_secs(2), Duration::from_millis(1800))
+        );
+    }
+
+    /// Backend that takes `delay` to send a message
+    struct SlowBackend {
+        delay: Duration,
+    }
+
+    impl EmailBackend for SlowBackend {
+        fn send(
+            &self,
+            _envelope_from: Option<&Address>,
+            _envelope_to: &[&Address],
+            _raw_email: &str,
+        ) -> Result<crate::backend::SendReceipt, crate::backend::BackendError> {
+            std::thread::sleep(self.delay);
+            Ok(crate::backend::SendReceipt::default())
+        }
+
+        fn describe(&self) -> String {
+            "slow".to_string()
+        }
+    }
+
+    #[test]
+    fn test_send_with_timeout() {
+        let send = |delay: Duration| {
+            send_with_timeout(
+                Arc::new(SlowBackend { delay }),
+                None,
+                vec![Address::from_str("recipient@example.com").unwrap()],
+                "Subject: Test\r\n\r\n".to_string(),
+                b"Body".to_vec(),
+                Duration::from_millis(200),
+            )
+        };
+        assert!(send(Duration::ZERO).is_ok());
+
+        let started = Instant::now();
+        let result = send(Duration::from_secs(10));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let error = result.unwrap_err();
+        assert!(matches!(error, crate::backend::BackendError::Timeout(_)));
+        assert_eq!(error.to_string(), "Sending timed out after 200ms");
+    }
+
+    #[test]
+    fn test_deadline_reader() {
+        let input = std::io::Cursor::new(b"Subject: Test\n\nBody".to_vec());
+        let mut reader = DeadlineReader::spawn(input, Instant::now());
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+
+        let input = std::io::Cursor::new(b"Subject: Test\n\nBody".to_vec());
+        let mut reader = DeadlineReader::spawn(input, Instant::now() + Duration::from_secs(10));
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "Subject: Test\n\nBody");
+    }
+
+    /// Reader whose first read never returns, like a stdin that is never written to
+    struct BlockingReader;
+
+    impl Read for BlockingReader {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            loop {
+                std::thread::park();
+            }
+        }
+    }
+
+    #[test]
+    fn test_deadline_reader_does_not_wait_for_a_blocked_read() {
+        let started = Instant::now();
+        let mut reader =
+            DeadlineReader::spawn(BlockingReader, started + Duration::from_millis(200));
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
 }
@@ -1,17 +1,205 @@
-pub fn init_logger(verbosity: u8) {
-    let level = match verbosity {
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::warn;
+
+/// Values that are replaced with `***` in every log message
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn level_filter(verbosity: u8) -> log::LevelFilter {
+    match verbosity {
         0 => log::LevelFilter::Off,
         1 => log::LevelFilter::Info,
         2 => log::LevelFilter::Debug,
         3 => log::LevelFilter::Trace,
         _ => log::LevelFilter::Trace,
+    }
+}
+
+/// Where log messages are written: the log file if it can be opened, stderr otherwise.
+///
+/// Returns the error that prevented opening the log file, so it can be logged once the logger
+/// is set up.
+fn log_target(
+    log_file: Option<&Path>,
+    max_size: Option<u64>,
+) -> (Box<dyn Write + Send>, Option<String>) {
+    let Some(path) = log_file else {
+        return (Box::new(std::io::stderr()), None);
     };
+    match open_log_file(path, max_size) {
+        Ok(file) => (Box::new(file), None),
+        Err(e) => (
+            Box::new(std::io::stderr()),
+            Some(format!(
+                "Failed to open log file {}, logging to stderr instead: {e}",
+                path.display()
+            )),
+        ),
+    }
+}
+
+/// Open the log file at `path` for appending, after shrinking it if it is larger than
+/// `max_size` bytes.
+fn open_log_file(path: &Path, max_size: Option<u64>) -> std::io::Result<File> {
+    if let Some(max_size) = max_size {
+        truncate_log_file(path, max_size)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Remove the oldest lines of the log file at `path` if it is larger than `max_size` bytes.
+///
+/// The newest lines that fit into half of `max_size` are kept, so the file is not rewritten by
+/// every invocation once it reached the limit.
+fn truncate_log_file(path: &Path, max_size: u64) -> std::io::Result<()> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    if len <= max_size {
+        return Ok(());
+    }
+
+    file.seek(SeekFrom::Start(len - max_size / 2))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    // Do not keep a partial line
+    let start = tail
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(tail.len(), |newline| newline + 1);
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&tail[start..])?;
+    file.set_len((tail.len() - start) as u64)
+}
+
+/// Set up logging at the level selected by `verbosity`.
+///
+/// Messages go to `log_file` if it is set (shrunk to at most `max_size` bytes first), and to
+/// stderr otherwise or if the file cannot be opened.
+#[cfg(not(feature = "tracing"))]
+pub fn init_logger(verbosity: u8, log_file: Option<&Path>, max_size: Option<u64>) {
+    let level = level_filter(verbosity);
+    let (target, open_error) = log_target(log_file, max_size);
 
     // `run_sendmail` can be invoked multiple times in-process (e.g. integration tests).
     // `env_logger::init()` panics if called more than once, so make this idempotent.
     let _ = env_logger::Builder::from_default_env()
         .filter_level(level)
-        .format_timestamp(None)
-        .format_target(false)
+        .target(env_logger::Target::Pipe(target))
+        .format(|buf, record| {
+            let style = buf.default_level_style(record.level());
+            let message = redact_secrets(&record.args().to_string());
+            writeln!(buf, "[{style}{:<5}{style:#}] {message}", record.level())
+        })
         .try_init();
+    if let Some(error) = open_error {
+        warn!("{error}");
+    }
+}
+
+/// Log through a `tracing` subscriber that also reports how long each phase took.
+///
+/// `log` records of this crate and its dependencies are forwarded to the subscriber. The log
+/// file is handled like without the `tracing` feature.
+#[cfg(feature = "tracing")]
+pub fn init_logger(verbosity: u8, log_file: Option<&Path>, max_size: Option<u64>) {
+    use tracing_log::AsTrace;
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    let level = level_filter(verbosity);
+    let (target, open_error) = log_target(log_file, max_size);
+
+    // Like `env_logger`, only the first initialization takes effect
+    let _ = tracing_log::LogTracer::init_with_filter(level);
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(level.as_trace())
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(Mutex::new(RedactingWriter(target)))
+        .finish();
+    let _ = tracing::subscriber::set_global_default(subscriber);
+    if let Some(error) = open_error {
+        warn!("{error}");
+    }
+}
+
+/// Writer that hides registered secrets, for subscribers that write a whole line at once
+#[cfg(feature = "tracing")]
+struct RedactingWriter<W>(W);
+
+#[cfg(feature = "tracing")]
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let message = redact_secrets(&String::from_utf8_lossy(buf));
+        self.0.write_all(message.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Hide `secret` in all log messages written from now on.
+///
+/// Dependencies log request headers at debug level and only hide the ones they know to be
+/// sensitive, so credentials sent in other headers need to be registered here.
+pub fn redact(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+fn redact_secrets(message: &str) -> String {
+    let secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    secrets.iter().fold(message.to_string(), |message, secret| {
+        message.replace(secret, "***")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_secrets() {
+        redact("s3cr3t-api-key");
+        redact("");
+        assert_eq!(
+            redact_secrets("writing prelude: X-Api-Key: s3cr3t-api-key\r\n"),
+            "writing prelude: X-Api-Key: ***\r\n"
+        );
+        assert_eq!(redact_secrets("nothing to hide"), "nothing to hide");
+    }
+
+    #[test]
+    fn test_truncate_log_file() {
+        let path = std::env::temp_dir().join(format!(
+            "wasix_sendmail_truncate_log_{}.log",
+            std::process::id()
+        ));
+        let lines: String = (0..10).map(|i| format!("line {i}\n")).collect();
+        std::fs::write(&path, &lines).unwrap();
+
+        // Within the limit nothing changes
+        truncate_log_file(&path, 1000).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), lines);
+
+        // 70 bytes are over the limit of 40, so the newest complete lines within 20 bytes stay
+        truncate_log_file(&path, 40).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 8\nline 9\n");
+
+        let _ = std::fs::remove_file(&path);
+        truncate_log_file(&path, 40).unwrap();
+    }
 }
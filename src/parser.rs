@@ -1,4 +1,4 @@
-use log::trace;
+use log::{debug, trace, warn};
 use rootcause::prelude::*;
 use std::str::FromStr;
 
@@ -11,16 +11,135 @@ pub struct HeaderField {
     pub value: String, // unfolded value
 }
 
+/// Errors produced when parsing the header section of an email
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The header section contains more header fields than allowed
+    TooManyHeaders { count: usize, limit: usize },
+    /// A single (unfolded) header value is longer than allowed
+    HeaderValueTooLong {
+        name: String,
+        length: usize,
+        limit: usize,
+    },
+    /// An international domain name cannot be encoded as ASCII
+    InvalidDomain { domain: String, reason: String },
+    /// The name or value of a header field contains a NUL byte
+    NulByte { name: String },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::TooManyHeaders { count, limit } => {
+                write!(
+                    f,
+                    "Too many header fields: {count} exceeds the limit of {limit}"
+                )
+            }
+            ParseError::HeaderValueTooLong {
+                name,
+                length,
+                limit,
+            } => write!(
+                f,
+                "Value of header {name} is too long: {length} bytes exceeds the limit of {limit}"
+            ),
+            ParseError::InvalidDomain { domain, reason } => {
+                write!(f, "Invalid domain {domain}: {reason}")
+            }
+            ParseError::NulByte { name } => {
+                write!(
+                    f,
+                    "Header {} contains a NUL byte",
+                    name.replace('\0', "\\0")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 /// Parse raw email content into unfolded header fields.
 ///
 /// RFC 5322 specifies that header field bodies can be folded across multiple lines by inserting
 /// CRLF followed by whitespace. Unfolding replaces each CRLF + WSP with a single SP.
 #[must_use]
 pub fn parse_email_headers(email: &str) -> Vec<HeaderField> {
+    parse_email_headers_with_limits(email, usize::MAX, usize::MAX)
+        .expect("parsing without limits cannot fail")
+}
+
+/// Parse raw email content into unfolded header fields, bounding the size of the result.
+///
+/// Parsing stops with an error as soon as more than `max_headers` header fields are found or
+/// an unfolded header value grows beyond `max_header_value_len` bytes. NUL bytes, which RFC 5322
+/// does not allow in header fields, are replaced with spaces.
+pub fn parse_email_headers_with_limits(
+    email: &str,
+    max_headers: usize,
+    max_header_value_len: usize,
+) -> Result<Vec<HeaderField>, ParseError> {
+    parse_headers(email, max_headers, max_header_value_len, false)
+}
+
+/// Parse raw email content into unfolded header fields like
+/// [`parse_email_headers_with_limits`], but fail on header fields containing NUL bytes.
+pub fn parse_email_headers_strict(
+    email: &str,
+    max_headers: usize,
+    max_header_value_len: usize,
+) -> Result<Vec<HeaderField>, ParseError> {
+    parse_headers(email, max_headers, max_header_value_len, true)
+}
+
+/// Whether `s` contains a NUL byte.
+#[must_use]
+pub fn contains_nul(s: &str) -> bool {
+    s.contains('\0')
+}
+
+/// Check a header name or value for NUL bytes, rejecting them if `strict` and replacing them
+/// with spaces otherwise.
+fn check_nul(name: &str, text: &mut String, strict: bool) -> Result<(), ParseError> {
+    if !contains_nul(text) {
+        return Ok(());
+    }
+    if strict {
+        return Err(ParseError::NulByte {
+            name: name.to_string(),
+        });
+    }
+    warn!(
+        "Replacing NUL bytes in header {} with spaces",
+        name.replace('\0', "\\0")
+    );
+    *text = text.replace('\0', " ");
+    Ok(())
+}
+
+fn parse_headers(
+    email: &str,
+    max_headers: usize,
+    max_header_value_len: usize,
+    strict: bool,
+) -> Result<Vec<HeaderField>, ParseError> {
     trace!("Parsing email headers");
     let mut headers: Vec<HeaderField> = Vec::new();
     let mut current: Option<HeaderField> = None;
 
+    let check_value_len = |header: &HeaderField| {
+        if header.value.len() > max_header_value_len {
+            return Err(ParseError::HeaderValueTooLong {
+                name: header.name.clone(),
+                length: header.value.len(),
+                limit: max_header_value_len,
+            });
+        }
+        Ok(())
+    };
+
     for line in email.lines() {
         if line.trim().is_empty() {
             break; // end of header section
@@ -30,8 +149,11 @@ pub fn parse_email_headers(email: &str) -> Vec<HeaderField> {
         if line.starts_with(' ') || line.starts_with('\t') {
             if let Some(cur) = current.as_mut() {
                 // Unfold by replacing the line break + WSP with a single space.
+                let mut continuation = line.trim().to_string();
+                check_nul(&cur.name, &mut continuation, strict)?;
                 cur.value.push(' ');
-                cur.value.push_str(line.trim());
+                cur.value.push_str(&continuation);
+                check_value_len(cur)?;
             }
             continue;
         }
@@ -43,9 +165,20 @@ pub fn parse_email_headers(email: &str) -> Vec<HeaderField> {
 
         // Parse "Name: value"
         if let Some(colon_pos) = line.find(':') {
-            let name = line[..colon_pos].trim().to_string();
-            let value = line[colon_pos + 1..].trim().to_string();
-            current = Some(HeaderField { name, value });
+            if headers.len() >= max_headers {
+                return Err(ParseError::TooManyHeaders {
+                    count: headers.len() + 1,
+                    limit: max_headers,
+                });
+            }
+            let mut name = line[..colon_pos].trim().to_string();
+            let mut value = line[colon_pos + 1..].trim().to_string();
+            let original_name = name.clone();
+            check_nul(&original_name, &mut name, strict)?;
+            check_nul(&original_name, &mut value, strict)?;
+            let header = HeaderField { name, value };
+            check_value_len(&header)?;
+            current = Some(header);
         } else {
             // Malformed header line; ignore.
             trace!("Ignoring malformed header line without ':'");
@@ -57,7 +190,7 @@ pub fn parse_email_headers(email: &str) -> Vec<HeaderField> {
     }
 
     trace!("Parsed {} header field(s)", headers.len());
-    headers
+    Ok(headers)
 }
 
 /// Parse a header value as mailboxes (address list) and extract email addresses.
@@ -82,6 +215,27 @@ pub fn parse_mailboxes_header(value: &str) -> Result<Vec<Address>, Report> {
         .collect()
 }
 
+/// Parse a header value like [`parse_mailboxes_header`], dropping repeated addresses.
+///
+/// The first occurrence of each address is kept. Domains are compared case-insensitively, local
+/// parts only if `case_sensitive_local` is not set: RFC 5321 makes them case-sensitive, but
+/// most servers treat them as case-insensitive.
+pub fn parse_mailboxes_deduplicated(
+    value: &str,
+    case_sensitive_local: bool,
+) -> Result<Vec<Address>, Report> {
+    let addresses = parse_mailboxes_header(value)?;
+    let count = addresses.len();
+    let addresses = dedup_addresses(addresses, case_sensitive_local);
+    if addresses.len() < count {
+        debug!(
+            "Removed {} duplicate address(es) from the header value",
+            count - addresses.len()
+        );
+    }
+    Ok(addresses)
+}
+
 /// Parse a header value as mailboxes and return the first email address.
 ///
 /// This is useful for headers like "From" where we typically want the first address
@@ -100,6 +254,384 @@ pub fn parse_mailbox_header(value: &str) -> Result<Address, Report> {
     }
 }
 
+/// Check that an envelope address matches the RFC 5321 `Mailbox` grammar (section 4.1.2).
+///
+/// lettre validates addresses against the more permissive RFC 5322 `addr-spec`, which allows
+/// characters such as `_` in domain names and untagged IPv6 literals that SMTP servers reject.
+/// Non-ASCII characters are accepted as allowed by SMTPUTF8 (RFC 6531).
+pub fn validate_envelope_address(address: &Address) -> Result<(), Report> {
+    let error = |reason: &str| {
+        report!("Invalid envelope address: {reason}").attach(format!("Address: {address}"))
+    };
+
+    let user = address.user();
+    let valid_user = match user.strip_prefix('"').and_then(|u| u.strip_suffix('"')) {
+        Some(quoted) => is_smtp_quoted_content(quoted),
+        None => user
+            .split('.')
+            .all(|atom| !atom.is_empty() && atom.chars().all(is_smtp_atext)),
+    };
+    if !valid_user {
+        return Err(error("local part is not a dot-string or quoted string"));
+    }
+
+    let domain = address.domain();
+    let valid_domain = match domain.strip_prefix('[').and_then(|d| d.strip_suffix(']')) {
+        Some(literal) => is_smtp_address_literal(literal),
+        None => domain.split('.').all(is_smtp_sub_domain),
+    };
+    if !valid_domain {
+        return Err(error("domain is not a host name or address literal"));
+    }
+    Ok(())
+}
+
+/// Encode the international domain name of an envelope address as ASCII.
+///
+/// Domains with non-ASCII characters are converted to A-labels (`xn--…`) with the strict
+/// IDNA2008 rules of UTS #46, so `user@münchen.de` becomes `user@xn--mnchen-3ya.de`. SMTP
+/// servers without SMTPUTF8 only accept this form; the message headers keep the Unicode form
+/// for display. Addresses with an ASCII domain are returned unchanged.
+pub fn encode_address_domain(address: Address) -> Result<Address, ParseError> {
+    let domain = address.domain();
+    if domain.is_ascii() {
+        return Ok(address);
+    }
+    let invalid = |reason: String| ParseError::InvalidDomain {
+        domain: domain.to_string(),
+        reason,
+    };
+    let ascii = idna::domain_to_ascii_strict(domain).map_err(|e| invalid(e.to_string()))?;
+    let encoded = Address::new(address.user(), &ascii).map_err(|e| invalid(e.to_string()))?;
+    trace!("Encoded domain {domain} as {ascii}");
+    Ok(encoded)
+}
+
+/// `atext` of RFC 5321, extended with non-ASCII characters by RFC 6531
+fn is_smtp_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+/// Content of a `Quoted-string` (`qtextSMTP` and `quoted-pairSMTP`)
+fn is_smtp_quoted_content(content: &str) -> bool {
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        let valid = match c {
+            '\\' => chars
+                .next()
+                .is_some_and(|escaped| (' '..='~').contains(&escaped)),
+            '"' => false,
+            c => (' '..='~').contains(&c) || !c.is_ascii(),
+        };
+        if !valid {
+            return false;
+        }
+    }
+    true
+}
+
+/// `sub-domain`: letters, digits and inner hyphens (or a non-ASCII U-label)
+fn is_smtp_sub_domain(label: &str) -> bool {
+    let is_let_dig = |c: char| c.is_alphanumeric();
+    label.starts_with(is_let_dig)
+        && label.ends_with(is_let_dig)
+        && label.chars().all(|c| is_let_dig(c) || c == '-')
+}
+
+/// `address-literal` without the surrounding brackets
+fn is_smtp_address_literal(literal: &str) -> bool {
+    if let Some(ipv6) = literal.strip_prefix("IPv6:") {
+        return ipv6.parse::<std::net::Ipv6Addr>().is_ok();
+    }
+    if literal.parse::<std::net::Ipv4Addr>().is_ok() {
+        return true;
+    }
+    // General-address-literal: Standardized-tag ":" 1*dcontent
+    literal.split_once(':').is_some_and(|(tag, content)| {
+        is_smtp_sub_domain(tag)
+            && !content.is_empty()
+            && content
+                .chars()
+                .all(|c| ('!'..='~').contains(&c) && !"[\\]".contains(c))
+    })
+}
+
+/// An email address that compares, hashes and orders with a case-insensitive domain.
+///
+/// The local part stays case-sensitive as required by RFC 5321, so `User@example.com` and
+/// `user@example.com` are different addresses while `user@Example.com` and `user@example.com`
+/// are the same.
+#[derive(Debug, Clone)]
+pub struct UniqueEmailAddress(pub Address);
+
+impl UniqueEmailAddress {
+    fn key(&self) -> (&str, String) {
+        (self.0.user(), self.0.domain().to_ascii_lowercase())
+    }
+}
+
+impl PartialEq for UniqueEmailAddress {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for UniqueEmailAddress {}
+
+impl std::hash::Hash for UniqueEmailAddress {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl PartialOrd for UniqueEmailAddress {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for UniqueEmailAddress {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl From<Address> for UniqueEmailAddress {
+    fn from(address: Address) -> Self {
+        UniqueEmailAddress(address)
+    }
+}
+
+impl From<UniqueEmailAddress> for Address {
+    fn from(address: UniqueEmailAddress) -> Self {
+        address.0
+    }
+}
+
+/// Remove duplicate recipients, keeping the first occurrence of each address.
+///
+/// Addresses are compared like [`UniqueEmailAddress`]es.
+#[must_use]
+pub fn dedup_recipients(recipients: Vec<Address>) -> Vec<Address> {
+    let mut seen = std::collections::HashSet::new();
+    recipients
+        .into_iter()
+        .filter(|recipient| {
+            let is_new = seen.insert(UniqueEmailAddress(recipient.clone()));
+            if !is_new {
+                trace!("Dropping duplicate recipient {recipient}");
+            }
+            is_new
+        })
+        .collect()
+}
+
+/// Remove duplicate addresses, keeping the first occurrence of each.
+///
+/// Domains are compared case-insensitively, local parts only if `case_sensitive_local` is not
+/// set.
+#[must_use]
+pub fn dedup_addresses(addresses: Vec<Address>, case_sensitive_local: bool) -> Vec<Address> {
+    if case_sensitive_local {
+        return dedup_recipients(addresses);
+    }
+    let mut seen = std::collections::HashSet::new();
+    addresses
+        .into_iter()
+        .filter(|address| {
+            let key = (
+                address.user().to_lowercase(),
+                address.domain().to_ascii_lowercase(),
+            );
+            let is_new = seen.insert(key);
+            if !is_new {
+                trace!("Dropping duplicate recipient {address}");
+            }
+            is_new
+        })
+        .collect()
+}
+
+/// Replace the values of all header fields named `name` (case-insensitive) in the header section
+/// of `raw_email`.
+///
+/// `replace` receives the unfolded value and returns the new value, or `None` to keep the field
+/// unchanged. Replaced fields are written on a single line with the line ending of the original
+/// field. The body is left untouched.
+pub fn replace_header_values(
+    raw_email: &str,
+    name: &str,
+    mut replace: impl FnMut(&str) -> Option<String>,
+) -> String {
+    replace_matching_header_values(
+        raw_email,
+        |field_name| field_name.eq_ignore_ascii_case(name),
+        |_, value, _| replace(value),
+    )
+}
+
+/// Like [`replace_header_values`], for all header fields whose name `matches`.
+///
+/// `replace` receives the name, the unfolded value and the line ending of the field, so a new
+/// value that is folded can use the same line ending.
+pub fn replace_matching_header_values(
+    raw_email: &str,
+    mut matches: impl FnMut(&str) -> bool,
+    mut replace: impl FnMut(&str, &str, &str) -> Option<String>,
+) -> String {
+    let mut output = String::with_capacity(raw_email.len());
+    let mut lines = raw_email.split_inclusive('\n').peekable();
+
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() {
+            // End of header section, copy the rest verbatim
+            output.push_str(line);
+            lines.for_each(|line| output.push_str(line));
+            break;
+        }
+
+        // Collect the field including its continuation lines
+        let mut field_lines = vec![line];
+        while let Some(next) = lines.next_if(|l| l.starts_with(' ') || l.starts_with('\t')) {
+            field_lines.push(next);
+        }
+
+        let replaced = line.split_once(':').and_then(|(field_name, first_value)| {
+            let field_name = field_name.trim();
+            if !matches(field_name) {
+                return None;
+            }
+            let value = std::iter::once(first_value)
+                .chain(field_lines[1..].iter().copied())
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            let last_line = field_lines.last().unwrap_or(&line);
+            let line_ending = &last_line[last_line.trim_end_matches(['\r', '\n']).len()..];
+            let new_value = replace(field_name, &value, line_ending)?;
+            Some(format!("{field_name}: {new_value}{line_ending}"))
+        });
+
+        match replaced {
+            Some(replaced) => output.push_str(&replaced),
+            None => field_lines.iter().for_each(|l| output.push_str(l)),
+        }
+    }
+
+    output
+}
+
+/// Length of a header line, without the line ending, above which [`render_headers`] folds the
+/// value (RFC 5322, section 2.1.1)
+const FOLD_LINE_LENGTH: usize = 78;
+
+/// Render header fields as a header block in wire format, one `Name: value\r\n` field after
+/// the other.
+///
+/// Values that make a line longer than 78 characters are folded before a space between two
+/// words (RFC 5322, section 2.2.3), so parsing the block gives back the same unfolded values.
+/// Words too long to fit on a line are not split. The empty line that ends the header section
+/// is not included.
+#[must_use]
+pub fn render_headers(headers: &[HeaderField]) -> String {
+    let mut block = String::new();
+    for header in headers {
+        block.push_str(&header.name);
+        block.push(':');
+        let mut line_len = header.name.chars().count() + 1;
+        let mut previous: Option<&str> = None;
+        for word in header.value.split(' ') {
+            let word_len = word.chars().count();
+            // Only a single space between two words survives unfolding unchanged
+            let can_fold = previous.is_some_and(|previous| {
+                !previous.is_empty() && !previous.ends_with(char::is_whitespace)
+            }) && !word.is_empty()
+                && !word.starts_with(char::is_whitespace);
+            if can_fold && line_len + 1 + word_len > FOLD_LINE_LENGTH {
+                block.push_str("\r\n");
+                line_len = 0;
+            }
+            block.push(' ');
+            block.push_str(word);
+            line_len += 1 + word_len;
+            previous = Some(word);
+        }
+        block.push_str("\r\n");
+    }
+    block
+}
+
+/// Count the header fields with a name (case-insensitive).
+#[must_use]
+pub fn count_header(headers: &[HeaderField], name: &str) -> usize {
+    header_values(headers, name).count()
+}
+
+/// Check if a header occurs more than once (case-insensitive).
+#[must_use]
+pub fn has_multiple(headers: &[HeaderField], name: &str) -> bool {
+    header_values(headers, name).nth(1).is_some()
+}
+
+/// Delivery priority of a message, as indicated by its `Precedence:` header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessagePriority {
+    /// Regular (transactional) mail
+    #[default]
+    Normal,
+    /// Bulk mail like newsletters and notifications
+    Bulk,
+    /// Mail of the lowest priority
+    Junk,
+}
+
+impl MessagePriority {
+    /// Value of the `Precedence:` header for this priority, `None` for normal mail
+    #[must_use]
+    pub fn precedence(self) -> Option<&'static str> {
+        match self {
+            MessagePriority::Normal => None,
+            MessagePriority::Bulk => Some("bulk"),
+            MessagePriority::Junk => Some("junk"),
+        }
+    }
+
+    /// Whether backends should deliver the message with a lower priority
+    #[must_use]
+    pub fn is_low(self) -> bool {
+        self != MessagePriority::Normal
+    }
+}
+
+impl FromStr for MessagePriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "normal" => Ok(MessagePriority::Normal),
+            "bulk" => Ok(MessagePriority::Bulk),
+            "junk" => Ok(MessagePriority::Junk),
+            _ => Err(format!(
+                "Invalid priority: {s} (expected `normal`, `bulk` or `junk`)"
+            )),
+        }
+    }
+}
+
+/// Determine the priority of a message from its first `Precedence:` header.
+///
+/// Only `bulk` and `junk` lower the priority; other values (like `list`) and a missing header
+/// mean normal priority.
+#[must_use]
+pub fn extract_precedence(headers: &[HeaderField]) -> MessagePriority {
+    match header_values(headers, "Precedence").next() {
+        Some(value) if value.eq_ignore_ascii_case("bulk") => MessagePriority::Bulk,
+        Some(value) if value.eq_ignore_ascii_case("junk") => MessagePriority::Junk,
+        _ => MessagePriority::Normal,
+    }
+}
+
 /// Return all header values for a header name (case-insensitive).
 pub fn header_values<'a>(
     headers: &'a [HeaderField],
@@ -117,10 +649,192 @@ pub fn has_header(headers: &[HeaderField], name: &str) -> bool {
     headers.iter().any(|h| h.name.eq_ignore_ascii_case(name))
 }
 
+/// A hop of the path a message took, from one of its `Received:` header fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedHeader {
+    /// Host the message was received from
+    pub from: Option<String>,
+    /// Host that received the message, empty if the field does not name it
+    pub by: String,
+    /// Date and time the message was received, as written after the final `;`
+    pub timestamp: Option<String>,
+}
+
+/// Extract the hops recorded in the `Received:` header fields, from most recent to oldest.
+///
+/// This is not a full RFC 5321 parser: comments are skipped and the word following the `from`
+/// and `by` clauses is taken as host, while all other clauses are ignored.
+#[must_use]
+pub fn extract_received_chain(headers: &[HeaderField]) -> Vec<ReceivedHeader> {
+    // Each hop adds its field on top, so the header order already is most recent first
+    header_values(headers, "Received")
+        .map(parse_received)
+        .collect()
+}
+
+/// Parse the value of a `Received:` header field.
+fn parse_received(value: &str) -> ReceivedHeader {
+    let (clauses, timestamp) = match value.rsplit_once(';') {
+        Some((clauses, timestamp)) => (clauses, Some(timestamp.trim()).filter(|t| !t.is_empty())),
+        None => (value, None),
+    };
+
+    // Remove comments, which may be nested, so their words are not taken for clauses
+    let mut words = String::with_capacity(clauses.len());
+    let mut depth = 0usize;
+    for c in clauses.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            c if depth == 0 => {
+                words.push(c);
+                continue;
+            }
+            _ => {}
+        }
+        words.push(' ');
+    }
+
+    let mut from = None;
+    let mut by = None;
+    let mut words = words.split_whitespace();
+    while let Some(word) = words.next() {
+        let slot = if word.eq_ignore_ascii_case("from") {
+            &mut from
+        } else if word.eq_ignore_ascii_case("by") {
+            &mut by
+        } else {
+            continue;
+        };
+        if slot.is_none() {
+            *slot = words.next().map(str::to_string);
+        }
+    }
+
+    ReceivedHeader {
+        from,
+        by: by.unwrap_or_default(),
+        timestamp: timestamp.map(str::to_string),
+    }
+}
+
+/// Check if a header name is one of the `Resent-*` fields (case-insensitive).
+#[must_use]
+pub fn is_resent_field(name: &str) -> bool {
+    name.get(..7)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("Resent-"))
+}
+
+/// The `Resent-*` fields of the most recent resend (RFC 5322 section 3.6.6).
+///
+/// Every resend adds a block of `Resent-*` fields on top of the earlier ones, so this is the
+/// first contiguous run of them. Empty if the message was never resent.
+#[must_use]
+pub fn resent_block(headers: &[HeaderField]) -> &[HeaderField] {
+    let Some(start) = headers.iter().position(|h| is_resent_field(&h.name)) else {
+        return &[];
+    };
+    let len = headers[start..]
+        .iter()
+        .take_while(|h| is_resent_field(&h.name))
+        .count();
+    &headers[start..start + len]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_render_headers_round_trip() {
+        let email = "From: sender@example.com\r\nTo: a@example.com,\r\n b@example.com\r\nSubject: Hello\r\n\r\nBody";
+        let headers = parse_email_headers(email);
+        let block = render_headers(&headers);
+        assert_eq!(
+            block,
+            "From: sender@example.com\r\nTo: a@example.com, b@example.com\r\nSubject: Hello\r\n"
+        );
+        assert_eq!(render_headers(&parse_email_headers(&block)), block);
+    }
+
+    #[test]
+    fn test_render_headers_folds_long_values() {
+        let value = (1..=30)
+            .map(|n| format!("user{n}@example.com"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let long_word = "x".repeat(100);
+        let headers = vec![
+            HeaderField {
+                name: "To".to_string(),
+                value: value.clone(),
+            },
+            HeaderField {
+                name: "X-Long".to_string(),
+                value: format!("{long_word} a  b {long_word}"),
+            },
+        ];
+
+        let block = render_headers(&headers);
+        let lines: Vec<&str> = block.split("\r\n").collect();
+        assert!(lines.len() > 4, "{block}");
+        assert!(lines[0].starts_with("To: user1@example.com,"));
+        for line in lines.iter().filter(|line| !line.contains(&long_word)) {
+            assert!(line.len() <= FOLD_LINE_LENGTH, "{line}");
+        }
+
+        let parsed = parse_email_headers(&block);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].value, value);
+        assert_eq!(parsed[1].value, headers[1].value);
+    }
+
+    #[test]
+    fn test_resent_block_is_most_recent() {
+        let email = "Received: from a\nResent-From: new@example.com\nresent-to: x@example.com\nReceived: from b\nResent-From: old@example.com\nResent-To: y@example.com\nFrom: author@example.com\n\nBody";
+        let headers = parse_email_headers(email);
+        let block = resent_block(&headers);
+        assert_eq!(block.len(), 2);
+        assert_eq!(
+            header_values(block, "Resent-To").collect::<Vec<_>>(),
+            ["x@example.com"]
+        );
+
+        let headers = parse_email_headers("From: author@example.com\nResent\n\nBody");
+        assert!(resent_block(&headers).is_empty());
+    }
+
+    #[test]
+    fn test_count_duplicate_from_headers() {
+        let email = "From: a@example.com\nTo: b@example.com\nfrom: c@example.com\n\nFrom: body";
+        let headers = parse_email_headers(email);
+
+        assert_eq!(count_header(&headers, "From"), 2);
+        assert_eq!(count_header(&headers, "To"), 1);
+        assert_eq!(count_header(&headers, "Date"), 0);
+        assert!(has_multiple(&headers, "FROM"));
+        assert!(!has_multiple(&headers, "To"));
+    }
+
+    #[test]
+    fn test_extract_precedence() {
+        let priority = |email: &str| extract_precedence(&parse_email_headers(email));
+        assert_eq!(priority("Subject: Test\n\nBody"), MessagePriority::Normal);
+        assert_eq!(priority("Precedence: bulk\n\nBody"), MessagePriority::Bulk);
+        assert_eq!(
+            priority("precedence:  JUNK \n\nBody"),
+            MessagePriority::Junk
+        );
+        assert_eq!(
+            priority("Precedence: list\n\nBody"),
+            MessagePriority::Normal
+        );
+        assert_eq!(
+            priority("Subject: Test\n\nPrecedence: bulk"),
+            MessagePriority::Normal
+        );
+    }
+
     #[test]
     fn test_parse_email_headers() {
         let email = "From: sender@example.com\nTo: recipient1@example.com, recipient2@example.com\nCc: cc@example.com\nSubject: Test\n\nBody content";
@@ -133,6 +847,85 @@ mod tests {
         assert!(has_header(&headers, "Subject"));
     }
 
+    #[test]
+    fn test_parse_email_headers_nul_in_value() {
+        let email = "Subject: Test\0From: attacker@evil.com\nTo: recipient@example.com\n\nBody";
+
+        let headers = parse_email_headers_with_limits(email, usize::MAX, usize::MAX).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].name, "Subject");
+        assert_eq!(headers[0].value, "Test From: attacker@evil.com");
+        assert!(!has_header(&headers, "From"));
+
+        let error = parse_email_headers_strict(email, usize::MAX, usize::MAX).unwrap_err();
+        assert_eq!(
+            error,
+            ParseError::NulByte {
+                name: "Subject".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_email_headers_nul_in_name() {
+        let email = "Sub\0ject: Test\nTo: recipient@example.com\n\nBody";
+
+        let headers = parse_email_headers_with_limits(email, usize::MAX, usize::MAX).unwrap();
+        assert_eq!(headers[0].name, "Sub ject");
+        assert_eq!(headers[0].value, "Test");
+
+        let error = parse_email_headers_strict(email, usize::MAX, usize::MAX).unwrap_err();
+        assert_eq!(error.to_string(), "Header Sub\\0ject contains a NUL byte");
+    }
+
+    #[test]
+    fn test_parse_email_headers_nul_in_folded_value() {
+        let email = "Subject: Test\n \0continued\n\nBody";
+
+        let headers = parse_email_headers(email);
+        assert_eq!(headers[0].value, "Test  continued");
+        assert!(parse_email_headers_strict(email, usize::MAX, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_contains_nul() {
+        assert!(contains_nul("Test\0"));
+        assert!(!contains_nul("Test"));
+    }
+
+    #[test]
+    fn test_extract_received_chain() {
+        let email = "Received: from relay.example.org (relay.example.org [192.0.2.1])\n\tby mx.example.com (Postfix) with ESMTPS id 4F1;\n\tTue, 2 Jan 2024 10:00:05 +0000\nReceived: from client.example.net (HELO client)\n by relay.example.org with ESMTP; Tue, 2 Jan 2024 10:00:01 +0000\nReceived: by client.example.net (sendmail from cron)\nSubject: Test\n\nBody";
+        let headers = parse_email_headers(email);
+
+        assert_eq!(
+            extract_received_chain(&headers),
+            vec![
+                ReceivedHeader {
+                    from: Some("relay.example.org".to_string()),
+                    by: "mx.example.com".to_string(),
+                    timestamp: Some("Tue, 2 Jan 2024 10:00:05 +0000".to_string()),
+                },
+                ReceivedHeader {
+                    from: Some("client.example.net".to_string()),
+                    by: "relay.example.org".to_string(),
+                    timestamp: Some("Tue, 2 Jan 2024 10:00:01 +0000".to_string()),
+                },
+                ReceivedHeader {
+                    from: None,
+                    by: "client.example.net".to_string(),
+                    timestamp: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_received_chain_without_received() {
+        let headers = parse_email_headers("Subject: Test\n\nBody");
+        assert!(extract_received_chain(&headers).is_empty());
+    }
+
     #[test]
     fn test_parse_mailboxes_header() {
         let value = "recipient1@example.com, recipient2@example.com";
@@ -142,6 +935,24 @@ mod tests {
         assert_eq!(addresses[1].to_string(), "recipient2@example.com");
     }
 
+    #[test]
+    fn test_parse_mailboxes_deduplicated() {
+        let value = "a@example.com, A@example.com, a@EXAMPLE.com";
+        let addresses: Vec<String> = parse_mailboxes_deduplicated(value, true)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(addresses, ["a@example.com", "A@example.com"]);
+
+        let addresses: Vec<String> = parse_mailboxes_deduplicated(value, false)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(addresses, ["a@example.com"]);
+    }
+
     #[test]
     fn test_parse_mailbox_header() {
         let value = "sender@example.com";
@@ -214,6 +1025,123 @@ mod tests {
         assert_eq!(to_addresses[0].to_string(), "to@example.com");
     }
 
+    #[test]
+    fn test_parse_email_headers_with_limits_header_count() {
+        let email = "A: 1\nB: 2\nC: 3\n\nBody";
+        let headers = parse_email_headers_with_limits(email, 3, 100).unwrap();
+        assert_eq!(headers.len(), 3);
+
+        let err = parse_email_headers_with_limits(email, 2, 100).unwrap_err();
+        assert_eq!(err, ParseError::TooManyHeaders { count: 3, limit: 2 });
+    }
+
+    #[test]
+    fn test_parse_email_headers_with_limits_header_count_without_separator() {
+        let email = "X-Header: value\n".repeat(10);
+        let err = parse_email_headers_with_limits(&email, 5, 100).unwrap_err();
+        assert_eq!(err, ParseError::TooManyHeaders { count: 6, limit: 5 });
+    }
+
+    #[test]
+    fn test_parse_email_headers_with_limits_value_length() {
+        let email = format!("Subject: {}\n\nBody", "x".repeat(10));
+        let headers = parse_email_headers_with_limits(&email, 10, 10).unwrap();
+        assert_eq!(headers[0].value.len(), 10);
+
+        let err = parse_email_headers_with_limits(&email, 10, 9).unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::HeaderValueTooLong {
+                name: "Subject".to_string(),
+                length: 10,
+                limit: 9,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_email_headers_with_limits_folded_value_length() {
+        // Unfolded value is "aaaa bbbb" (9 bytes)
+        let email = "Subject: aaaa\n bbbb\n\nBody";
+        let headers = parse_email_headers_with_limits(email, 10, 9).unwrap();
+        assert_eq!(headers[0].value, "aaaa bbbb");
+
+        let err = parse_email_headers_with_limits(email, 10, 8).unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::HeaderValueTooLong {
+                length: 9,
+                limit: 8,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_replace_header_values() {
+        let email = "From: a@example.com\r\nSubject: Folded\r\n  subject\r\nfrom: b@example.com\r\n\r\nFrom: body";
+        let result = replace_header_values(email, "From", |value| Some(value.to_uppercase()));
+        assert_eq!(
+            result,
+            "From: A@EXAMPLE.COM\r\nSubject: Folded\r\n  subject\r\nfrom: B@EXAMPLE.COM\r\n\r\nFrom: body"
+        );
+    }
+
+    #[test]
+    fn test_replace_header_values_unfolds_replaced_field() {
+        let email = "Subject: Folded\n subject\nTo: a@example.com\n\nBody";
+        let result = replace_header_values(email, "subject", |value| Some(format!("[{value}]")));
+        assert_eq!(
+            result,
+            "Subject: [Folded subject]\nTo: a@example.com\n\nBody"
+        );
+
+        let unchanged = replace_header_values(email, "Subject", |_| None);
+        assert_eq!(unchanged, email);
+    }
+
+    #[test]
+    fn test_unique_email_address_domain_is_case_insensitive() {
+        let a = UniqueEmailAddress(Address::from_str("user@Example.com").unwrap());
+        let b = UniqueEmailAddress(Address::from_str("user@example.com").unwrap());
+        assert_eq!(a, b);
+        assert_eq!(a.cmp(&b), std::cmp::Ordering::Equal);
+
+        let set: std::collections::HashSet<_> = [a, b].into_iter().collect();
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn test_unique_email_address_local_part_is_case_sensitive() {
+        let a = UniqueEmailAddress(Address::from_str("User@example.com").unwrap());
+        let b = UniqueEmailAddress(Address::from_str("user@example.com").unwrap());
+        assert_ne!(a, b);
+
+        let set: std::collections::BTreeSet<_> = [a, b].into_iter().collect();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_dedup_recipients_keeps_first_occurrence() {
+        let recipients = [
+            "b@example.com",
+            "a@Example.com",
+            "A@example.com",
+            "a@example.com",
+        ]
+        .iter()
+        .map(|a| Address::from_str(a).unwrap())
+        .collect();
+        let deduped: Vec<String> = dedup_recipients(recipients)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            deduped,
+            vec!["b@example.com", "a@Example.com", "A@example.com"]
+        );
+    }
+
     #[test]
     #[ignore = "Comments are not supported for now. If we want them we need to switch from lettre to a custom parser."]
     fn rfc5322_comments_are_ignored() {
@@ -229,5 +1157,61 @@ mod tests {
         assert_eq!(recipient_strs, vec!["a@example.com", "b@example.com"]);
     }
 
+    #[test]
+    fn test_validate_envelope_address() {
+        let valid = [
+            "user@example.com",
+            "first.last+tag@sub.example-host.com",
+            "\"john doe\"@example.com",
+            "user@[192.0.2.1]",
+            "user@[IPv6:2001:db8::1]",
+            "us\u{e9}r@b\u{fc}cher.example",
+        ];
+        for address in valid {
+            let address = Address::from_str(address).unwrap();
+            assert!(validate_envelope_address(&address).is_ok(), "{address}");
+        }
+
+        // All of these are accepted by lettre
+        let invalid = [
+            "user@foo_bar.example.com",
+            "user@exa!mple.com",
+            "user@[::1]",
+        ];
+        for address in invalid {
+            let address = Address::from_str(address).unwrap();
+            assert!(validate_envelope_address(&address).is_err(), "{address}");
+        }
+    }
+
+    #[test]
+    fn test_encode_address_domain() {
+        let encode = |address: &str| {
+            encode_address_domain(Address::from_str(address).unwrap()).map(|a| a.to_string())
+        };
+        assert_eq!(encode("user@münchen.de").unwrap(), "user@xn--mnchen-3ya.de");
+        assert_eq!(
+            encode("user@пример.рф").unwrap(),
+            "user@xn--e1afmkfd.xn--p1ai"
+        );
+        assert_eq!(
+            encode("us\u{e9}r@例子.中文").unwrap(),
+            "us\u{e9}r@xn--fsqu00a.xn--fiq228c"
+        );
+        // ASCII domains, including A-labels, are left alone
+        assert_eq!(encode("User@Example.com").unwrap(), "User@Example.com");
+        assert_eq!(
+            encode("user@xn--mnchen-3ya.de").unwrap(),
+            "user@xn--mnchen-3ya.de"
+        );
+
+        // A right-to-left label must not start with a left-to-right character (Bidi rule)
+        let error = encode("user@a\u{5d0}.example").unwrap_err();
+        assert!(
+            matches!(&error, ParseError::InvalidDomain { domain, .. } if domain == "a\u{5d0}.example"),
+            "{error:?}"
+        );
+    }
+
     // Tests for the new chumsky-based parser are in email_parser.rs
 }
@@ -0,0 +1,1226 @@
+// The mock SMTP server does currently not work on WASIX
+#![allow(unexpected_cfgs)]
+#![cfg(not(target_vendor = "wasmer"))]
+use lettre::Address;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::TcpListener;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use wasix_sendmail::args::{DsnNotify, DsnRet, SmtpAuthMechanism, SmtpRelayProtocol};
+use wasix_sendmail::backend::{BackendError, EmailBackend, SmtpBackend};
+
+fn email_address(addr: &str) -> Address {
+    Address::from_str(addr).expect("valid email address")
+}
+
+/// Start a minimal SMTP server that rejects the given recipients and records every line it
+/// receives (commands and message content). Recipients at `deferred.example.com` are deferred
+/// with a transient reply.
+fn start_mock_smtp_server(
+    rejected_recipients: &'static [&'static str],
+) -> (u16, thread::JoinHandle<Vec<String>>) {
+    start_mock_smtp_server_on(
+        TcpListener::bind("127.0.0.1:0").unwrap(),
+        rejected_recipients,
+        EHLO_REPLY,
+    )
+}
+
+/// Reply of the mock SMTP server to `EHLO`
+const EHLO_REPLY: &[u8] = b"250-mock.example.com\r\n250 8BITMIME\r\n";
+
+/// Like [`start_mock_smtp_server`], listening on `listener` and answering `EHLO` with
+/// `ehlo_reply`
+fn start_mock_smtp_server_on(
+    listener: TcpListener,
+    rejected_recipients: &'static [&'static str],
+    ehlo_reply: &'static [u8],
+) -> (u16, thread::JoinHandle<Vec<String>>) {
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = thread::spawn(move || {
+        let mut received = Vec::new();
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        writer.write_all(b"220 mock.example.com ESMTP\r\n").unwrap();
+        let mut in_data = false;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let command = line.trim_end().to_string();
+            line.clear();
+            received.push(command.clone());
+
+            if in_data {
+                if command == "." {
+                    in_data = false;
+                    writer
+                        .write_all(b"250 2.0.0 Ok: queued as 4BfQ2x1v3Zz9\r\n")
+                        .unwrap();
+                }
+                continue;
+            }
+
+            let verb = command.to_ascii_uppercase();
+            let reply: &[u8] = if verb.starts_with("EHLO") {
+                ehlo_reply
+            } else if verb.starts_with("RCPT")
+                && rejected_recipients.iter().any(|r| command.contains(r))
+            {
+                b"550 5.1.1 User unknown\r\n"
+            } else if verb.starts_with("RCPT") && command.contains("@deferred.example.com") {
+                b"451 4.7.1 Try again later\r\n"
+            } else if verb.starts_with("DATA") {
+                in_data = true;
+                b"354 End data with <CR><LF>.<CR><LF>\r\n"
+            } else if let Some(size) = verb.strip_prefix("BDAT ") {
+                // The chunk follows the command right away and is recorded as one entry
+                let size: usize = size.split_whitespace().next().unwrap().parse().unwrap();
+                let mut chunk = vec![0; size];
+                reader.read_exact(&mut chunk).unwrap();
+                received.push(String::from_utf8(chunk).unwrap());
+                if verb.ends_with(" LAST") {
+                    b"250 2.0.0 Ok: queued as 4BfQ2x1v3Zz9\r\n"
+                } else {
+                    b"250 2.0.0 Ok\r\n"
+                }
+            } else if verb.starts_with("QUIT") {
+                writer.write_all(b"221 2.0.0 Bye\r\n").unwrap();
+                break;
+            } else {
+                b"250 2.0.0 Ok\r\n"
+            };
+            writer.write_all(reply).unwrap();
+        }
+        received
+    });
+
+    (port, handle)
+}
+
+fn plain_backend(port: u16) -> SmtpBackend {
+    SmtpBackend::new(
+        "127.0.0.1".to_string(),
+        port,
+        SmtpRelayProtocol::Plain,
+        None,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_smtp_backend_successful_send() {
+    let (port, handle) = start_mock_smtp_server(&[]);
+    let backend = plain_backend(port);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let raw_email = "Subject: Test\r\n\r\nTest body";
+
+    backend.send(Some(&from), &[&to], raw_email).unwrap();
+
+    let received = handle.join().unwrap();
+    assert!(received.contains(&"MAIL FROM:<sender@example.com>".to_string()));
+    assert!(received.contains(&"RCPT TO:<recipient@example.com>".to_string()));
+    assert!(received.contains(&"Test body".to_string()));
+}
+
+#[test]
+fn test_smtp_backend_ipv6_relay_host() {
+    let Ok(listener) = TcpListener::bind("[::1]:0") else {
+        eprintln!("IPv6 loopback is not available, skipping");
+        return;
+    };
+    let (port, handle) = start_mock_smtp_server_on(listener, &[], EHLO_REPLY);
+
+    // SMTP address literal notation is accepted as well
+    let backend = SmtpBackend::new("[::1]".to_string(), port, SmtpRelayProtocol::Plain, None)
+        .unwrap()
+        .with_ipv6_host()
+        .unwrap();
+    assert!(
+        backend
+            .describe()
+            .starts_with(&format!("smtp relay=[::1]:{port} "))
+    );
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let result = backend.send(Some(&from), &[&to], "Subject: Test\r\n\r\nBody");
+    assert!(result.is_ok(), "{result:?}");
+
+    let received = handle.join().unwrap();
+    assert!(received.contains(&"RCPT TO:<recipient@example.com>".to_string()));
+}
+
+#[test]
+fn test_smtp_backend_null_sender() {
+    let (port, handle) = start_mock_smtp_server(&[]);
+    let backend = plain_backend(port);
+
+    let to = email_address("recipient@example.com");
+    backend
+        .send(None, &[&to], "Subject: Bounce\r\n\r\nTest body")
+        .unwrap();
+
+    let received = handle.join().unwrap();
+    assert!(received.contains(&"MAIL FROM:<>".to_string()));
+}
+
+#[test]
+fn test_smtp_backend_partial_delivery() {
+    let (port, handle) = start_mock_smtp_server(&["rejected@example.com"]);
+    let backend = plain_backend(port);
+
+    let from = email_address("sender@example.com");
+    let to1 = email_address("accepted@example.com");
+    let to2 = email_address("rejected@example.com");
+    let raw_email = "Subject: Test\r\n\r\nTest body";
+
+    let result = backend.send(Some(&from), &[&to1, &to2], raw_email);
+    let Err(BackendError::PartialDelivery { accepted, rejected }) = result else {
+        panic!("expected a partial delivery, got {result:?}");
+    };
+    assert_eq!(accepted, vec!["accepted@example.com"]);
+    assert_eq!(rejected.len(), 1);
+    assert_eq!(rejected[0].0, "rejected@example.com");
+    assert!(rejected[0].1.contains("550"));
+
+    // The message must still have been sent to the accepted recipient
+    let received = handle.join().unwrap();
+    assert!(received.contains(&"DATA".to_string()));
+    assert!(received.contains(&"Test body".to_string()));
+}
+
+#[test]
+fn test_smtp_backend_all_recipients_rejected_skips_data() {
+    let (port, handle) = start_mock_smtp_server(&["rejected@example.com"]);
+    let backend = plain_backend(port);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("rejected@example.com");
+    let raw_email = "Subject: Test\r\n\r\nTest body";
+
+    let result = backend.send(Some(&from), &[&to], raw_email);
+    let Err(BackendError::Failed(report)) = result else {
+        panic!("expected a failure, got {result:?}");
+    };
+    assert!(format!("{report}").contains("All recipients were rejected"));
+
+    let received = handle.join().unwrap();
+    assert!(!received.contains(&"DATA".to_string()));
+}
+
+/// Start a mock SMTP server that announces `SIZE 100`
+fn start_size_limited_smtp_server() -> (u16, thread::JoinHandle<Vec<String>>) {
+    start_mock_smtp_server_on(
+        TcpListener::bind("127.0.0.1:0").unwrap(),
+        &[],
+        b"250-mock.example.com\r\n250-SIZE 100\r\n250 8BITMIME\r\n",
+    )
+}
+
+#[test]
+fn test_smtp_backend_announces_message_size() {
+    let (port, handle) = start_size_limited_smtp_server();
+    let backend = plain_backend(port);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let raw_email = "Subject: Test\r\n\r\nTest body";
+    backend.send(Some(&from), &[&to], raw_email).unwrap();
+
+    let received = handle.join().unwrap();
+    let mail_from = format!("MAIL FROM:<sender@example.com> SIZE={}", raw_email.len());
+    assert!(received.contains(&mail_from), "{received:?}");
+}
+
+#[test]
+fn test_smtp_backend_refuses_long_body_lines() {
+    // The message is refused before connecting, so nothing has to listen on the port
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend = plain_backend(listener.local_addr().unwrap().port());
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let raw_email = format!("Subject: Test\r\n\r\n{}\r\n", "x".repeat(2000));
+    let error = backend.send(Some(&from), &[&to], &raw_email).unwrap_err();
+    assert!(error.to_string().contains("2000 octets long"), "{error}");
+}
+
+#[test]
+fn test_smtp_backend_wraps_long_body_lines() {
+    let (port, handle) = start_mock_smtp_server(&[]);
+    let backend = plain_backend(port).with_wrap_long_lines();
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let raw_email = format!("Subject: Test\r\n\r\n{}\r\n", "x".repeat(2000));
+    backend.send(Some(&from), &[&to], &raw_email).unwrap();
+
+    let received = handle.join().unwrap();
+    let start = received.iter().position(|line| line == "DATA").unwrap() + 1;
+    let body: Vec<&String> = received[start..]
+        .iter()
+        .skip_while(|line| !line.is_empty())
+        .skip(1)
+        .take_while(|line| *line != ".")
+        .filter(|line| !line.is_empty())
+        .collect();
+    assert_eq!(body.len(), 3, "{body:?}");
+    assert!(body.iter().all(|line| line.len() <= 998));
+    let joined: String = body.iter().map(|line| line.trim_end_matches(' ')).collect();
+    assert_eq!(joined, "x".repeat(2000));
+}
+
+#[test]
+fn test_smtp_backend_sends_with_bdat() {
+    let (port, handle) = start_mock_smtp_server_on(
+        TcpListener::bind("127.0.0.1:0").unwrap(),
+        &[],
+        b"250-mock.example.com\r\n250-CHUNKING\r\n250 8BITMIME\r\n",
+    );
+    let backend = plain_backend(port).with_bdat();
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let receipt = backend
+        .send(Some(&from), &[&to], "Subject: Test\r\n\r\n.leading dot\r\n")
+        .unwrap();
+    assert_eq!(receipt.message_id.as_deref(), Some("4BfQ2x1v3Zz9"));
+
+    let received = handle.join().unwrap();
+    let bdat = received
+        .iter()
+        .position(|line| line == "BDAT 31 LAST")
+        .unwrap_or_else(|| panic!("{received:?}"));
+    // The chunk is sent as it is, without dot-stuffing
+    assert_eq!(received[bdat + 1], "Subject: Test\r\n\r\n.leading dot\r\n");
+    assert!(!received.iter().any(|line| line == "DATA"), "{received:?}");
+}
+
+#[test]
+fn test_smtp_backend_bdat_without_chunking_uses_data() {
+    let (port, handle) = start_mock_smtp_server(&[]);
+    let backend = plain_backend(port).with_bdat();
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    backend
+        .send(Some(&from), &[&to], "Subject: Test\r\n\r\nTest body")
+        .unwrap();
+
+    let received = handle.join().unwrap();
+    assert!(received.iter().any(|line| line == "DATA"), "{received:?}");
+    assert!(
+        !received.iter().any(|line| line.starts_with("BDAT")),
+        "{received:?}"
+    );
+}
+
+/// Start a mock server that announces the `DSN` extension
+fn start_dsn_smtp_server() -> (u16, thread::JoinHandle<Vec<String>>) {
+    start_mock_smtp_server_on(
+        TcpListener::bind("127.0.0.1:0").unwrap(),
+        &[],
+        b"250-mock.example.com\r\n250-DSN\r\n250 8BITMIME\r\n",
+    )
+}
+
+#[test]
+fn test_smtp_backend_requests_configured_dsn() {
+    let (port, handle) = start_dsn_smtp_server();
+    let backend = plain_backend(port)
+        .with_dsn_notify(vec![DsnNotify::Success, DsnNotify::Failure])
+        .unwrap()
+        .with_dsn_ret(DsnRet::Hdrs);
+
+    let from = email_address("sender@example.com");
+    let a = email_address("a@example.com");
+    let b = email_address("b@example.com");
+    backend
+        .send(Some(&from), &[&a, &b], "Subject: Test\r\n\r\nTest body")
+        .unwrap();
+
+    let received = handle.join().unwrap();
+    for command in [
+        "MAIL FROM:<sender@example.com> RET=HDRS",
+        "RCPT TO:<a@example.com> NOTIFY=SUCCESS,FAILURE",
+        "RCPT TO:<b@example.com> NOTIFY=SUCCESS,FAILURE",
+    ] {
+        assert!(
+            received.iter().any(|line| line == command),
+            "{command}: {received:?}"
+        );
+    }
+}
+
+#[test]
+fn test_smtp_backend_bulk_mail_requests_no_dsn() {
+    let (port, handle) = start_dsn_smtp_server();
+    let backend = plain_backend(port);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    backend
+        .send(Some(&from), &[&to], "Precedence: bulk\r\n\r\nNewsletter")
+        .unwrap();
+
+    let received = handle.join().unwrap();
+    assert!(
+        received
+            .iter()
+            .any(|line| line == "RCPT TO:<recipient@example.com> NOTIFY=NEVER"),
+        "{received:?}"
+    );
+    assert!(
+        received
+            .iter()
+            .any(|line| line == "MAIL FROM:<sender@example.com>"),
+        "{received:?}"
+    );
+}
+
+#[test]
+fn test_smtp_backend_without_dsn_sends_no_parameters() {
+    // Regular mail without configured notifications
+    let (port, handle) = start_dsn_smtp_server();
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    plain_backend(port)
+        .send(Some(&from), &[&to], "Subject: Test\r\n\r\nTest body")
+        .unwrap();
+    let received = handle.join().unwrap();
+    assert!(
+        received
+            .iter()
+            .any(|line| line == "RCPT TO:<recipient@example.com>"),
+        "{received:?}"
+    );
+
+    // A relay without DSN does not get the parameters, even if they are configured
+    let (port, handle) = start_mock_smtp_server(&[]);
+    plain_backend(port)
+        .with_dsn_notify(vec![DsnNotify::Failure])
+        .unwrap()
+        .with_dsn_ret(DsnRet::Full)
+        .send(Some(&from), &[&to], "Subject: Test\r\n\r\nTest body")
+        .unwrap();
+    let received = handle.join().unwrap();
+    assert!(
+        !received
+            .iter()
+            .any(|line| line.contains("NOTIFY=") || line.contains("RET=")),
+        "{received:?}"
+    );
+}
+
+#[test]
+fn test_smtp_backend_message_larger_than_server_size() {
+    let (port, handle) = start_size_limited_smtp_server();
+    let backend = plain_backend(port);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let raw_email = format!("Subject: Test\r\n\r\n{}", "x".repeat(100));
+    let result = backend.send(Some(&from), &[&to], &raw_email);
+    assert!(
+        matches!(
+            result,
+            Err(BackendError::MessageTooLarge { size, limit: Some(100), .. }) if size == raw_email.len()
+        ),
+        "{result:?}"
+    );
+
+    let received = handle.join().unwrap();
+    assert!(!received.iter().any(|line| line.starts_with("MAIL FROM")));
+}
+
+#[test]
+fn test_smtp_backend_configured_limit_checked_before_connecting() {
+    // The message is refused before connecting, so nothing has to listen on the port
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend = plain_backend(listener.local_addr().unwrap().port()).with_max_message_size(20);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let result = backend.send(Some(&from), &[&to], "Subject: Test\r\n\r\nTest body");
+    assert!(
+        matches!(
+            result,
+            Err(BackendError::MessageTooLarge {
+                limit: Some(20),
+                ..
+            })
+        ),
+        "{result:?}"
+    );
+}
+
+#[test]
+fn test_run_sendmail_prints_smtp_queue_id() {
+    let (port, handle) = start_mock_smtp_server(&[]);
+
+    let args = vec!["sendmail".to_string(), "recipient@example.com".to_string()];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), port.to_string()),
+        ("SENDMAIL_RELAY_PROTO".to_string(), "plain".to_string()),
+    ];
+    let stdin = Cursor::new(b"Subject: Test\n\nBody".to_vec());
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    let rc = wasix_sendmail::run_sendmail(stdin, &mut stdout, &mut stderr, &args, &envs);
+    assert_eq!(rc, 0, "{}", String::from_utf8_lossy(&stderr));
+    assert_eq!(String::from_utf8(stdout).unwrap(), "4BfQ2x1v3Zz9\n");
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_run_sendmail_partial_delivery_exits_with_error() {
+    let (port, handle) = start_mock_smtp_server(&["rejected@example.com"]);
+
+    let args = vec![
+        "sendmail".to_string(),
+        "accepted@example.com".to_string(),
+        "rejected@example.com".to_string(),
+    ];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), port.to_string()),
+        ("SENDMAIL_RELAY_PROTO".to_string(), "plain".to_string()),
+    ];
+    let stdin = Cursor::new(b"Subject: Test\n\nBody".to_vec());
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    let rc = wasix_sendmail::run_sendmail(stdin, &mut stdout, &mut stderr, &args, &envs);
+    assert_eq!(rc, 1);
+
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(stderr.contains("not delivered to 1 of 2 recipient(s)"));
+
+    let received = handle.join().unwrap();
+    assert!(received.contains(&"Body".to_string()));
+}
+
+#[test]
+fn test_run_sendmail_deferred_recipient_exits_with_tempfail() {
+    let (port, handle) = start_mock_smtp_server(&[]);
+
+    let args = vec![
+        "sendmail".to_string(),
+        "user@deferred.example.com".to_string(),
+    ];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), port.to_string()),
+        ("SENDMAIL_RELAY_PROTO".to_string(), "plain".to_string()),
+    ];
+    let stdin = Cursor::new(b"Subject: Test\n\nBody".to_vec());
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    let rc = wasix_sendmail::run_sendmail(stdin, &mut stdout, &mut stderr, &args, &envs);
+    assert_eq!(rc, wasix_sendmail::EX_TEMPFAIL);
+
+    let received = handle.join().unwrap();
+    assert!(!received.iter().any(|line| line == "DATA"), "{received:?}");
+}
+
+#[test]
+fn test_smtp_backend_verify_connects_without_sending() {
+    let (port, handle) = start_mock_smtp_server(&[]);
+    let backend = plain_backend(port);
+
+    let summary = backend.verify().unwrap();
+    assert_eq!(summary, format!("connected to 127.0.0.1:{port}"));
+
+    let received = handle.join().unwrap();
+    assert_eq!(received.len(), 3, "{received:?}");
+    assert!(received[0].starts_with("EHLO "));
+    assert!(received[1].starts_with("EHLO "));
+    assert_eq!(received[2], "QUIT");
+}
+
+#[test]
+fn test_run_sendmail_check_reachable_relay() {
+    let (port, handle) = start_mock_smtp_server(&[]);
+
+    let args = vec!["sendmail".to_string(), "--check".to_string()];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), port.to_string()),
+        ("SENDMAIL_RELAY_PROTO".to_string(), "plain".to_string()),
+    ];
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    let rc = wasix_sendmail::run_sendmail(std::io::empty(), &mut stdout, &mut stderr, &args, &envs);
+    assert_eq!(rc, 0, "{}", String::from_utf8_lossy(&stderr));
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        format!(
+            "backend: smtp relay=127.0.0.1:{port} tls=plain\nstatus: ok (connected to 127.0.0.1:{port})\n"
+        )
+    );
+
+    let received = handle.join().unwrap();
+    assert!(received[0].starts_with("EHLO "), "{received:?}");
+    assert!(!received.iter().any(|line| line.starts_with("MAIL")));
+}
+
+#[test]
+fn test_run_sendmail_verify_backend_unreachable_relay() {
+    // Nothing listens on the port once the listener is dropped
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let args = vec!["sendmail".to_string(), "--verify-backend".to_string()];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), port.to_string()),
+        ("SENDMAIL_RELAY_PROTO".to_string(), "plain".to_string()),
+    ];
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    let rc = wasix_sendmail::run_sendmail(std::io::empty(), &mut stdout, &mut stderr, &args, &envs);
+    // Nothing listens on the port, which may change when retrying later
+    assert_eq!(rc, wasix_sendmail::EX_TEMPFAIL);
+
+    let stdout = String::from_utf8(stdout).unwrap();
+    assert!(stdout.starts_with(&format!("backend: smtp relay=127.0.0.1:{port} tls=plain\n")));
+    assert!(
+        stdout.contains("status: failed (Failed to connect to SMTP relay"),
+        "{stdout}"
+    );
+}
+
+/// Start a mock SMTP server that offers the `offered` AUTH mechanisms but only accepts
+/// `AUTH LOGIN` (user `user`, password `secret`); `AUTH PLAIN` is rejected.
+fn start_login_only_smtp_server(offered: &'static str) -> (u16, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = thread::spawn(move || {
+        let mut received = Vec::new();
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        writer.write_all(b"220 mock.example.com ESMTP\r\n").unwrap();
+        let mut authenticated = false;
+        let mut login_step = 0;
+        let mut in_data = false;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let command = line.trim_end().to_string();
+            line.clear();
+            received.push(command.clone());
+
+            if in_data {
+                if command == "." {
+                    in_data = false;
+                    writer.write_all(b"250 2.0.0 Ok: queued\r\n").unwrap();
+                }
+                continue;
+            }
+
+            let verb = command.to_ascii_uppercase();
+            let reply = if login_step == 1 {
+                login_step = if command == "dXNlcg==" { 2 } else { 0 };
+                "334 UGFzc3dvcmQ6\r\n".to_string()
+            } else if login_step == 2 {
+                login_step = 0;
+                authenticated = command == "c2VjcmV0";
+                if authenticated {
+                    "235 2.7.0 Authentication successful\r\n".to_string()
+                } else {
+                    "535 5.7.8 Authentication failed\r\n".to_string()
+                }
+            } else if verb.starts_with("EHLO") {
+                format!("250-mock.example.com\r\n250-AUTH {offered}\r\n250 8BITMIME\r\n")
+            } else if verb.starts_with("AUTH LOGIN") {
+                login_step = 1;
+                "334 VXNlcm5hbWU6\r\n".to_string()
+            } else if verb.starts_with("AUTH") {
+                "504 5.5.4 Unrecognized authentication type\r\n".to_string()
+            } else if verb.starts_with("MAIL") && !authenticated {
+                "530 5.7.0 Authentication required\r\n".to_string()
+            } else if verb.starts_with("DATA") {
+                in_data = true;
+                "354 End data with <CR><LF>.<CR><LF>\r\n".to_string()
+            } else if verb.starts_with("QUIT") {
+                writer.write_all(b"221 2.0.0 Bye\r\n").unwrap();
+                break;
+            } else {
+                "250 2.0.0 Ok\r\n".to_string()
+            };
+            writer.write_all(reply.as_bytes()).unwrap();
+        }
+        received
+    });
+
+    (port, handle)
+}
+
+fn login_backend(port: u16, mechanism: SmtpAuthMechanism) -> SmtpBackend {
+    SmtpBackend::new(
+        "127.0.0.1".to_string(),
+        port,
+        SmtpRelayProtocol::Plain,
+        Some(("user".to_string(), "secret".to_string())),
+    )
+    .unwrap()
+    .with_auth_mechanism(mechanism)
+}
+
+#[test]
+fn test_smtp_backend_auth_login_mechanism() {
+    let (port, handle) = start_login_only_smtp_server("PLAIN LOGIN");
+    let backend = login_backend(port, SmtpAuthMechanism::Login);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    backend
+        .send(Some(&from), &[&to], "Subject: Test\r\n\r\nTest body")
+        .unwrap();
+
+    let received = handle.join().unwrap();
+    assert!(received.contains(&"AUTH LOGIN".to_string()));
+    assert!(!received.iter().any(|line| line.starts_with("AUTH PLAIN")));
+    assert!(received.contains(&"Test body".to_string()));
+    // The extensions are queried before AUTH, not with a new EHLO in the authenticated session
+    let auth = received
+        .iter()
+        .position(|line| line == "AUTH LOGIN")
+        .unwrap();
+    assert!(!received[auth..].iter().any(|line| line.starts_with("EHLO")));
+}
+
+#[test]
+fn test_smtp_backend_auth_plain_rejected() {
+    let (port, handle) = start_login_only_smtp_server("PLAIN LOGIN");
+    let backend = login_backend(port, SmtpAuthMechanism::Plain);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let result = backend.send(Some(&from), &[&to], "Subject: Test\r\n\r\nTest body");
+    assert!(result.is_err());
+
+    let received = handle.join().unwrap();
+    assert!(received.iter().any(|line| line.starts_with("AUTH PLAIN")));
+}
+
+#[test]
+fn test_smtp_backend_auth_external_requires_client_certificate() {
+    let (port, handle) = start_login_only_smtp_server("PLAIN LOGIN");
+    let backend = login_backend(port, SmtpAuthMechanism::External);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let result = backend.send(Some(&from), &[&to], "Subject: Test\r\n\r\nTest body");
+    assert!(
+        matches!(result, Err(BackendError::SmtpAuthFailed(ref message)) if message.contains("client certificate")),
+        "{result:?}"
+    );
+
+    let received = handle.join().unwrap();
+    assert!(!received.iter().any(|line| line.starts_with("AUTH")));
+    assert!(!received.iter().any(|line| line.starts_with("MAIL")));
+}
+
+#[test]
+fn test_run_sendmail_relay_auth_external_without_client_certificate() {
+    let args = vec!["sendmail".to_string(), "recipient@example.com".to_string()];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), "1".to_string()),
+        (
+            "SENDMAIL_RELAY_AUTH_MECHANISM".to_string(),
+            "external".to_string(),
+        ),
+    ];
+    let stdin = Cursor::new(b"Subject: Test\n\nBody".to_vec());
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    let rc = wasix_sendmail::run_sendmail(stdin, &mut stdout, &mut stderr, &args, &envs);
+    assert_ne!(rc, 0);
+
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(
+        stderr.contains("SENDMAIL_RELAY_AUTH_MECHANISM=external requires a client certificate"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_smtp_backend_auth_auto_falls_back_to_login() {
+    let (port, handle) = start_login_only_smtp_server("LOGIN");
+    let backend = login_backend(port, SmtpAuthMechanism::Auto);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    backend
+        .send(Some(&from), &[&to], "Subject: Test\r\n\r\nTest body")
+        .unwrap();
+
+    let received = handle.join().unwrap();
+    assert!(received.contains(&"AUTH LOGIN".to_string()));
+}
+
+#[test]
+fn test_smtp_backend_verify_authenticates() {
+    let (port, handle) = start_login_only_smtp_server("LOGIN");
+    let backend = login_backend(port, SmtpAuthMechanism::Auto);
+
+    let summary = backend.verify().unwrap();
+    assert_eq!(
+        summary,
+        format!("connected to 127.0.0.1:{port} and authenticated as user")
+    );
+
+    let received = handle.join().unwrap();
+    assert!(received.contains(&"AUTH LOGIN".to_string()));
+    assert!(!received.iter().any(|line| line.starts_with("MAIL")));
+}
+
+#[test]
+fn test_smtp_backend_verify_connection_rejected_credentials() {
+    let (port, handle) = start_login_only_smtp_server("LOGIN");
+    let backend = SmtpBackend::new(
+        "127.0.0.1".to_string(),
+        port,
+        SmtpRelayProtocol::Plain,
+        Some(("user".to_string(), "wrong".to_string())),
+    )
+    .unwrap();
+
+    let result = backend.verify_connection();
+    let Err(BackendError::SmtpAuthFailed(reason)) = result else {
+        panic!("expected an authentication failure, got {result:?}");
+    };
+    assert!(reason.contains("535"), "{reason}");
+
+    let received = handle.join().unwrap();
+    assert!(!received.iter().any(|line| line.starts_with("MAIL")));
+}
+
+/// Stdin that must not be read
+struct UnreadableStdin;
+
+impl std::io::Read for UnreadableStdin {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        panic!("stdin should not be read");
+    }
+}
+
+#[test]
+fn test_run_sendmail_smtp_preflight_fails_before_reading_message() {
+    let (port, handle) = start_login_only_smtp_server("LOGIN");
+
+    let args = vec!["sendmail".to_string(), "recipient@example.com".to_string()];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), port.to_string()),
+        ("SENDMAIL_RELAY_PROTO".to_string(), "plain".to_string()),
+        ("SENDMAIL_RELAY_USER".to_string(), "user".to_string()),
+        ("SENDMAIL_RELAY_PASS".to_string(), "wrong".to_string()),
+        ("SENDMAIL_SMTP_PREFLIGHT".to_string(), "1".to_string()),
+    ];
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    // The message should not be read
+    let rc = wasix_sendmail::run_sendmail(UnreadableStdin, &mut stdout, &mut stderr, &args, &envs);
+    assert_eq!(rc, wasix_sendmail::EX_USAGE);
+
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(stderr.contains("Failed to authenticate"), "{stderr}");
+
+    let received = handle.join().unwrap();
+    assert!(!received.iter().any(|line| line.starts_with("MAIL")));
+}
+
+#[test]
+fn test_run_sendmail_relay_no_auth_skips_authentication() {
+    let (port, handle) = start_mock_smtp_server(&[]);
+
+    let args = vec!["sendmail".to_string(), "recipient@example.com".to_string()];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), port.to_string()),
+        ("SENDMAIL_RELAY_PROTO".to_string(), "plain".to_string()),
+        ("SENDMAIL_RELAY_USER".to_string(), "user".to_string()),
+        ("SENDMAIL_RELAY_PASS".to_string(), "secret".to_string()),
+        ("SENDMAIL_RELAY_NO_AUTH".to_string(), "1".to_string()),
+    ];
+    let stdin = Cursor::new(b"Subject: Test\n\nBody".to_vec());
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    let rc = wasix_sendmail::run_sendmail(stdin, &mut stdout, &mut stderr, &args, &envs);
+    assert_eq!(rc, 0, "{}", String::from_utf8_lossy(&stderr));
+
+    let received = handle.join().unwrap();
+    assert!(!received.iter().any(|line| line.starts_with("AUTH")));
+    assert!(received.contains(&"Body".to_string()));
+}
+
+/// Start a mock SMTP server for legacy relays that rejects `EHLO` and only accepts `HELO`. It
+/// serves `connections` connections one after the other and records every line it receives.
+fn start_helo_only_smtp_server(connections: usize) -> (u16, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = thread::spawn(move || {
+        let mut received = Vec::new();
+        for _ in 0..connections {
+            let (stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            writer
+                .write_all(b"220 legacy.example.com SMTP\r\n")
+                .unwrap();
+            let mut in_data = false;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                received.push(command.clone());
+
+                if in_data {
+                    if command == "." {
+                        in_data = false;
+                        writer.write_all(b"250 Ok: queued as LEGACY42\r\n").unwrap();
+                    }
+                    continue;
+                }
+
+                let verb = command.to_ascii_uppercase();
+                let reply: &[u8] = if verb.starts_with("EHLO") {
+                    b"502 5.5.2 Error: command not recognized\r\n"
+                } else if verb.starts_with("DATA") {
+                    in_data = true;
+                    b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                } else if verb.starts_with("QUIT") {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 Ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+        }
+        received
+    });
+
+    (port, handle)
+}
+
+#[test]
+fn test_smtp_backend_falls_back_to_helo() {
+    let (port, handle) = start_helo_only_smtp_server(2);
+    let backend = plain_backend(port);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let receipt = backend
+        .send(
+            Some(&from),
+            &[&to],
+            "Subject: Test\r\n\r\n.leading dot\r\nBody",
+        )
+        .unwrap();
+    assert_eq!(receipt.message_id.as_deref(), Some("LEGACY42"));
+
+    let received = handle.join().unwrap();
+    let ehlo = received.iter().position(|line| line.starts_with("EHLO"));
+    let helo = received.iter().position(|line| line.starts_with("HELO"));
+    assert!(ehlo.unwrap() < helo.unwrap(), "{received:?}");
+    assert!(received.contains(&"MAIL FROM:<sender@example.com>".to_string()));
+    assert!(received.contains(&"RCPT TO:<recipient@example.com>".to_string()));
+    assert!(received.contains(&"..leading dot".to_string()));
+    assert!(received.contains(&"Body".to_string()));
+}
+
+#[test]
+fn test_smtp_backend_helo_fallback_cannot_authenticate() {
+    let (port, handle) = start_helo_only_smtp_server(1);
+    let backend = login_backend(port, SmtpAuthMechanism::Auto);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let err = backend
+        .send(Some(&from), &[&to], "Subject: Test\r\n\r\nBody")
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("not supported after HELO"),
+        "{err}"
+    );
+
+    let received = handle.join().unwrap();
+    assert!(!received.iter().any(|line| line.starts_with("MAIL")));
+}
+
+#[test]
+fn test_run_sendmail_relay_force_helo() {
+    let (port, handle) = start_helo_only_smtp_server(1);
+
+    let args = vec!["sendmail".to_string(), "recipient@example.com".to_string()];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), port.to_string()),
+        ("SENDMAIL_RELAY_FORCE_HELO".to_string(), "1".to_string()),
+    ];
+    let stdin = Cursor::new(b"Subject: Test\n\nBody".to_vec());
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    let rc = wasix_sendmail::run_sendmail(stdin, &mut stdout, &mut stderr, &args, &envs);
+    assert_eq!(rc, 0, "{}", String::from_utf8_lossy(&stderr));
+    assert_eq!(String::from_utf8(stdout).unwrap(), "LEGACY42\n");
+
+    let received = handle.join().unwrap();
+    assert!(!received.iter().any(|line| line.starts_with("EHLO")));
+    assert!(received[0].starts_with("HELO "), "{received:?}");
+}
+
+#[test]
+fn test_run_sendmail_timeout_with_hanging_relay() {
+    // The relay accepts the connection but never greets
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_secs(3));
+        drop(stream);
+    });
+
+    let args = vec!["sendmail".to_string(), "recipient@example.com".to_string()];
+    let envs = vec![
+        ("SENDMAIL_RELAY_HOST".to_string(), "127.0.0.1".to_string()),
+        ("SENDMAIL_RELAY_PORT".to_string(), port.to_string()),
+        ("SENDMAIL_RELAY_PROTO".to_string(), "plain".to_string()),
+        ("SENDMAIL_TIMEOUT_SECS".to_string(), "1".to_string()),
+    ];
+    let stdin = Cursor::new(b"Subject: Test\n\nBody".to_vec());
+    let mut stdout = Vec::<u8>::new();
+    let mut stderr = Vec::<u8>::new();
+
+    let started = std::time::Instant::now();
+    let rc = wasix_sendmail::run_sendmail(stdin, &mut stdout, &mut stderr, &args, &envs);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(rc, 75);
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(stderr.contains("Sending timed out after 900ms"), "{stderr}");
+
+    let _ = handle.join();
+}
+
+/// Start a server that refuses `connections` connections one after the other with the
+/// greeting `banner`, keeping each open until the client closes it. Returns how many
+/// connections were made, including any that are still waiting to be accepted after that.
+fn start_refusing_smtp_server(
+    banner: &'static str,
+    connections: usize,
+) -> (u16, thread::JoinHandle<usize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = thread::spawn(move || {
+        let mut accepted = 0;
+        for _ in 0..connections {
+            let (stream, _) = listener.accept().unwrap();
+            accepted += 1;
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            writer.write_all(banner.as_bytes()).unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                line.clear();
+            }
+        }
+        thread::sleep(Duration::from_millis(200));
+        listener.set_nonblocking(true).unwrap();
+        while listener.accept().is_ok() {
+            accepted += 1;
+        }
+        accepted
+    });
+
+    (port, handle)
+}
+
+#[test]
+fn test_smtp_backend_refused_greeting_fails_without_waiting() {
+    let (port, handle) = start_refusing_smtp_server("421 4.3.2 Service not available\r\n", 2);
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+
+    for backend in [
+        plain_backend(port).with_timeout(Duration::from_secs(10)),
+        plain_backend(port)
+            .with_timeout(Duration::from_secs(10))
+            .with_force_helo(),
+    ] {
+        let started = std::time::Instant::now();
+        let result = backend.send(Some(&from), &[&to], "Subject: Test\r\n\r\nBody");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        // A 421 greeting is temporary, so the message may be sent later
+        let Err(error @ BackendError::Unavailable(_)) = result else {
+            panic!("expected the relay to be unavailable, got {result:?}");
+        };
+        let message = error.to_string();
+        assert!(message.contains("refused service"), "{message}");
+        assert!(message.contains("Service not available"), "{message}");
+    }
+    assert_eq!(handle.join().unwrap(), 2);
+}
+
+#[test]
+fn test_smtp_backend_refused_greeting_is_not_retried_with_helo() {
+    let (port, handle) = start_refusing_smtp_server("554 5.7.1 No SMTP service here\r\n", 1);
+    let backend = plain_backend(port);
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let result = backend.send(Some(&from), &[&to], "Subject: Test\r\n\r\nBody");
+    assert!(
+        matches!(result, Err(BackendError::SmtpProtocolError(ref message)) if message.contains("No SMTP service here")),
+        "{result:?}"
+    );
+    assert_eq!(handle.join().unwrap(), 1, "no second connection for HELO");
+}
+
+/// Start a server that accepts one connection and greets after `greeting_delay`, then accepts
+/// a message, taking `data_delay` to answer the end of the message.
+fn start_slow_smtp_server(
+    greeting_delay: Duration,
+    data_delay: Duration,
+) -> (u16, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        thread::sleep(greeting_delay);
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        if writer.write_all(b"220 slow.example.com ESMTP\r\n").is_err() {
+            return;
+        }
+
+        let mut in_data = false;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let command = line.trim_end().to_ascii_uppercase();
+            line.clear();
+            let reply: &[u8] = if in_data {
+                if command != "." {
+                    continue;
+                }
+                in_data = false;
+                thread::sleep(data_delay);
+                b"250 2.0.0 Ok\r\n"
+            } else if command.starts_with("EHLO") {
+                b"250 slow.example.com\r\n"
+            } else if command.starts_with("DATA") {
+                in_data = true;
+                b"354 Go ahead\r\n"
+            } else if command.starts_with("QUIT") {
+                let _ = writer.write_all(b"221 Bye\r\n");
+                break;
+            } else {
+                b"250 Ok\r\n"
+            };
+            if writer.write_all(reply).is_err() {
+                break;
+            }
+        }
+    });
+
+    (port, handle)
+}
+
+#[test]
+fn test_smtp_backend_connect_timeout_without_greeting() {
+    let (port, handle) = start_slow_smtp_server(Duration::from_secs(3), Duration::ZERO);
+    let backend = plain_backend(port)
+        .with_timeout(Duration::from_secs(30))
+        .with_connect_timeout(Duration::from_millis(300));
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let started = std::time::Instant::now();
+    let result = backend.send(Some(&from), &[&to], "Subject: Test\r\n\r\nBody");
+    assert!(started.elapsed() < Duration::from_secs(2));
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("Failed to connect to SMTP relay"), "{error}");
+
+    let _ = handle.join();
+}
+
+#[test]
+fn test_smtp_backend_connect_timeout_does_not_limit_commands() {
+    // Answering the message takes longer than the connect timeout
+    let (port, handle) = start_slow_smtp_server(Duration::ZERO, Duration::from_millis(800));
+    let backend = plain_backend(port)
+        .with_timeout(Duration::from_secs(30))
+        .with_connect_timeout(Duration::from_millis(300));
+
+    let from = email_address("sender@example.com");
+    let to = email_address("recipient@example.com");
+    let result = backend.send(Some(&from), &[&to], "Subject: Test\r\n\r\nBody");
+    assert!(result.is_ok(), "{result:?}");
+
+    let _ = handle.join();
+}
+
+#[test]
+#[ignore = "needs a network that silently drops packets to 10.255.255.1"]
+fn test_smtp_backend_connect_timeout_on_filtered_port() {
+    let backend = SmtpBackend::new(
+        "10.255.255.1".to_string(),
+        25,
+        SmtpRelayProtocol::Plain,
+        None,
+    )
+    .unwrap()
+    .with_timeout(Duration::from_secs(60))
+    .with_connect_timeout(Duration::from_secs(1));
+
+    let started = std::time::Instant::now();
+    let result = backend.verify();
+    let elapsed = started.elapsed();
+    assert!(result.is_err());
+    assert!(
+        elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(5),
+        "{elapsed:?}"
+    );
+}